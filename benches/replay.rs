@@ -0,0 +1,267 @@
+//! Measures replay throughput, and API latency while the server replays a large log and
+//! then while it tails the log as new lines are appended to it.
+//!
+//! Run with `cargo bench --bench replay`. The size of the log can be changed with
+//! `BENCH_LINES` (default 2,000,000) and `BENCH_DEVICES` (default 20,000), the number of
+//! concurrent clients with `BENCH_CLIENTS` (default 8), and how many lines a second are
+//! appended once the replay is done with `BENCH_TAIL_RATE` (default 1,000).
+
+use chrono::{Duration as ChronoDuration, TimeZone, Utc};
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::TcpListener,
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const ENDPOINTS: &[&str] = &[
+    "/api/v2/online?limit=50",
+    "/api/v2/mac/02:00:00:00:00:2a",
+    "/api/v2/ap",
+    "/api/v1/map",
+    "/status",
+];
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Writes line `i` of a log of devices associating, identifying and disassociating across a
+/// handful of access points.
+fn write_line(out: &mut impl Write, i: usize, devices: usize) {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let device = (i / 3) % devices;
+    let mac = format!(
+        "02:00:00:{:02x}:{:02x}:{:02x}",
+        (device >> 16) & 0xff,
+        (device >> 8) & 0xff,
+        device & 0xff
+    );
+    let host = format!("ap-{}", device % 10);
+    let interface = if device % 2 == 0 { "wlan0" } else { "wlan1" };
+    let message = match i % 3 {
+        0 => "IEEE 802.11: associated".to_string(),
+        1 => format!("IEEE 802.1X: STA identity 'user{}'", device % 500),
+        _ if (i / 3 / devices) % 2 == 0 => "IEEE 802.11: disassociated".to_string(),
+        _ => "WPA: group key handshake completed (RSN)".to_string(),
+    };
+    let timestamp = start + ChronoDuration::milliseconds(i64::try_from(i).unwrap() * 10);
+    writeln!(
+    out,
+        r#"{{"host":"{host}","program":"hostapd","timestamp":"{}","message":"{interface}: STA {mac} {message}"}}"#,
+        timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    )
+    .unwrap();
+}
+
+fn generate_log(path: &Path, lines: usize, devices: usize) {
+    let mut out = BufWriter::new(File::create(path).unwrap());
+    for i in 0..lines {
+        write_line(&mut out, i, devices);
+    }
+    out.flush().unwrap();
+}
+
+/// Appends lines to the log, continuing from line `from`, at `rate` lines a second for
+/// `duration`. Returns how many lines were appended.
+fn append_log(path: &Path, from: usize, devices: usize, rate: usize, duration: Duration) -> usize {
+    let mut out = OpenOptions::new().append(true).open(path).unwrap();
+    let started = Instant::now();
+    let mut appended = 0;
+    while started.elapsed() < duration {
+        // catch up with the rate rather than sleeping a fixed interval per line
+        let due = (started.elapsed().as_secs_f64() * rate as f64) as usize;
+        let mut batch = Vec::new();
+        for i in appended..due {
+            write_line(&mut batch, from + i, devices);
+        }
+        out.write_all(&batch).unwrap();
+        appended = appended.max(due);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    appended
+}
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn summarize(name: &str, latencies: &mut [Duration]) {
+    if latencies.is_empty() {
+        println!("{name:<32} no requests");
+        return;
+    }
+    latencies.sort_unstable();
+    let at = |q: f64| {
+        let i = ((latencies.len() - 1) as f64 * q) as usize;
+        latencies[i]
+    };
+    println!(
+        "{name:<32} n={:<7} p50={:>9.2?} p90={:>9.2?} p99={:>9.2?} max={:>9.2?}",
+        latencies.len(),
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+async fn status(client: &reqwest::Client, base: &str) -> Option<serde_json::Value> {
+    let response = client.get(format!("{base}/status")).send().await.ok()?;
+    Some(response.json().await.unwrap())
+}
+
+async fn replayed(client: &reqwest::Client, base: &str) -> bool {
+    status(client, base)
+        .await
+        .and_then(|status| status["readiness"]["replayed"].as_bool())
+        .unwrap_or(false)
+}
+
+async fn records_applied(client: &reqwest::Client, base: &str) -> u64 {
+    let status = status(client, base).await.unwrap();
+    status["ingest"]["records_applied"].as_u64().unwrap()
+}
+
+/// Requests every endpoint in turn until `until`, recording the latency of each.
+async fn client_loop(base: String, until: Instant) -> Vec<Vec<Duration>> {
+    let client = reqwest::Client::new();
+    let mut latencies = vec![Vec::new(); ENDPOINTS.len()];
+    while Instant::now() < until {
+        for (i, endpoint) in ENDPOINTS.iter().enumerate() {
+            let started = Instant::now();
+            let response = client.get(format!("{base}{endpoint}")).send().await;
+            if let Ok(response) = response {
+                let _ = response.bytes().await;
+                latencies[i].push(started.elapsed());
+            }
+        }
+    }
+    latencies
+}
+
+async fn measure(base: &str, clients: usize, duration: Duration) -> Vec<Vec<Duration>> {
+    let until = Instant::now() + duration;
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(client_loop(base.to_string(), until)))
+        .collect();
+    let mut latencies = vec![Vec::new(); ENDPOINTS.len()];
+    for task in tasks {
+        for (all, some) in latencies.iter_mut().zip(task.await.unwrap()) {
+            all.extend(some);
+        }
+    }
+    latencies
+}
+
+#[tokio::main]
+async fn main() {
+    let lines = var("BENCH_LINES", 2_000_000);
+    let devices = var("BENCH_DEVICES", 20_000);
+    let clients = var("BENCH_CLIENTS", 8);
+    let tail_rate = var("BENCH_TAIL_RATE", 1_000);
+
+    let dir = env::temp_dir().join(format!("hostapd-api-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("messages.json");
+    let started = Instant::now();
+    generate_log(&log, lines, devices);
+    println!(
+        "generated {lines} lines for {devices} devices in {:.2?}",
+        started.elapsed()
+    );
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let base = format!("http://127.0.0.1:{port}");
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_hostapd-api"))
+            .arg("--file")
+            .arg(&log)
+            .arg("--listen")
+            .arg(format!("127.0.0.1:{port}"))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+    while client.get(format!("{base}/healthz")).send().await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // measure in short rounds until the replay is finished
+    let mut during = vec![Vec::new(); ENDPOINTS.len()];
+    while !replayed(&client, &base).await {
+        for (all, some) in during
+            .iter_mut()
+            .zip(measure(&base, clients, Duration::from_millis(500)).await)
+        {
+            all.extend(some);
+        }
+    }
+    let replay = started.elapsed();
+    let rate = lines as f64 / replay.as_secs_f64();
+    println!("replayed in {replay:.2?} ({rate:.0} lines/s)");
+    let status = status(&client, &base).await.unwrap();
+    let ingest = &status["ingest"];
+    println!(
+        "applied {} records in {} batches, reading held back {} times for {:.2}s",
+        ingest["records_applied"],
+        ingest["batches"],
+        ingest["held_back"],
+        ingest["held_back_seconds"]
+    );
+
+    println!("\nduring replay, {clients} clients:");
+    for (endpoint, latencies) in ENDPOINTS.iter().zip(&mut during) {
+        summarize(endpoint, latencies);
+    }
+
+    println!("\nafter replay, {clients} clients:");
+    let mut after = measure(&base, clients, Duration::from_secs(5)).await;
+    for (endpoint, latencies) in ENDPOINTS.iter().zip(&mut after) {
+        summarize(endpoint, latencies);
+    }
+
+    // the steady state: new lines arriving while clients keep asking
+    let tail_for = Duration::from_secs(5);
+    let before = records_applied(&client, &base).await;
+    let appender = {
+        let log = log.clone();
+        std::thread::spawn(move || append_log(&log, lines, devices, tail_rate, tail_for))
+    };
+    let mut tailing = measure(&base, clients, tail_for).await;
+    let appended = appender.join().unwrap();
+    let caught_up = Instant::now();
+    let mut applied = records_applied(&client, &base).await - before;
+    while applied < appended as u64 && caught_up.elapsed() < Duration::from_secs(10) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        applied = records_applied(&client, &base).await - before;
+    }
+    println!("\nwhile tailing {tail_rate} lines/s, {clients} clients:");
+    println!(
+        "appended {appended} lines, applied {applied} records, caught up {:.2?} after the last",
+        caught_up.elapsed()
+    );
+    for (endpoint, latencies) in ENDPOINTS.iter().zip(&mut tailing) {
+        summarize(endpoint, latencies);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
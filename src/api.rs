@@ -0,0 +1,317 @@
+use crate::{
+    audit::AuditLog,
+    auth::{Auth, Scope},
+    db::{DeviceQuery, DB},
+    mac::{InvalidMacAddr, MacAddr},
+    series::SharedStore,
+    status::SharedStatus,
+    storage::DeviceStore,
+};
+use axum::{
+    extract::{Query, Request, State},
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, sync::Arc};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+
+mod admin;
+mod extract;
+mod health;
+mod reports;
+mod series;
+#[cfg(test)]
+mod tests;
+mod v1;
+pub mod v2;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "A read-only API for devices associated with hostapd access points"),
+    nest(
+        (path = "/api/v1", api = v1::V1Doc),
+        (path = "/api/v2", api = v2::V2Doc),
+        (path = "/api/v2", api = reports::ReportsDoc),
+        (path = "/api/v2", api = series::SeriesDoc),
+        (path = "/api/v2/admin", api = admin::AdminDoc),
+    ),
+    modifiers(&TrimTrailingSlash),
+)]
+pub struct ApiDoc;
+
+/// Nesting documents the `/` routes as `/api/v1/` and `/api/v2/`, but the router serves
+/// them without the trailing slash.
+struct TrimTrailingSlash;
+
+impl Modify for TrimTrailingSlash {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let paths = std::mem::take(&mut openapi.paths.paths);
+        openapi.paths.paths = paths
+            .into_iter()
+            .map(|(path, item)| match path.strip_suffix('/') {
+                Some(trimmed) if !trimmed.is_empty() => (trimmed.to_string(), item),
+                _ => (path, item),
+            })
+            .collect();
+    }
+}
+
+/// Builds the router for every version of the API. The unprefixed routes are aliases for
+/// `/api/v1` and are marked as deprecated, and as going away at `sunset` if it is set. The
+/// admin routes are only served when authentication is enabled, and the time series when
+/// there is a store for them.
+pub fn router(
+    db: DB,
+    status: SharedStatus,
+    auth: Auth,
+    audit: AuditLog,
+    series: Option<SharedStore>,
+    sunset: Option<DateTime<Utc>>,
+) -> Router {
+    let presence = middleware::from_fn_with_state((auth.clone(), Scope::Presence), authorize);
+    let history = middleware::from_fn_with_state((auth.clone(), Scope::History), authorize);
+    let as_of = middleware::from_fn_with_state((auth.clone(), Scope::History), authorize_as_of);
+    let mut router = Router::new();
+    if let Some(store) = series {
+        router = router.nest(
+            "/api/v2",
+            series::router(store).route_layer(history.clone()),
+        );
+    }
+    if auth.is_enabled() {
+        let admin = middleware::from_fn_with_state((auth, Scope::Admin), authorize);
+        router = router.nest(
+            "/api/v2/admin",
+            admin::router(db.clone(), audit).route_layer(admin),
+        );
+    }
+
+    router
+        .nest(
+            "/api/v1",
+            v1::router()
+                .route_layer(presence.clone())
+                .route_layer(as_of.clone()),
+        )
+        .nest(
+            "/api/v2",
+            v2::router()
+                .route_layer(presence.clone())
+                .route_layer(as_of.clone())
+                .merge(reports::router(db.clone()).route_layer(history)),
+        )
+        .merge(
+            v1::router()
+                .route_layer(presence.clone())
+                .route_layer(as_of)
+                .layer(middleware::from_fn_with_state(sunset, deprecated)),
+        )
+        .merge(health::router(db.clone(), status.clone()).route_layer(presence.clone()))
+        .merge(health::probes(db.clone(), status))
+        .route("/openapi.json", get(route_openapi))
+        .route("/docs", get(route_docs))
+        .with_state(db)
+}
+
+/// Rejects requests that don't present a credential with the given scope, when
+/// authentication is enabled.
+async fn authorize(
+    State((auth, scope)): State<(Auth, Scope)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if auth.is_enabled() {
+        let credential = auth
+            .authenticate(request.headers())
+            .ok_or(ApiError::Unauthorized)?;
+        if !credential.allows(scope) {
+            tracing::warn!("{} is not allowed {scope} access", credential.name());
+            return Err(ApiError::Forbidden(scope));
+        }
+        let actor = Actor(credential.name().to_string());
+        request.extensions_mut().insert(actor);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Like [`authorize`], but only for requests that ask about an earlier moment with `at`.
+async fn authorize_as_of(
+    state: State<(Auth, Scope)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let as_of =
+        Query::<AsOf>::try_from_uri(request.uri()).is_ok_and(|Query(as_of)| as_of.at.is_some());
+    if as_of {
+        authorize(state, request, next).await
+    } else {
+        Ok(next.run(request).await)
+    }
+}
+
+/// Asks for the state as of an earlier moment rather than now.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct AsOf {
+    /// An RFC 3339 timestamp. The answer is rebuilt from the event history as it was then,
+    /// which requires the `history` scope.
+    at: Option<DateTime<Utc>>,
+}
+
+impl AsOf {
+    /// The latest snapshot, or the database rebuilt as of `at`.
+    async fn database(&self, db: &DB) -> Result<Arc<dyn DeviceStore>, ApiError> {
+        let Some(at) = self.at else {
+            return Ok(db.read());
+        };
+        db.at(at)
+            .await?
+            .map(|db| Arc::new(db) as Arc<dyn DeviceStore>)
+            .ok_or_else(|| ApiError::BadRequest(format!("the history doesn't go back to {at}")))
+    }
+}
+
+/// Narrows `query` to the devices a route lists, such as `/offline` or `/ap/{ap}`. A query
+/// parameter asking for other devices is refused rather than overridden.
+fn narrow(mut query: DeviceQuery, route: DeviceQuery) -> Result<DeviceQuery, ApiError> {
+    fn set<T: PartialEq + Display>(
+        name: &str,
+        given: &mut Option<T>,
+        route: Option<T>,
+    ) -> Result<(), ApiError> {
+        let Some(route) = route else {
+            return Ok(());
+        };
+        if let Some(given) = given.as_ref().filter(|given| **given != route) {
+            return Err(ApiError::BadRequest(format!(
+                "{name}={given} contradicts the route, which lists devices with {name}={route}"
+            )));
+        }
+        given.replace(route);
+        Ok(())
+    }
+
+    set("online", &mut query.online, route.online)?;
+    set("ap", &mut query.ap, route.ap)?;
+    set("interface", &mut query.interface, route.interface)?;
+    set("ssid", &mut query.ssid, route.ssid)?;
+    set("identity", &mut query.identity, route.identity)?;
+    set("vlan", &mut query.vlan, route.vlan)?;
+    Ok(query)
+}
+
+/// The name of the credential a request was authenticated with, for the audit log.
+#[derive(Debug, Clone)]
+pub struct Actor(pub String);
+
+/// When the unprefixed routes were deprecated, as `@` and a Unix timestamp: the release that
+/// introduced `/api/v2`, on 2026-10-18.
+const DEPRECATION: &str = "@1792281600";
+
+/// Adds the `Deprecation` header (RFC 9745), the `Sunset` header (RFC 8594) if a sunset is
+/// configured, and a link to the versioned route.
+async fn deprecated(
+    State(sunset): State<Option<DateTime<Utc>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let successor = format!(
+        "</api/v1{}>; rel=\"successor-version\"",
+        request.uri().path().trim_end_matches('/')
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static(DEPRECATION));
+    if let Some(sunset) = sunset {
+        let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        if let Ok(date) = HeaderValue::from_str(&date) {
+            headers.insert("sunset", date);
+        }
+    }
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert("link", link);
+    }
+    response
+}
+
+/// Errors returned to HTTP clients, serialized as [`ErrorResponse`].
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error(transparent)]
+    InvalidMac(#[from] InvalidMacAddr),
+
+    #[error("invalid VLAN ID: {0}")]
+    InvalidVlan(String),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("device not found: {0}")]
+    DeviceNotFound(MacAddr),
+
+    #[error("access point not found: {0}")]
+    AccessPointNotFound(String),
+
+    #[error("interface not found: {0}")]
+    InterfaceNotFound(String),
+
+    #[error("authentication required")]
+    Unauthorized,
+
+    #[error("{0} scope required")]
+    Forbidden(Scope),
+
+    #[error(transparent)]
+    Storage(#[from] crate::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ApiError::InvalidMac(_) | ApiError::InvalidVlan(_) | ApiError::BadRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::DeviceNotFound(_)
+            | ApiError::AccessPointNotFound(_)
+            | ApiError::InterfaceNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Storage(ref e) => {
+                tracing::error!("error reading the history: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let body = ErrorResponse {
+            error: self.to_string(),
+        };
+
+        let mut response = (status, Json(body)).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Bearer, Basic realm=\"hostapd-api\""),
+            );
+        }
+        response
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: String,
+}
+
+async fn route_openapi() -> Json<utoipa::openapi::OpenApi> {
+    // the health endpoints sit at the root, which `nest` can't express
+    Json(ApiDoc::openapi().merge_from(health::HealthDoc::openapi()))
+}
+
+async fn route_docs() -> Html<&'static str> {
+    Html(include_str!("api/docs.html"))
+}
@@ -0,0 +1,162 @@
+use super::{extract::Path, v2::DeviceResponse, Actor, ApiError, ErrorResponse};
+use crate::{
+    audit::{AuditAction, AuditLog},
+    db::DB,
+    mac::MacAddr,
+    parser::Change,
+};
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+
+/// Endpoints that modify the database. Changes are kept in the history, so they hold for
+/// `?at=` and anything else replayed from it, and, with SQLite storage, across restarts.
+/// Every one is recorded in the audit log.
+#[derive(OpenApi)]
+#[openapi(paths(
+    route_device_delete,
+    route_device_merge,
+    route_device_offline,
+    route_ap_clear,
+))]
+pub struct AdminDoc;
+
+pub fn router(db: DB, audit: AuditLog) -> Router<DB> {
+    Router::new()
+        .route("/mac/:mac", delete(route_device_delete))
+        .route("/mac/:mac/merge", post(route_device_merge))
+        .route("/mac/:mac/offline", post(route_device_offline))
+        .route("/ap/:ap/clear", post(route_ap_clear))
+        .with_state((db, audit))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MergeRequest {
+    /// The MAC address whose record is merged into this one and then removed.
+    from: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClearResponse {
+    /// The number of devices that were associated with the access point.
+    devices: usize,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/mac/{mac}",
+    params(("mac" = String, Path, description = "MAC address in any common notation")),
+    responses(
+        (status = 204, description = "The device was forgotten"),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+async fn route_device_delete(
+    State((db, audit)): State<(DB, AuditLog)>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(mac): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mac = mac.parse::<MacAddr>()?;
+    if db.change(Change::Forget { mac }).await? == 0 {
+        return Err(ApiError::DeviceNotFound(mac));
+    }
+
+    audit.record(&actor, AuditAction::Forget { mac }).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/mac/{mac}/merge",
+    params(("mac" = String, Path, description = "The MAC address to keep")),
+    request_body = MergeRequest,
+    responses(
+        (status = 200, description = "The merged device", body = DeviceResponse),
+        (status = 400, description = "Invalid MAC address or request", body = ErrorResponse),
+        (status = 404, description = "Unknown device to merge from", body = ErrorResponse),
+    )
+)]
+async fn route_device_merge(
+    State((db, audit)): State<(DB, AuditLog)>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(mac): Path<String>,
+    request: Result<Json<MergeRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let into = mac.parse::<MacAddr>()?;
+    let Json(request) = request.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let from = request.from.parse::<MacAddr>()?;
+    if from == into {
+        return Err(ApiError::BadRequest(
+            "cannot merge a device into itself".to_string(),
+        ));
+    }
+
+    if db.change(Change::Merge { from, into }).await? == 0 {
+        return Err(ApiError::DeviceNotFound(from));
+    }
+    audit
+        .record(&actor, AuditAction::Merge { from, into })
+        .await;
+
+    let device = db.read().get(into)?.ok_or(ApiError::DeviceNotFound(into))?;
+    Ok(Json(DeviceResponse::from(&device)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/mac/{mac}/offline",
+    params(("mac" = String, Path, description = "MAC address in any common notation")),
+    responses(
+        (status = 200, description = "The device, now offline", body = DeviceResponse),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+async fn route_device_offline(
+    State((db, audit)): State<(DB, AuditLog)>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(mac): Path<String>,
+) -> Result<Response, ApiError> {
+    let mac = mac.parse::<MacAddr>()?;
+    if db.change(Change::ForceOffline { mac }).await? == 0 {
+        return Err(ApiError::DeviceNotFound(mac));
+    }
+    audit
+        .record(&actor, AuditAction::ForceOffline { mac })
+        .await;
+
+    let device = db.read().get(mac)?.ok_or(ApiError::DeviceNotFound(mac))?;
+    Ok(Json(DeviceResponse::from(&device)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/ap/{ap}/clear",
+    params(("ap" = String, Path, description = "Access point hostname")),
+    responses(
+        (status = 200, description = "Every device on the access point was marked offline from it", body = ClearResponse),
+        (status = 404, description = "Unknown access point", body = ErrorResponse),
+    )
+)]
+async fn route_ap_clear(
+    State((db, audit)): State<(DB, AuditLog)>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(ap): Path<String>,
+) -> Result<Json<ClearResponse>, ApiError> {
+    if !db.read().knows_ap(&ap) {
+        return Err(ApiError::AccessPointNotFound(ap));
+    }
+    let devices = db.change(Change::ClearAp { ap: ap.clone() }).await?;
+    audit
+        .record(&actor, AuditAction::ClearAp { ap, devices })
+        .await;
+
+    Ok(Json(ClearResponse { devices }))
+}
@@ -0,0 +1,72 @@
+use crate::{
+    db::DB,
+    status::{Readiness, SharedStatus, StatusReport},
+};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
+
+/// Endpoints for health checks and monitoring. They aren't versioned.
+#[derive(OpenApi)]
+#[openapi(paths(route_healthz, route_readyz, route_status))]
+pub struct HealthDoc;
+
+/// The probes are left public so that Docker and Kubernetes don't need credentials.
+pub fn probes(db: DB, status: SharedStatus) -> Router<DB> {
+    Router::new()
+        .route("/healthz", get(route_healthz))
+        .route("/readyz", get(route_readyz))
+        .with_state((db, status))
+}
+
+pub fn router(db: DB, status: SharedStatus) -> Router<DB> {
+    Router::new()
+        .route("/status", get(route_status))
+        .with_state((db, status))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Health {
+    status: &'static str,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses((status = 200, description = "The process is alive", body = Health))
+)]
+async fn route_healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The logs have been replayed and are being followed", body = Readiness),
+        (status = 503, description = "The service is not ready", body = Readiness),
+    )
+)]
+async fn route_readyz(
+    State((db, status)): State<(DB, SharedStatus)>,
+) -> (StatusCode, Json<Readiness>) {
+    let last_event = db.read().last_event_timestamp();
+    let readiness = status.readiness(last_event);
+
+    let code = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(readiness))
+}
+
+#[utoipa::path(
+    get,
+    path = "/status",
+    responses((status = 200, description = "The state of log ingestion", body = StatusReport))
+)]
+async fn route_status(State((db, status)): State<(DB, SharedStatus)>) -> Json<StatusReport> {
+    let last_event = db.read().last_event_timestamp();
+    Json(status.report(last_event))
+}
@@ -0,0 +1,304 @@
+use super::{
+    extract::{Path, Query},
+    v2::Station,
+    ApiError, ErrorResponse,
+};
+use crate::{
+    db::{replay_blocking, DB},
+    mac::MacAddr,
+    presence::{self, DeviceDay, Interval, Summary},
+    stats::{self, Activity},
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::Mutex;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+/// Presence timelines, reports and statistics, worked out from the event history. They
+/// require the `history` scope.
+#[derive(OpenApi)]
+#[openapi(paths(
+    route_device_timeline,
+    route_user_timeline,
+    route_daily_report,
+    route_stats
+))]
+pub struct ReportsDoc;
+
+pub fn router(db: DB) -> Router<DB> {
+    Router::new()
+        .route("/mac/:mac/timeline", get(route_device_timeline))
+        .route("/users/:identity/timeline", get(route_user_timeline))
+        .route("/reports/daily", get(route_daily_report))
+        .route("/stats", get(route_stats))
+        .with_state((db, Days::default()))
+}
+
+/// The presence of every device on a day.
+type Presence = BTreeMap<MacAddr, DeviceDay>;
+
+/// How many past days the presence is kept of.
+const CACHED_DAYS: usize = 31;
+
+/// The presence on the days the log has moved past, which doesn't change any more, so
+/// that asking for one again doesn't replay the history again.
+#[derive(Debug, Clone, Default)]
+struct Days(Arc<Mutex<BTreeMap<NaiveDate, Arc<Presence>>>>);
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct DayQuery {
+    /// The day, in UTC, as `YYYY-MM-DD`. Defaults to today.
+    day: Option<NaiveDate>,
+}
+
+impl DayQuery {
+    fn day(&self) -> NaiveDate {
+        self.day.unwrap_or_else(|| Utc::now().date_naive())
+    }
+}
+
+/// Works out the presence on `day` on a blocking thread, so that ingestion carries on
+/// while the history is replayed, unless it was worked out already. A day that starts
+/// before the history is refused, since its presence would be incomplete.
+async fn presence_on(
+    db: &DB,
+    days: &Days,
+    day: NaiveDate,
+    now: DateTime<Utc>,
+) -> Result<Arc<Presence>, ApiError> {
+    let (start, end) = presence::day_bounds(day);
+    if db.history_start().await.is_some_and(|since| start < since) {
+        return Err(ApiError::BadRequest(format!(
+            "the history doesn't go back to {day}"
+        )));
+    }
+    if let Some(presence) = days.0.lock().await.get(&day) {
+        return Ok(presence.clone());
+    }
+
+    let replay = db.replay(end).await?;
+    let presence =
+        Arc::new(replay_blocking(replay, move |replay| presence::day(replay, day, now)).await?);
+    // the events of the day may still be coming in until the log has moved past it
+    if db.read().last_event_timestamp().is_some_and(|t| t >= end) {
+        let mut days = days.0.lock().await;
+        days.insert(day, presence.clone());
+        while days.len() > CACHED_DAYS {
+            days.pop_first();
+        }
+    }
+    Ok(presence)
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceTimeline {
+    mac: MacAddr,
+    day: NaiveDate,
+    identity: Option<String>,
+    #[serde(flatten)]
+    summary: Summary,
+    intervals: Vec<Interval>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserTimeline {
+    identity: String,
+    day: NaiveDate,
+    /// The devices that authenticated with the identity and were present that day.
+    devices: Vec<MacAddr>,
+    #[serde(flatten)]
+    summary: Summary,
+    /// When any of the devices was online. Overlapping intervals are merged.
+    intervals: Vec<Interval>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceSummary {
+    mac: MacAddr,
+    identity: Option<String>,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DailyReport {
+    day: NaiveDate,
+    /// Every device that was seen or online that day.
+    devices: Vec<DeviceSummary>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/mac/{mac}/timeline",
+    params(("mac" = String, Path, description = "MAC address in any common notation"), DayQuery),
+    responses(
+        (status = 200, description = "When the device was online that day", body = DeviceTimeline),
+        (status = 400, description = "Invalid MAC address, or the history doesn't go back to the day", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+async fn route_device_timeline(
+    State((db, days)): State<(DB, Days)>,
+    Path(mac): Path<String>,
+    Query(query): Query<DayQuery>,
+) -> Result<Response, ApiError> {
+    let mac = mac.parse::<MacAddr>()?;
+    let day = query.day();
+    let presence = presence_on(&db, &days, day, Utc::now()).await?;
+
+    let timeline = if let Some(device) = presence.get(&mac).cloned() {
+        DeviceTimeline {
+            mac,
+            day,
+            identity: device.identity,
+            summary: device.summary,
+            intervals: device.intervals,
+        }
+    } else {
+        // known, but not present that day
+        let device = db.read().get(mac)?.ok_or(ApiError::DeviceNotFound(mac))?;
+        DeviceTimeline {
+            mac,
+            day,
+            identity: device.device.identity.clone(),
+            summary: Summary::default(),
+            intervals: Vec::new(),
+        }
+    };
+    Ok(Json(timeline).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{identity}/timeline",
+    params(("identity" = String, Path, description = "802.1X identity"), DayQuery),
+    responses(
+        (status = 200, description = "When any of the identity's devices was online that day", body = UserTimeline),
+        (status = 400, description = "The history doesn't go back to the day", body = ErrorResponse),
+    )
+)]
+async fn route_user_timeline(
+    State((db, days)): State<(DB, Days)>,
+    Path(identity): Path<String>,
+    Query(query): Query<DayQuery>,
+) -> Result<Response, ApiError> {
+    let day = query.day();
+    let now = Utc::now();
+    let presence = presence_on(&db, &days, day, now).await?;
+
+    let devices: Vec<_> = presence
+        .values()
+        .filter(|device| device.identity.as_deref() == Some(identity.as_str()))
+        .collect();
+    let (summary, intervals) = presence::combine(devices.iter().copied(), now);
+    Ok(Json(UserTimeline {
+        devices: devices.iter().map(|device| device.mac).collect(),
+        identity,
+        day,
+        summary,
+        intervals,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/reports/daily",
+    params(DayQuery),
+    responses(
+        (status = 200, description = "The presence of every device that day", body = DailyReport),
+        (status = 400, description = "The history doesn't go back to the day", body = ErrorResponse),
+    )
+)]
+async fn route_daily_report(
+    State((db, days)): State<(DB, Days)>,
+    Query(query): Query<DayQuery>,
+) -> Result<Response, ApiError> {
+    let day = query.day();
+    let presence = presence_on(&db, &days, day, Utc::now()).await?;
+
+    Ok(Json(DailyReport {
+        day,
+        devices: presence
+            .values()
+            .map(|device| DeviceSummary {
+                mac: device.mac,
+                identity: device.identity.clone(),
+                summary: device.summary.clone(),
+            })
+            .collect(),
+    })
+    .into_response())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccessPointStats {
+    name: String,
+    #[serde(flatten)]
+    activity: Activity,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StationStats<'a> {
+    #[serde(flatten)]
+    station: Station<'a>,
+    #[serde(flatten)]
+    activity: Activity,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatsResponse<'a> {
+    /// The statistics cover the 24 hours from `since` to `until`, apart from the current
+    /// client counts.
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    access_points: Vec<AccessPointStats>,
+    stations: Vec<StationStats<'a>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses((status = 200, description = "Activity per access point and station over the last 24 hours", body = StatsResponse))
+)]
+async fn route_stats(State((db, _)): State<(DB, Days)>) -> Result<Response, ApiError> {
+    let now = Utc::now();
+    let window = Duration::days(1);
+    let live = db.read();
+    let replay = db.replay(now).await?;
+    let stats = {
+        let live = live.clone();
+        replay_blocking(replay, move |replay| {
+            stats::stats(replay, &*live, now, window)
+        })
+        .await?
+    };
+
+    Ok(Json(StatsResponse {
+        since: now - window,
+        until: now,
+        access_points: stats
+            .access_points
+            .into_iter()
+            .map(|(name, activity)| AccessPointStats { name, activity })
+            .collect(),
+        stations: stats
+            .stations
+            .iter()
+            .map(|(station, activity)| StationStats {
+                station: Station::new(station, live.interface_info(station)),
+                activity: activity.clone(),
+            })
+            .collect(),
+    })
+    .into_response())
+}
@@ -0,0 +1,101 @@
+use super::{extract::Query, ApiError, ErrorResponse};
+use crate::{
+    db::DB,
+    series::{self, Filter, Point, SharedStore},
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+/// Client counts sampled over time. They require the `history` scope, and are only served
+/// when a series directory is configured.
+#[derive(OpenApi)]
+#[openapi(paths(route_clients))]
+pub struct SeriesDoc;
+
+pub fn router(store: SharedStore) -> Router<DB> {
+    Router::new()
+        .route("/series/clients", get(route_clients))
+        .with_state(store)
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct SeriesQuery {
+    /// Only count the clients on this access point.
+    ap: Option<String>,
+    /// Only count the clients on interfaces with this name.
+    interface: Option<String>,
+    /// Only count the clients on this SSID.
+    ssid: Option<String>,
+    /// An RFC 3339 timestamp. Defaults to a day before `to`.
+    from: Option<DateTime<Utc>>,
+    /// An RFC 3339 timestamp. Defaults to now.
+    to: Option<DateTime<Utc>>,
+    /// The step between points, e.g. `5m` or `1h`. Defaults to the finest step kept as far
+    /// back as `from`, and is rounded up to a multiple of it.
+    step: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SeriesResponse {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    /// The step between points.
+    step_seconds: i64,
+    /// The steps with samples, in order.
+    points: Vec<Point>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/series/clients",
+    params(SeriesQuery),
+    responses(
+        (status = 200, description = "The number of clients over time", body = SeriesResponse),
+        (status = 400, description = "Invalid range or step", body = ErrorResponse),
+    )
+)]
+async fn route_clients(
+    State(store): State<SharedStore>,
+    Query(query): Query<SeriesQuery>,
+) -> Result<Response, ApiError> {
+    let now = Utc::now();
+    let to = query.to.unwrap_or(now);
+    let from = match query.from {
+        Some(from) => from,
+        None => to
+            .checked_sub_signed(Duration::days(1))
+            .ok_or_else(|| ApiError::BadRequest(format!("{to} is out of range")))?,
+    };
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+    let step = query
+        .step
+        .as_deref()
+        .map(series::parse_duration)
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let filter = Filter {
+        access_point: query.ap,
+        interface: query.interface,
+        ssid: query.ssid,
+    };
+
+    let (step, points) = store.lock().await.query(&filter, from, to, step, now);
+    Ok(Json(SeriesResponse {
+        from,
+        to,
+        step_seconds: step.num_seconds(),
+        points,
+    })
+    .into_response())
+}
@@ -0,0 +1,529 @@
+//! Checks every documented route against the schema it is documented with, so the
+//! OpenAPI document can't drift from what the API actually returns.
+
+use super::router;
+use crate::{
+    audit::AuditLog,
+    auth::Auth,
+    db::{Database, DB},
+    history::History,
+    series::{Store, Tier},
+    status::Status,
+    storage::conformance,
+};
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    Router,
+};
+use chrono::{Duration, SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Mutex;
+use tower::ServiceExt;
+
+/// The values substituted for path parameters, all of which the fixture knows about.
+const PARAMETERS: &[(&str, &str)] = &[
+    ("mac", "04:17:b6:37:96:dc"),
+    ("identity", "alice@example.com"),
+    ("ap", "garage-ap"),
+    ("interface", "wlan0"),
+    ("ssid", "home"),
+    ("vlan", "20"),
+];
+
+/// The query parameters set on every request that takes them, so the reports and series
+/// cover the day the fixture log is from.
+const QUERY: &[(&str, &str)] = &[
+    ("day", "2024-01-02"),
+    ("from", "2024-01-02T00:00:00Z"),
+    ("to", "2024-01-03T00:00:00Z"),
+    ("step", "1h"),
+];
+
+const TOKEN: &str = "conformance";
+
+/// Numbers the fixtures, so that tests running at once keep their series apart.
+static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// The router over the fixture log, and the directory its series are kept in.
+async fn fixture() -> (Router, PathBuf) {
+    fixture_over(History::new(Database::new(), None)).await
+}
+
+/// Like [`fixture`], keeping the history in `history`, which is pruned once the log is
+/// applied.
+async fn fixture_over(history: History) -> (Router, PathBuf) {
+    let db = DB::new(Box::new(Database::new()), Box::new(history)).unwrap();
+    db.apply(conformance::records()).await;
+    db.prune_history(Utc::now()).await.unwrap();
+
+    let dir = std::env::temp_dir().join(format!(
+        "hostapd-api-openapi-{}-{}",
+        std::process::id(),
+        FIXTURES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let tier = Tier {
+        step: Duration::minutes(1),
+        retention: Duration::days(36500),
+    };
+    let mut store = Store::open(&dir, &[tier]).expect("the series store opens");
+    let start = Utc.with_ymd_and_hms(2024, 1, 2, 12, 0, 0).unwrap();
+    for minutes in 0..60 {
+        store
+            .sample(&*db.read(), start + Duration::minutes(minutes))
+            .expect("the sample is kept");
+    }
+
+    let auth = Auth::load(None, Some(&format!("{TOKEN}:presence,history,admin")))
+        .await
+        .expect("the token is valid");
+    let audit = AuditLog::open(None).await.expect("the audit log opens");
+    let status = Status::new(Duration::days(36500), false);
+    status.set_tailing(true);
+    let status = Arc::new(status);
+    let store = Some(Arc::new(Mutex::new(store)));
+    (router(db, status, auth, audit, store, None), dir)
+}
+
+async fn call(
+    router: &Router,
+    method: &Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, format!("Bearer {TOKEN}"))
+        .header("content-type", "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .expect("the request is valid");
+    let response = router
+        .clone()
+        .oneshot(request)
+        .await
+        .expect("the router is infallible");
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("the body is read");
+    let body = if bytes.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&bytes)
+            .unwrap_or_else(|e| panic!("{method} {uri} returned invalid JSON: {e}"))
+    };
+    (status, body)
+}
+
+/// Fills in the path parameters of a documented path, and the query parameters the
+/// operation takes from [`QUERY`].
+fn uri(path: &str, operation: &Value, parameters: &[(&str, &str)]) -> String {
+    let mut uri = path.to_string();
+    for (name, value) in parameters {
+        uri = uri.replace(&format!("{{{name}}}"), value);
+    }
+    assert!(
+        !uri.contains('{'),
+        "{path} has a parameter without a fixture"
+    );
+    let takes: BTreeSet<&str> = operation["parameters"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|parameter| parameter["in"] == "query")
+        .filter_map(|parameter| parameter["name"].as_str())
+        .collect();
+    let query: Vec<String> = QUERY
+        .iter()
+        .filter(|(name, _)| takes.contains(name))
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    if !query.is_empty() {
+        uri = format!("{uri}?{}", query.join("&"));
+    }
+    uri
+}
+
+struct Validator<'a> {
+    document: &'a Value,
+    errors: Vec<String>,
+}
+
+impl Validator<'_> {
+    fn resolve<'s>(&'s self, schema: &'s Value) -> &'s Value {
+        match schema["$ref"].as_str() {
+            Some(reference) => {
+                let pointer = reference.trim_start_matches('#');
+                let target = self
+                    .document
+                    .pointer(pointer)
+                    .unwrap_or_else(|| panic!("{reference} doesn't resolve"));
+                self.resolve(target)
+            }
+            None => schema,
+        }
+    }
+
+    /// The properties an object matching `schema` may have, or `None` if it may have any.
+    fn properties(&self, schema: &Value) -> Option<BTreeSet<String>> {
+        let schema = self.resolve(schema);
+        if schema
+            .get("additionalProperties")
+            .is_some_and(|a| a != false)
+        {
+            return None;
+        }
+        let mut properties: BTreeSet<String> = schema["properties"]
+            .as_object()
+            .map(|properties| properties.keys().cloned().collect())
+            .unwrap_or_default();
+        for key in ["allOf", "oneOf", "anyOf"] {
+            for member in schema[key].as_array().into_iter().flatten() {
+                properties.extend(self.properties(member)?);
+            }
+        }
+        Some(properties)
+    }
+
+    fn fail(&mut self, at: &str, message: impl std::fmt::Display) {
+        self.errors.push(format!("{at}: {message}"));
+    }
+
+    /// Checks `value` against `schema`. Properties that no part of a composed schema
+    /// declares are only checked at the top of the composition, by `closed`.
+    fn validate(&mut self, value: &Value, schema: &Value, at: &str, closed: bool) {
+        let schema = self.resolve(schema).clone();
+
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect(),
+            };
+            let matches = types.iter().any(|t| match *t {
+                "null" => value.is_null(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "string" => value.is_string(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                _ => false,
+            });
+            if !matches {
+                self.fail(at, format!("expected {types:?}, got {value}"));
+                return;
+            }
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            if !values.contains(value) {
+                self.fail(at, format!("{value} isn't one of {values:?}"));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema["minimum"].as_f64(), value.as_f64()) {
+            if number < minimum {
+                self.fail(at, format!("{number} is below {minimum}"));
+            }
+        }
+
+        for member in schema["allOf"].as_array().into_iter().flatten() {
+            self.validate(value, member, at, false);
+        }
+        for key in ["oneOf", "anyOf"] {
+            if let Some(members) = schema[key].as_array() {
+                let matched = members.iter().any(|member| {
+                    let mut validator = Validator {
+                        document: self.document,
+                        errors: Vec::new(),
+                    };
+                    validator.validate(value, member, at, true);
+                    validator.errors.is_empty()
+                });
+                if !matched {
+                    self.fail(at, format!("{value} matches none of {key}"));
+                }
+            }
+        }
+
+        if let Value::Array(items) = value {
+            if let Some(schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    self.validate(item, schema, &format!("{at}[{i}]"), true);
+                }
+            }
+        }
+        if let Value::Object(object) = value {
+            self.validate_object(object, &schema, at, closed);
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        object: &Map<String, Value>,
+        schema: &Value,
+        at: &str,
+        closed: bool,
+    ) {
+        for required in schema["required"].as_array().into_iter().flatten() {
+            let required = required.as_str().unwrap_or_default();
+            if !object.contains_key(required) {
+                self.fail(at, format!("is missing {required}"));
+            }
+        }
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties {
+                if let Some(value) = object.get(name) {
+                    self.validate(value, property, &format!("{at}.{name}"), true);
+                }
+            }
+        }
+        if let Some(additional) = schema.get("additionalProperties").filter(|a| a.is_object()) {
+            let declared = schema["properties"].as_object();
+            for (name, value) in object {
+                if declared.map_or(true, |declared| !declared.contains_key(name)) {
+                    self.validate(value, additional, &format!("{at}.{name}"), true);
+                }
+            }
+        }
+        if let Some(names) = schema.get("propertyNames") {
+            for name in object.keys() {
+                self.validate(&json!(name), names, &format!("{at}.{name}"), true);
+            }
+        }
+        if closed {
+            if let Some(declared) = self.properties(schema) {
+                for name in object.keys().filter(|name| !declared.contains(*name)) {
+                    self.fail(at, format!("has undeclared property {name}"));
+                }
+            }
+        }
+    }
+}
+
+/// Checks a response against the schema documented for its status.
+fn check(
+    document: &Value,
+    operation: &Value,
+    what: &str,
+    status: StatusCode,
+    body: &Value,
+) -> Vec<String> {
+    let Some(response) = operation["responses"].get(status.as_str()) else {
+        return vec![format!("{what}: returned undocumented status {status}")];
+    };
+    let Some(schema) = response.pointer("/content/application~1json/schema") else {
+        return if body.is_null() {
+            Vec::new()
+        } else {
+            vec![format!(
+                "{what}: returned a body for {status}, which documents none"
+            )]
+        };
+    };
+    let mut validator = Validator {
+        document,
+        errors: Vec::new(),
+    };
+    validator.validate(body, schema, what, true);
+    validator.errors
+}
+
+#[tokio::test]
+async fn routes_match_their_schemas() {
+    let (router, dir) = fixture().await;
+    let (status, document) = call(&router, &Method::GET, "/openapi.json", None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut operations = Vec::new();
+    for (path, item) in document["paths"].as_object().expect("there are paths") {
+        for (method, operation) in item.as_object().expect("the path is an object") {
+            let method: Method = method.to_uppercase().parse().expect("the method is valid");
+            operations.push((path.clone(), method, operation.clone()));
+        }
+    }
+    // the admin routes change the fixture, so they go last, and the device is forgotten
+    // only after everything else has looked at it
+    operations
+        .sort_by_key(|(path, method, _)| (path.contains("/admin/"), *method == Method::DELETE));
+
+    let unknown = [
+        ("mac", "00:00:00:00:00:01"),
+        ("identity", "nobody@example.com"),
+        ("ap", "unknown-ap"),
+        ("interface", "wlan9"),
+        ("ssid", "unknown"),
+        ("vlan", "999"),
+    ];
+    let mut errors = Vec::new();
+    for (path, method, operation) in &operations {
+        let body = operation
+            .get("requestBody")
+            .map(|_| json!({ "from": "32:42:fd:88:86:0c" }));
+        for parameters in [PARAMETERS, &unknown] {
+            let uri = uri(path, operation, parameters);
+            let (status, response) = call(&router, method, &uri, body.clone()).await;
+            let what = format!("{method} {uri}");
+            errors.extend(check(&document, operation, &what, status, &response));
+            if parameters == PARAMETERS && !status.is_success() {
+                errors.push(format!("{what}: returned {status} for the fixture"));
+            }
+            if path.contains('{') {
+                continue;
+            }
+            break;
+        }
+    }
+    let _ = std::fs::remove_dir_all(dir);
+    assert!(errors.is_empty(), "{}", errors.join("\n"));
+}
+
+#[tokio::test]
+async fn series_rejects_dates_out_of_range() {
+    let (router, dir) = fixture().await;
+    for uri in [
+        "/api/v2/series/clients?to=-262144-01-01T12:00:00Z",
+        "/api/v2/series/clients?step=25269512338830y",
+    ] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn admin_changes_are_replayed() {
+    let (router, dir) = fixture().await;
+    let mac = "aa:bb:cc:dd:ee:ff";
+    let (status, body) = call(
+        &router,
+        &Method::DELETE,
+        &format!("/api/v2/admin/mac/{mac}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{body}");
+
+    let at = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+    let (status, body) = call(&router, &Method::GET, &format!("/api/v2?at={at}"), None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let macs: Vec<&Value> = body["devices"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| &d["mac"])
+        .collect();
+    assert!(!macs.contains(&&Value::from(mac)), "{body}");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn vlans_are_validated_and_broken_down() {
+    let (router, dir) = fixture().await;
+    for uri in ["/api/v1/vlan/iot", "/api/v2/vlan/65536"] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+        assert!(body["error"].is_string(), "{uri}: {body}");
+    }
+
+    let (status, body) = call(&router, &Method::GET, "/api/v2/map/stations", None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let tagged = body["vlans"]
+        .as_object()
+        .unwrap()
+        .values()
+        .flat_map(|interfaces| interfaces.as_object().unwrap().values())
+        .any(|vlans| vlans.get("20").is_some());
+    assert!(tagged, "{body}");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn malformed_paths_and_queries_get_json_errors() {
+    let (router, dir) = fixture().await;
+    for uri in [
+        "/api/v1/mac/%FF",
+        "/api/v2/mac/%FF",
+        "/api/v1?limit=all",
+        "/api/v2/online?sort=nowhere",
+        "/api/v2/map?at=yesterday",
+        "/api/v2/series/clients?step=1",
+    ] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+        assert!(body["error"].is_string(), "{uri}: {body}");
+    }
+    let (status, body) = call(&router, &Method::GET, "/api/v2/mac/04:17-b6:37-96:dc", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn vendors_match_in_every_notation() {
+    let (router, dir) = fixture().await;
+    let mut totals = BTreeSet::new();
+    for vendor in ["04:17:b6", "04-17-B6", "0417.b6", "0417b6"] {
+        let uri = format!("/api/v2?vendor={vendor}");
+        let (status, body) = call(&router, &Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+        totals.insert(body["total"].as_u64().unwrap());
+    }
+    assert_eq!(totals.len(), 1, "{totals:?}");
+    assert!(totals.first().is_some_and(|total| *total > 0));
+
+    for vendor in ["04:17", "04:17-b6", "apple"] {
+        let uri = format!("/api/v2?vendor={vendor}");
+        let (status, body) = call(&router, &Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn routes_refuse_filters_that_contradict_them() {
+    let (router, dir) = fixture().await;
+    for uri in [
+        "/api/v1/offline?online=true",
+        "/api/v2/offline?online=true",
+        "/api/v2/online?online=false",
+        "/api/v2/vlan/20?vlan=30",
+        "/api/v2/ap/garage-ap?ap=unknown-ap",
+    ] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+        assert!(body["error"].is_string(), "{uri}: {body}");
+    }
+    for uri in [
+        "/api/v2/offline?online=false",
+        "/api/v2/ap/garage-ap?ap=garage-ap",
+    ] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn days_before_the_history_are_refused() {
+    let (router, dir) = fixture_over(History::new(Database::new(), Some(Duration::days(7)))).await;
+    for uri in [
+        "/api/v2/reports/daily?day=2024-01-02",
+        "/api/v2/users/alice@example.com/timeline?day=2024-01-02",
+        "/api/v2/mac/04:17:b6:37:96:dc/timeline?day=2024-01-02",
+    ] {
+        let (status, body) = call(&router, &Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {body}");
+        assert!(body["error"].is_string(), "{uri}: {body}");
+    }
+    let (status, body) = call(&router, &Method::GET, "/api/v2/reports/daily", None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let _ = std::fs::remove_dir_all(dir);
+}
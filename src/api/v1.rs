@@ -0,0 +1,448 @@
+use super::{
+    extract::{Path, Query},
+    narrow, ApiError, AsOf, ErrorResponse,
+};
+use crate::{
+    db::{self, DeviceListItem, DeviceMapItem, DeviceQuery, DB},
+    mac::MacAddr,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use utoipa::{OpenApi, ToSchema};
+
+/// The original API. It is served under `/api/v1` and, for compatibility with existing
+/// clients, without a prefix.
+#[derive(OpenApi)]
+#[openapi(paths(
+    route_index,
+    route_mac_get,
+    route_user_get,
+    route_station_index,
+    route_ap_index,
+    route_ap_get,
+    route_ap_interface_get,
+    route_interface_get,
+    route_vlan_get,
+    route_ssid_get,
+    route_online,
+    route_offline,
+    route_map,
+    route_map_stations,
+))]
+pub struct V1Doc;
+
+pub fn router() -> Router<DB> {
+    Router::new()
+        .route("/", get(route_index))
+        .route("/mac/:mac", get(route_mac_get))
+        .route("/users/:identity", get(route_user_get))
+        .route("/stations", get(route_station_index))
+        .route("/ap", get(route_ap_index))
+        .route("/ap/:ap", get(route_ap_get))
+        .route("/ap/:ap/:interface", get(route_ap_interface_get))
+        .route("/interface/:interface", get(route_interface_get))
+        .route("/vlan/:vlan", get(route_vlan_get))
+        .route("/ssid/:ssid", get(route_ssid_get))
+        .route("/online", get(route_online))
+        .route("/offline", get(route_offline))
+        .route("/map", get(route_map))
+        .route("/map/stations", get(route_map_stations))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceList {
+    devices: Vec<DeviceListItem>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceResponse {
+    device: DeviceListItem,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StationIndex {
+    /// Interfaces with associated devices, keyed by access point.
+    stations: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccessPointIndex {
+    access_points: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceMap<'a> {
+    /// Online devices, keyed by access point.
+    device_map: BTreeMap<&'a str, Vec<DeviceMapItem<'a>>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StationMap<'a> {
+    /// Online devices, keyed by access point and then interface.
+    station_map: BTreeMap<&'a str, BTreeMap<&'a str, Vec<DeviceMapItem<'a>>>>,
+    /// The MAC addresses of the same devices, keyed by access point, interface and then
+    /// VLAN (or `untagged`).
+    vlans: BTreeMap<&'a str, BTreeMap<&'a str, BTreeMap<String, Vec<MacAddr>>>>,
+}
+
+/// The devices in `devices` on each access point, once for every interface of it they are
+/// on.
+fn device_map(devices: &[DeviceListItem]) -> BTreeMap<&str, Vec<DeviceMapItem<'_>>> {
+    let mut map: BTreeMap<&str, Vec<DeviceMapItem>> = BTreeMap::new();
+    for item in devices {
+        for station in &item.stations {
+            map.entry(&station.station.hostname)
+                .or_default()
+                .push(item.device.map_item(item.mac));
+        }
+    }
+    map
+}
+
+/// The devices in `devices` on each access point, and then on each interface.
+fn station_map(
+    devices: &[DeviceListItem],
+) -> BTreeMap<&str, BTreeMap<&str, Vec<DeviceMapItem<'_>>>> {
+    let mut map: BTreeMap<&str, BTreeMap<&str, Vec<DeviceMapItem>>> = BTreeMap::new();
+    for item in devices {
+        for station in &item.stations {
+            map.entry(&station.station.hostname)
+                .or_default()
+                .entry(&station.station.interface)
+                .or_default()
+                .push(item.device.map_item(item.mac));
+        }
+    }
+    map
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    params(DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "All devices", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`", body = ErrorResponse),
+    )
+)]
+async fn route_index(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let db = as_of.database(&db).await?;
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations",
+    responses((status = 200, description = "Interfaces with associated devices", body = StationIndex))
+)]
+async fn route_station_index(State(db): State<DB>) -> Result<Response, ApiError> {
+    let mut stations: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (station, clients) in db.read().station_clients()? {
+        if clients > 0 {
+            stations
+                .entry(station.hostname)
+                .or_default()
+                .insert(station.interface);
+        }
+    }
+
+    Ok(Json(StationIndex { stations }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap",
+    responses((status = 200, description = "Access points with associated devices", body = AccessPointIndex))
+)]
+async fn route_ap_index(State(db): State<DB>) -> Result<Response, ApiError> {
+    let access_points = db
+        .read()
+        .access_point_clients()?
+        .into_iter()
+        .filter(|(_, clients)| *clients > 0)
+        .map(|(ap, _)| ap)
+        .collect();
+
+    Ok(Json(AccessPointIndex { access_points }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/map",
+    params(AsOf),
+    responses(
+        (status = 200, description = "Online devices by access point", body = DeviceMap),
+        (status = 400, description = "The history doesn't go back to `at`", body = ErrorResponse),
+    )
+)]
+async fn route_map(State(db): State<DB>, Query(as_of): Query<AsOf>) -> Result<Response, ApiError> {
+    let db = as_of.database(&db).await?;
+    let devices = db.device_list(&DeviceQuery::default().online(true))?;
+
+    Ok(Json(DeviceMap {
+        device_map: device_map(&devices),
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/map/stations",
+    responses((status = 200, description = "Online devices by access point and interface, and by VLAN", body = StationMap))
+)]
+async fn route_map_stations(State(db): State<DB>) -> Result<Response, ApiError> {
+    let devices = db
+        .read()
+        .device_list(&DeviceQuery::default().online(true))?;
+
+    Ok(Json(StationMap {
+        station_map: station_map(&devices),
+        vlans: db::station_vlans(&devices),
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/mac/{mac}",
+    params(("mac" = String, Path, description = "MAC address in any common notation")),
+    responses(
+        (status = 200, description = "The device", body = DeviceResponse),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+async fn route_mac_get(
+    State(db): State<DB>,
+    Path(mac): Path<String>,
+) -> Result<Response, ApiError> {
+    let mac = mac.parse::<MacAddr>()?;
+    let device = db.read().get(mac)?.ok_or(ApiError::DeviceNotFound(mac))?;
+
+    Ok(Json(DeviceResponse { device }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{identity}",
+    params(("identity" = String, Path, description = "802.1X (EAP) identity"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices that authenticated with the identity", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_user_get(
+    State(db): State<DB>,
+    Path(identity): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().identity(identity))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ssid/{ssid}",
+    params(("ssid" = String, Path, description = "SSID"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices connected to the SSID", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_ssid_get(
+    State(db): State<DB>,
+    Path(ssid): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().ssid(ssid))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap/{ap}",
+    params(("ap" = String, Path, description = "Access point hostname"), DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "Devices associated with the access point", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`, or a query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown access point", body = ErrorResponse),
+    )
+)]
+async fn route_ap_get(
+    State(db): State<DB>,
+    Path(ap): Path<String>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().ap(ap.clone()))?;
+    let db = as_of.database(&db).await?;
+    if !db.knows_ap(&ap) {
+        return Err(ApiError::AccessPointNotFound(ap));
+    }
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap/{ap}/{interface}",
+    params(
+        ("ap" = String, Path, description = "Access point hostname"),
+        ("interface" = String, Path, description = "Interface name"),
+        DeviceQuery,
+    ),
+    responses(
+        (status = 200, description = "Devices associated with the interface", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown access point or interface", body = ErrorResponse),
+    )
+)]
+async fn route_ap_interface_get(
+    State(db): State<DB>,
+    Path((ap, interface)): Path<(String, String)>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(
+        query,
+        DeviceQuery::default()
+            .ap(ap.clone())
+            .interface(interface.clone()),
+    )?;
+    let db = db.read();
+    if !db.knows_ap(&ap) {
+        return Err(ApiError::AccessPointNotFound(ap));
+    }
+    if !db.knows_station(&ap, &interface) {
+        return Err(ApiError::InterfaceNotFound(format!("{ap}@{interface}")));
+    }
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/interface/{interface}",
+    params(("interface" = String, Path, description = "Interface name"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices associated with the interface on any access point", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown interface", body = ErrorResponse),
+    )
+)]
+async fn route_interface_get(
+    State(db): State<DB>,
+    Path(interface): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().interface(interface.clone()))?;
+    let db = db.read();
+    if !db.knows_interface(&interface) {
+        return Err(ApiError::InterfaceNotFound(interface));
+    }
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/vlan/{vlan}",
+    params(("vlan" = u16, Path, description = "VLAN ID"), DeviceQuery),
+    responses(
+        (status = 200, description = "Online devices on the VLAN", body = DeviceList),
+        (status = 400, description = "Invalid VLAN ID, or a query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_vlan_get(
+    State(db): State<DB>,
+    Path(vlan): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let vlan = vlan
+        .parse::<u16>()
+        .map_err(|_| ApiError::InvalidVlan(vlan))?;
+    let query = narrow(query, DeviceQuery::default().online(true).vlan(vlan))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/online",
+    params(DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "Online devices", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`, or a query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_online(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().online(true))?;
+    let db = as_of.database(&db).await?;
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/offline",
+    params(DeviceQuery),
+    responses(
+        (status = 200, description = "Offline devices", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_offline(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().online(false))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList {
+        devices: db.device_list(&query)?,
+    })
+    .into_response())
+}
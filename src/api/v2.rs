@@ -0,0 +1,577 @@
+use super::{
+    extract::{Path, Query},
+    narrow, ApiError, AsOf, ErrorResponse,
+};
+use crate::{
+    db::{self, DeviceListItem, DeviceQuery, StationItem, DB},
+    interface::{Band, InterfaceInfo},
+    mac::MacAddr,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::{OpenApi, ToSchema};
+
+/// The current API. Devices carry their full station metadata, authentication and VLAN
+/// details, and lists report how many devices matched before pagination.
+#[derive(OpenApi)]
+#[openapi(paths(
+    route_devices,
+    route_device_get,
+    route_online,
+    route_offline,
+    route_user_get,
+    route_ap_index,
+    route_ap_get,
+    route_ap_interface_get,
+    route_interface_get,
+    route_vlan_get,
+    route_ssid_get,
+    route_stations,
+    route_map,
+    route_map_stations,
+))]
+pub struct V2Doc;
+
+pub fn router() -> Router<DB> {
+    Router::new()
+        .route("/", get(route_devices))
+        .route("/mac/:mac", get(route_device_get))
+        .route("/online", get(route_online))
+        .route("/offline", get(route_offline))
+        .route("/users/:identity", get(route_user_get))
+        .route("/ap", get(route_ap_index))
+        .route("/ap/:ap", get(route_ap_get))
+        .route("/ap/:ap/:interface", get(route_ap_interface_get))
+        .route("/interface/:interface", get(route_interface_get))
+        .route("/vlan/:vlan", get(route_vlan_get))
+        .route("/ssid/:ssid", get(route_ssid_get))
+        .route("/stations", get(route_stations))
+        .route("/map", get(route_map))
+        .route("/map/stations", get(route_map_stations))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::Station)]
+pub struct Station<'a> {
+    access_point: &'a str,
+    interface: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    band: Option<Band>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+impl<'a> Station<'a> {
+    pub(super) fn new(station: &'a db::Station, info: Option<InterfaceInfo>) -> Self {
+        let info = info.unwrap_or_default();
+        Self {
+            access_point: &station.hostname,
+            interface: &station.interface,
+            ssid: info.ssid,
+            band: info.band,
+            channel: info.channel,
+            frequency: info.frequency,
+            label: info.label,
+        }
+    }
+}
+
+impl<'a> From<&'a StationItem> for Station<'a> {
+    fn from(item: &'a StationItem) -> Self {
+        Self::new(&item.station, item.info.clone())
+    }
+}
+
+/// How the device authenticated, on 802.1X (WPA-Enterprise) networks.
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::Authentication)]
+pub struct Authentication<'a> {
+    identity: Option<&'a str>,
+    accounting_session: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::Vlan)]
+pub struct Vlan<'a> {
+    id: Option<u16>,
+    interface: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::Device)]
+pub struct Device<'a> {
+    mac: MacAddr,
+    online: bool,
+    stations: Vec<Station<'a>>,
+    last_associated: Option<DateTime<Utc>>,
+    last_disassociated: Option<DateTime<Utc>>,
+    last_observed: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authentication: Option<Authentication<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan: Option<Vlan<'a>>,
+}
+
+impl<'a> From<&'a DeviceListItem> for Device<'a> {
+    fn from(item: &'a DeviceListItem) -> Self {
+        let device = &item.device;
+        let authentication = (device.identity.is_some() || device.accounting_session.is_some())
+            .then_some(Authentication {
+                identity: device.identity.as_deref(),
+                accounting_session: device.accounting_session.as_deref(),
+            });
+        let vlan = (device.vlan.is_some() || device.vlan_interface.is_some()).then_some(Vlan {
+            id: device.vlan,
+            interface: device.vlan_interface.as_deref(),
+        });
+
+        Self {
+            mac: item.mac,
+            online: item.online,
+            stations: item.stations.iter().map(Into::into).collect(),
+            last_associated: device.last_associated,
+            last_disassociated: device.last_disassociated,
+            last_observed: device.last_observed,
+            last_seen: device.last_seen(),
+            authentication,
+            vlan,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::DeviceList)]
+pub struct DeviceList<'a> {
+    /// The number of devices that matched, before `limit` and `offset` were applied.
+    total: usize,
+    devices: Vec<Device<'a>>,
+}
+
+impl<'a> From<&'a (usize, Vec<DeviceListItem>)> for DeviceList<'a> {
+    fn from((total, devices): &'a (usize, Vec<DeviceListItem>)) -> Self {
+        Self {
+            total: *total,
+            devices: devices.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::DeviceResponse)]
+pub struct DeviceResponse<'a> {
+    device: Device<'a>,
+}
+
+impl<'a> From<&'a DeviceListItem> for DeviceResponse<'a> {
+    fn from(item: &'a DeviceListItem) -> Self {
+        Self {
+            device: item.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::StationSummary)]
+pub struct StationSummary<'a> {
+    #[serde(flatten)]
+    station: Station<'a>,
+    /// The number of devices currently associated.
+    clients: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::StationList)]
+pub struct StationList<'a> {
+    stations: Vec<StationSummary<'a>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::AccessPoint)]
+pub struct AccessPoint<'a> {
+    name: &'a str,
+    /// The number of devices currently associated.
+    clients: usize,
+    interfaces: Vec<&'a str>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::AccessPointList)]
+pub struct AccessPointList<'a> {
+    access_points: Vec<AccessPoint<'a>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::DeviceMap)]
+pub struct DeviceMap<'a> {
+    /// Online devices, keyed by access point.
+    access_points: BTreeMap<&'a str, Vec<Device<'a>>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = v2::StationMap)]
+pub struct StationMap<'a> {
+    /// Online devices, keyed by access point and then interface.
+    access_points: BTreeMap<&'a str, BTreeMap<&'a str, Vec<Device<'a>>>>,
+    /// The MAC addresses of the same devices, keyed by access point, interface and then
+    /// VLAN (or `untagged`).
+    vlans: BTreeMap<&'a str, BTreeMap<&'a str, BTreeMap<String, Vec<MacAddr>>>>,
+}
+
+/// The devices in `devices`, once for every station they are on.
+fn on_stations(devices: &[DeviceListItem]) -> Vec<(&db::Station, Device<'_>)> {
+    devices
+        .iter()
+        .flat_map(|item| {
+            item.stations
+                .iter()
+                .map(move |station| (&station.station, item.into()))
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    params(DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "All devices", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`", body = ErrorResponse),
+    )
+)]
+async fn route_devices(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let db = as_of.database(&db).await?;
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/mac/{mac}",
+    params(("mac" = String, Path, description = "MAC address in any common notation")),
+    responses(
+        (status = 200, description = "The device", body = DeviceResponse),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+async fn route_device_get(
+    State(db): State<DB>,
+    Path(mac): Path<String>,
+) -> Result<Response, ApiError> {
+    let mac = mac.parse::<MacAddr>()?;
+    let device = db.read().get(mac)?.ok_or(ApiError::DeviceNotFound(mac))?;
+
+    Ok(Json(DeviceResponse::from(&device)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/online",
+    params(DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "Online devices", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`, or a query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_online(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().online(true))?;
+    let db = as_of.database(&db).await?;
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/offline",
+    params(DeviceQuery),
+    responses(
+        (status = 200, description = "Offline devices", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_offline(
+    State(db): State<DB>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().online(false))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{identity}",
+    params(("identity" = String, Path, description = "802.1X (EAP) identity"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices that authenticated with the identity", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_user_get(
+    State(db): State<DB>,
+    Path(identity): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().identity(identity))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap",
+    responses((status = 200, description = "Every known access point", body = AccessPointList))
+)]
+async fn route_ap_index(State(db): State<DB>) -> Result<Response, ApiError> {
+    let db = db.read();
+    let stations = db.station_clients()?;
+    let clients = db.access_point_clients()?;
+    let mut access_points: BTreeMap<&str, AccessPoint> = BTreeMap::new();
+    for station in stations.keys() {
+        access_points
+            .entry(&station.hostname)
+            .or_insert_with(|| AccessPoint {
+                name: &station.hostname,
+                clients: clients.get(&station.hostname).copied().unwrap_or_default(),
+                interfaces: Vec::new(),
+            })
+            .interfaces
+            .push(&station.interface);
+    }
+
+    Ok(Json(AccessPointList {
+        access_points: access_points.into_values().collect(),
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap/{ap}",
+    params(("ap" = String, Path, description = "Access point hostname"), DeviceQuery, AsOf),
+    responses(
+        (status = 200, description = "Devices associated with the access point", body = DeviceList),
+        (status = 400, description = "The history doesn't go back to `at`, or a query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown access point", body = ErrorResponse),
+    )
+)]
+async fn route_ap_get(
+    State(db): State<DB>,
+    Path(ap): Path<String>,
+    Query(query): Query<DeviceQuery>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().ap(ap.clone()))?;
+    let db = as_of.database(&db).await?;
+    if !db.knows_ap(&ap) {
+        return Err(ApiError::AccessPointNotFound(ap));
+    }
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ap/{ap}/{interface}",
+    params(
+        ("ap" = String, Path, description = "Access point hostname"),
+        ("interface" = String, Path, description = "Interface name"),
+        DeviceQuery,
+    ),
+    responses(
+        (status = 200, description = "Devices associated with the interface", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown access point or interface", body = ErrorResponse),
+    )
+)]
+async fn route_ap_interface_get(
+    State(db): State<DB>,
+    Path((ap, interface)): Path<(String, String)>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(
+        query,
+        DeviceQuery::default()
+            .ap(ap.clone())
+            .interface(interface.clone()),
+    )?;
+    let db = db.read();
+    if !db.knows_ap(&ap) {
+        return Err(ApiError::AccessPointNotFound(ap));
+    }
+    if !db.knows_station(&ap, &interface) {
+        return Err(ApiError::InterfaceNotFound(format!("{ap}@{interface}")));
+    }
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/interface/{interface}",
+    params(("interface" = String, Path, description = "Interface name"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices associated with the interface on any access point", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+        (status = 404, description = "Unknown interface", body = ErrorResponse),
+    )
+)]
+async fn route_interface_get(
+    State(db): State<DB>,
+    Path(interface): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().interface(interface.clone()))?;
+    let db = db.read();
+    if !db.knows_interface(&interface) {
+        return Err(ApiError::InterfaceNotFound(interface));
+    }
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/vlan/{vlan}",
+    params(("vlan" = u16, Path, description = "VLAN ID"), DeviceQuery),
+    responses(
+        (status = 200, description = "Online devices on the VLAN", body = DeviceList),
+        (status = 400, description = "Invalid VLAN ID, or a query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_vlan_get(
+    State(db): State<DB>,
+    Path(vlan): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let vlan = vlan
+        .parse::<u16>()
+        .map_err(|_| ApiError::InvalidVlan(vlan))?;
+    let query = narrow(query, DeviceQuery::default().online(true).vlan(vlan))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ssid/{ssid}",
+    params(("ssid" = String, Path, description = "SSID"), DeviceQuery),
+    responses(
+        (status = 200, description = "Devices connected to the SSID", body = DeviceList),
+        (status = 400, description = "A query parameter contradicts the route", body = ErrorResponse),
+    )
+)]
+async fn route_ssid_get(
+    State(db): State<DB>,
+    Path(ssid): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Response, ApiError> {
+    let query = narrow(query, DeviceQuery::default().ssid(ssid))?;
+    let db = db.read();
+
+    Ok(Json(DeviceList::from(&db.device_page(&query)?)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations",
+    responses((status = 200, description = "Every known station and its metadata", body = StationList))
+)]
+async fn route_stations(State(db): State<DB>) -> Result<Response, ApiError> {
+    let db = db.read();
+    let clients = db.station_clients()?;
+    let stations = clients
+        .iter()
+        .map(|(station, clients)| StationSummary {
+            station: Station::new(station, db.interface_info(station)),
+            clients: *clients,
+        })
+        .collect();
+
+    Ok(Json(StationList { stations }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/map",
+    params(AsOf),
+    responses(
+        (status = 200, description = "Online devices by access point", body = DeviceMap),
+        (status = 400, description = "The history doesn't go back to `at`", body = ErrorResponse),
+    )
+)]
+async fn route_map(State(db): State<DB>, Query(as_of): Query<AsOf>) -> Result<Response, ApiError> {
+    let devices = as_of
+        .database(&db)
+        .await?
+        .device_list(&DeviceQuery::default().online(true))?;
+    let mut access_points: BTreeMap<&str, Vec<Device>> = BTreeMap::new();
+    for (station, device) in on_stations(&devices) {
+        access_points
+            .entry(&station.hostname)
+            .or_default()
+            .push(device);
+    }
+
+    Ok(Json(DeviceMap { access_points }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/map/stations",
+    params(AsOf),
+    responses(
+        (status = 200, description = "Online devices by access point and interface, and by VLAN", body = StationMap),
+        (status = 400, description = "The history doesn't go back to `at`", body = ErrorResponse),
+    )
+)]
+async fn route_map_stations(
+    State(db): State<DB>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Response, ApiError> {
+    let devices = as_of
+        .database(&db)
+        .await?
+        .device_list(&DeviceQuery::default().online(true))?;
+    let mut access_points: BTreeMap<&str, BTreeMap<&str, Vec<Device>>> = BTreeMap::new();
+    for (station, device) in on_stations(&devices) {
+        access_points
+            .entry(&station.hostname)
+            .or_default()
+            .entry(&station.interface)
+            .or_default()
+            .push(device);
+    }
+    let vlans = db::station_vlans(&devices);
+
+    Ok(Json(StationMap {
+        access_points,
+        vlans,
+    })
+    .into_response())
+}
@@ -1,17 +1,172 @@
-use clap::Parser;
-use std::{net::SocketAddr, path::PathBuf};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use hostapd_api::{
+    mac::MacAddr,
+    series::{Tier, DEFAULT_TIERS},
+    server::{ListenAddr, TlsConfig},
+};
+use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Without a subcommand, the server is run with these arguments.
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Follow the log and serve the HTTP API. This is the default.
+    Serve(Box<ServeArgs>),
+    /// Run a log through the parser and the database offline, and print the devices it ends
+    /// with.
+    Replay(ReplayArgs),
+    /// Parse log lines from stdin and print the parsed records as JSON lines.
+    Parse(ParseArgs),
+    /// Query a running server.
+    Query(QueryArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ServeArgs {
     /// The file to read from. It is assumed this log will be in json format,
     /// with the following keys: host, program, timestamp, and message.
     #[arg(short, long, default_value = "/var/log/messages")]
     pub file: PathBuf,
 
-    /// The address to listen on for HTTP requests
-    #[arg(short, long, default_value = "0.0.0.0:5580")]
-    pub listen: SocketAddr,
+    /// The addresses to listen on for HTTP requests, separated by commas or given more than
+    /// once. Each is a TCP address, e.g. `[::]:5580`, a UNIX socket, e.g. `unix:/run/hostapd-api.sock`,
+    /// or `systemd` for the sockets passed by systemd socket activation.
+    #[arg(short, long, default_value = "0.0.0.0:5580", value_delimiter = ',')]
+    pub listen: Vec<ListenAddr>,
+
+    /// When the unprefixed routes, which are deprecated aliases for `/api/v1`, will stop
+    /// being served, as an RFC 3339 timestamp. It is announced in their `Sunset` header.
+    #[arg(long, value_name = "TIMESTAMP")]
+    pub unprefixed_sunset: Option<DateTime<Utc>>,
+
+    /// A JSON file mapping access points and their interfaces to SSID, band, channel and label
+    /// metadata. Use `*` as the access point to apply to every access point.
+    #[arg(long)]
+    pub interfaces: Option<PathBuf>,
+
+    /// Forget offline devices that haven't been seen for this many days.
+    #[arg(long, value_name = "DAYS")]
+    pub retention_days: Option<u32>,
+
+    /// The most devices to remember. Beyond this, the offline devices seen least recently are
+    /// forgotten.
+    #[arg(long)]
+    pub max_devices: Option<usize>,
+
+    /// A JSON list of MAC addresses that are never forgotten by `--retention-days` or
+    /// `--max-devices`.
+    #[arg(long)]
+    pub keep_devices: Option<PathBuf>,
+
+    /// How many days of events to keep for point-in-time (`?at=`) queries and the reports
+    /// worked out from them.
+    #[arg(long, value_name = "DAYS", default_value = "7")]
+    pub history_days: u32,
+
+    /// An SQLite database file to keep the history and the devices in, instead of memory.
+    /// It is created if it doesn't exist.
+    #[arg(long, env = "SQLITE_PATH", value_name = "PATH")]
+    pub sqlite: Option<PathBuf>,
+
+    /// A directory to keep time series of the client counts on every station in, for
+    /// `/api/v2/series/clients`. Without it, no series are kept.
+    #[arg(long, env = "SERIES_DIR")]
+    pub series_dir: Option<PathBuf>,
+
+    /// How often to sample the client counts for the time series.
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub series_interval: u32,
+
+    /// The resolutions the time series are downsampled to, and how long each is kept, as
+    /// `STEP:RETENTION` separated by commas. Units are `s`, `m`, `h`, `d`, `w` and `y`.
+    #[arg(long, default_value = DEFAULT_TIERS, value_delimiter = ',')]
+    pub series_tiers: Vec<Tier>,
+
+    /// A JSON file of credentials for the HTTP API. Each entry is either a bearer token
+    /// (`{"token": "...", "scopes": [...]}`) or a basic auth user (`{"username": "...",
+    /// "password": "...", "scopes": [...]}`). Scopes are `presence`, `history` and `admin`.
+    #[arg(long, env = "AUTH_FILE")]
+    pub auth_file: Option<PathBuf>,
+
+    /// Whitespace separated bearer tokens for the HTTP API, each optionally followed by a
+    /// colon and comma separated scopes, e.g. `s3cret:presence,history`.
+    #[arg(long, env = "API_TOKENS", hide_env_values = true)]
+    pub api_tokens: Option<String>,
+
+    /// A file to append a JSON line to for every change made through the admin API.
+    #[arg(long, env = "AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+
+    /// A PEM certificate (chain) to serve HTTPS with. It is reloaded when it changes.
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key for `--tls-cert`.
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// A PEM bundle of CAs. When set, HTTPS clients must present a certificate signed by one
+    /// of them (mutual TLS).
+    #[arg(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// How many minutes may pass without an event before the events are reported as not
+    /// fresh, and the watchdog alert is sent. Readiness only depends on it with
+    /// `--require-fresh`.
+    #[arg(long, value_name = "MINUTES", default_value = "30")]
+    pub max_event_age: u32,
+
+    /// Only report ready while the last event is at most `--max-event-age` old. A quiet
+    /// network can go longer than that without an event, so this is off by default.
+    #[arg(long)]
+    pub require_fresh: bool,
+
+    /// An InfluxDB line protocol endpoint to push events and client counts to: an HTTP write
+    /// URL, e.g. `http://influxdb:8086/api/v2/write?org=home&bucket=wifi`, or
+    /// `udp://host:port`.
+    #[arg(long, env = "INFLUX_URL")]
+    pub influx_url: Option<String>,
+
+    /// A token for `--influx-url`, sent as `Authorization: Token ...`.
+    #[arg(long, env = "INFLUX_TOKEN", hide_env_values = true)]
+    pub influx_token: Option<String>,
+
+    /// How often to push the client count of every access point to `--influx-url`.
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub influx_interval: u32,
+
+    /// Loki's push endpoint, e.g. `http://loki:3100/loki/api/v1/push`, to send every event,
+    /// session and roam to.
+    #[arg(long, env = "LOKI_URL")]
+    pub loki_url: Option<String>,
+
+    /// An Elasticsearch server, e.g. `http://elasticsearch:9200`, to index every event,
+    /// session and roam in.
+    #[arg(long, env = "ELASTICSEARCH_URL")]
+    pub elasticsearch_url: Option<String>,
+
+    /// The index for `--elasticsearch-url`.
+    #[arg(long, default_value = "hostapd-api")]
+    pub elasticsearch_index: String,
+
+    /// An API key for `--elasticsearch-url`, sent as `Authorization: ApiKey ...`.
+    #[arg(long, env = "ELASTICSEARCH_API_KEY", hide_env_values = true)]
+    pub elasticsearch_api_key: Option<String>,
+
+    /// A directory to keep how far into the log the entries sent to Loki and Elasticsearch
+    /// go, so that the log replayed after a restart isn't sent again.
+    #[arg(long, env = "EXPORT_STATE_DIR")]
+    pub export_state_dir: Option<PathBuf>,
 
     /// Enable JSON logging (off by default)
     #[arg(long, default_value = "false")]
@@ -28,3 +183,127 @@ impl Args {
         Self::parse()
     }
 }
+
+impl ServeArgs {
+    pub fn tls(&self) -> Option<TlsConfig> {
+        Some(TlsConfig {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+        })
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ReplayArgs {
+    /// The log file to replay, in the same format as `--file`.
+    pub file: PathBuf,
+
+    /// A JSON file of interface metadata, as for `--interfaces`.
+    #[arg(long)]
+    pub interfaces: Option<PathBuf>,
+
+    /// Only print the devices that are online at the end of the log.
+    #[arg(long)]
+    pub online: bool,
+
+    /// Print the devices as JSON, in the same format as `/api/v2`, rather than a table.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ParseArgs {
+    /// Stop at the first line that can't be parsed, rather than reporting it and carrying on.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct QueryArgs {
+    /// The base URL of the server.
+    #[arg(long, env = "HOSTAPD_API_URL", default_value = "http://127.0.0.1:5580")]
+    pub server: String,
+
+    /// A bearer token to authenticate with.
+    #[arg(long, env = "HOSTAPD_API_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Print the response as JSON rather than a table.
+    #[arg(long)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub endpoint: Endpoint,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Endpoint {
+    /// List devices, optionally filtered.
+    Devices(DeviceFilters),
+    /// Show a single device.
+    Mac { mac: MacAddr },
+    /// List access points and how many devices are on each.
+    Aps,
+    /// List stations (access point interfaces) and how many devices are on each.
+    Stations,
+    /// Show whether the server is ready, and how ingestion is going.
+    Status,
+}
+
+/// The query parameters of `/api/v2`. See the README for their meaning.
+#[derive(Debug, clap::Args)]
+pub struct DeviceFilters {
+    #[arg(long)]
+    pub online: Option<bool>,
+    #[arg(long)]
+    pub ap: Option<String>,
+    #[arg(long)]
+    pub interface: Option<String>,
+    #[arg(long)]
+    pub ssid: Option<String>,
+    #[arg(long)]
+    pub identity: Option<String>,
+    #[arg(long)]
+    pub vlan: Option<u16>,
+    #[arg(long)]
+    pub vendor: Option<String>,
+    #[arg(long)]
+    pub sort: Option<String>,
+    #[arg(long)]
+    pub order: Option<String>,
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+impl DeviceFilters {
+    pub fn to_query(&self) -> Vec<(&'static str, String)> {
+        let DeviceFilters {
+            online,
+            ap,
+            interface,
+            ssid,
+            identity,
+            vlan,
+            vendor,
+            sort,
+            order,
+            limit,
+        } = self;
+        [
+            ("online", online.map(|online| online.to_string())),
+            ("ap", ap.clone()),
+            ("interface", interface.clone()),
+            ("ssid", ssid.clone()),
+            ("identity", identity.clone()),
+            ("vlan", vlan.map(|vlan| vlan.to_string())),
+            ("vendor", vendor.clone()),
+            ("sort", sort.clone()),
+            ("order", order.clone()),
+            ("limit", limit.map(|limit| limit.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{mac::MacAddr, Error};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{path::Path, sync::Arc};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+/// A change made through the admin API.
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AuditAction {
+    Forget { mac: MacAddr },
+    Merge { from: MacAddr, into: MacAddr },
+    ForceOffline { mac: MacAddr },
+    ClearAp { ap: String, devices: usize },
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp: DateTime<Utc>,
+    /// The name of the credential that made the change.
+    actor: &'a str,
+    #[serde(flatten)]
+    action: &'a AuditAction,
+}
+
+/// Records admin changes in the log and, when configured, appends them as JSON lines to
+/// an audit file.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl AuditLog {
+    /// Opens the audit file for appending, if one is given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be opened.
+    pub async fn open(path: Option<&Path>) -> Result<Self, Error> {
+        let file = match path {
+            Some(path) => Some(Arc::new(Mutex::new(
+                File::options().create(true).append(true).open(path).await?,
+            ))),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    pub async fn record(&self, actor: &str, action: AuditAction) {
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            actor,
+            action: &action,
+        };
+        tracing::info!(target: "audit", "{actor}: {action:?}");
+
+        let Some(ref file) = self.file else {
+            return;
+        };
+        let result = match serde_json::to_vec(&entry) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                let mut file = file.lock().await;
+                match file.write_all(&line).await {
+                    Ok(()) => file.flush().await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            tracing::error!("error writing audit log: {}", e);
+        }
+    }
+}
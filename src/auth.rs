@@ -0,0 +1,272 @@
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+};
+use serde::Deserialize;
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+/// What a credential is allowed to do. `admin` implies every other scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read the current presence of devices.
+    Presence,
+    /// Read historical data, such as timelines and reports.
+    History,
+    /// Modify the database.
+    Admin,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Presence => write!(f, "presence"),
+            Scope::History => write!(f, "history"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "presence" => Ok(Scope::Presence),
+            "history" => Ok(Scope::History),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(crate::Error::Auth(format!("unknown scope: {s}"))),
+        }
+    }
+}
+
+fn default_scopes() -> BTreeSet<Scope> {
+    BTreeSet::from([Scope::Presence])
+}
+
+/// A single entry in the auth file, either a bearer token or a username and password for
+/// HTTP basic auth.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Credential {
+    Token {
+        #[serde(default)]
+        name: Option<String>,
+        token: String,
+        #[serde(default = "default_scopes")]
+        scopes: BTreeSet<Scope>,
+    },
+    Basic {
+        username: String,
+        password: String,
+        #[serde(default = "default_scopes")]
+        scopes: BTreeSet<Scope>,
+    },
+}
+
+impl Credential {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Credential::Token { name, .. } => name.as_deref().unwrap_or("token"),
+            Credential::Basic { username, .. } => username,
+        }
+    }
+
+    #[must_use]
+    pub fn allows(&self, scope: Scope) -> bool {
+        let scopes = match self {
+            Credential::Token { scopes, .. } | Credential::Basic { scopes, .. } => scopes,
+        };
+        scopes.contains(&scope) || scopes.contains(&Scope::Admin)
+    }
+
+    /// Rejects credentials that an empty `Authorization` header would match.
+    fn validate(&self) -> Result<(), crate::Error> {
+        let empty = match self {
+            Credential::Token { token, .. } => token.is_empty().then_some("token"),
+            Credential::Basic {
+                username, password, ..
+            } => match (username.is_empty(), password.is_empty()) {
+                (true, _) => Some("username"),
+                (false, true) => Some("password"),
+                (false, false) => None,
+            },
+        };
+        match empty {
+            Some(field) => Err(crate::Error::Auth(format!(
+                "{} has an empty {field}",
+                self.name()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn matches(&self, presented: &Presented) -> bool {
+        match (self, presented) {
+            (Credential::Token { token, .. }, Presented::Bearer(presented)) => {
+                constant_time_eq(token.as_bytes(), presented.as_bytes())
+            }
+            (
+                Credential::Basic {
+                    username, password, ..
+                },
+                Presented::Basic(presented_username, presented_password),
+            ) => {
+                // evaluate both so the time taken doesn't reveal which one was wrong
+                let username = constant_time_eq(username.as_bytes(), presented_username.as_bytes());
+                let password = constant_time_eq(password.as_bytes(), presented_password.as_bytes());
+                username & password
+            }
+            _ => false,
+        }
+    }
+}
+
+enum Presented {
+    Bearer(String),
+    Basic(String, String),
+}
+
+/// The credentials accepted by the HTTP API. When none are configured, authentication
+/// is disabled and every request is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    credentials: Arc<Vec<Credential>>,
+}
+
+impl Auth {
+    /// Loads credentials from a JSON auth file and/or a list of tokens, usually from the
+    /// environment. Tokens are separated by whitespace, and may be followed by a colon and a
+    /// comma separated list of scopes, e.g. `s3cret:presence,history`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or parsed, a scope is unknown, or a token,
+    /// username or password is empty.
+    pub async fn load(file: Option<&Path>, tokens: Option<&str>) -> Result<Self, crate::Error> {
+        let mut credentials: Vec<Credential> = match file {
+            Some(file) => serde_json::from_str(&tokio::fs::read_to_string(file).await?)?,
+            None => Vec::new(),
+        };
+
+        for entry in tokens.unwrap_or_default().split_whitespace() {
+            let (token, scopes) = match entry.split_once(':') {
+                Some((token, scopes)) => (
+                    token,
+                    scopes
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()?,
+                ),
+                None => (entry, default_scopes()),
+            };
+            credentials.push(Credential::Token {
+                name: None,
+                token: token.to_string(),
+                scopes,
+            });
+        }
+
+        for credential in &credentials {
+            credential.validate()?;
+        }
+        Ok(Self {
+            credentials: Arc::new(credentials),
+        })
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.credentials.is_empty()
+    }
+
+    /// Returns the credential presented in the `Authorization` header, if it is valid.
+    #[must_use]
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<&Credential> {
+        let presented = presented(headers)?;
+        // check every credential rather than stopping at the first match
+        self.credentials.iter().fold(None, |found, credential| {
+            if credential.matches(&presented) {
+                found.or(Some(credential))
+            } else {
+                found
+            }
+        })
+    }
+}
+
+fn presented(headers: &HeaderMap) -> Option<Presented> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (kind, value) = value.split_once(' ')?;
+
+    if kind.eq_ignore_ascii_case("bearer") {
+        Some(Presented::Bearer(value.trim().to_string()))
+    } else if kind.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(STANDARD.decode(value.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Presented::Basic(username.to_string(), password.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Compares secrets by their SHA-256 digests, which are the same length whatever the
+/// secrets are, so that the time taken reveals neither their contents nor their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (digest(&SHA256, a), digest(&SHA256, b));
+    verify_slices_are_equal(a.as_ref(), b.as_ref()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, io::Write};
+
+    async fn load_file(json: &str) -> Result<Auth, crate::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "hostapd-api-auth-{}-{}.json",
+            std::process::id(),
+            json.len()
+        ));
+        File::create(&path)?.write_all(json.as_bytes())?;
+        let auth = Auth::load(Some(&path), None).await;
+        let _ = std::fs::remove_file(path);
+        auth
+    }
+
+    #[tokio::test]
+    async fn loads_tokens_with_scopes() {
+        let auth = Auth::load(None, Some("s3cret:presence,history other"))
+            .await
+            .unwrap();
+        assert_eq!(auth.credentials.len(), 2);
+        assert!(auth.credentials[0].allows(Scope::History));
+        assert!(!auth.credentials[1].allows(Scope::History));
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_tokens() {
+        for tokens in [":admin", "s3cret :presence"] {
+            assert!(Auth::load(None, Some(tokens)).await.is_err(), "{tokens}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_credentials_in_the_file() {
+        for json in [
+            r#"[{"token": "", "scopes": ["admin"]}]"#,
+            r#"[{"username": "", "password": "s3cret"}]"#,
+            r#"[{"username": "alice", "password": ""}]"#,
+        ] {
+            assert!(load_file(json).await.is_err(), "{json}");
+        }
+        assert!(
+            load_file(r#"[{"username": "alice", "password": "s3cret"}]"#)
+                .await
+                .is_ok()
+        );
+    }
+}
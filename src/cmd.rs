@@ -0,0 +1,112 @@
+//! The subcommands of the binary, and the table output shared by `replay` and `query`.
+
+use serde_json::Value;
+use std::io::{self, Write};
+
+pub mod parse;
+pub mod query;
+pub mod replay;
+pub mod serve;
+
+/// Writes rows under a header, with every column padded to its widest cell.
+fn write_table(out: &mut impl Write, header: &[&str], rows: &[Vec<String>]) -> io::Result<()> {
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header: Vec<String> = header.iter().map(ToString::to_string).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Writes the fields of a JSON object as name and value columns. Nested objects and arrays
+/// are flattened into dotted names.
+fn write_fields(out: &mut impl Write, value: &Value) -> io::Result<()> {
+    let mut rows = Vec::new();
+    flatten(String::new(), value, &mut rows);
+    write_table(out, &["FIELD", "VALUE"], &rows)
+}
+
+fn flatten(name: String, value: &Value, rows: &mut Vec<Vec<String>>) {
+    let prefix = |key: &dyn std::fmt::Display| {
+        if name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{name}.{key}")
+        }
+    };
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                flatten(prefix(key), value, rows);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, value) in items.iter().enumerate() {
+                flatten(prefix(&i), value, rows);
+            }
+        }
+        value => rows.push(vec![name, cell(value)]),
+    }
+}
+
+/// The text of a JSON value in a table.
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::Bool(true) => "yes".to_string(),
+        Value::Bool(false) => "no".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) if items.is_empty() => "-".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Writes devices in the `/api/v2` format as a table.
+fn write_devices(out: &mut impl Write, devices: &[Value]) -> io::Result<()> {
+    let rows: Vec<_> = devices
+        .iter()
+        .map(|device| {
+            let stations: Vec<String> = device["stations"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .map(|station| {
+                    format!(
+                        "{}/{}",
+                        cell(&station["access_point"]),
+                        cell(&station["interface"])
+                    )
+                })
+                .collect();
+            vec![
+                cell(&device["mac"]),
+                cell(&device["online"]),
+                if stations.is_empty() {
+                    "-".to_string()
+                } else {
+                    stations.join(",")
+                },
+                cell(&device["authentication"]["identity"]),
+                cell(&device["vlan"]["id"]),
+                cell(&device["last_seen"]),
+            ]
+        })
+        .collect();
+    write_table(
+        out,
+        &["MAC", "ONLINE", "STATIONS", "IDENTITY", "VLAN", "LAST SEEN"],
+        &rows,
+    )
+}
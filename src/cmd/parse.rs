@@ -0,0 +1,22 @@
+use crate::args::ParseArgs;
+use hostapd_api::{parser, Error};
+use std::io::{self, BufRead, Write};
+
+/// Parses lines from stdin, printing a JSON line for every record. Lines that aren't
+/// station or interface messages are skipped, and lines that can't be parsed are reported
+/// on stderr.
+pub fn run(args: &ParseArgs) -> Result<(), Error> {
+    let mut out = io::stdout().lock();
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        match parser::parse(&line?) {
+            Ok(Some(record)) => {
+                serde_json::to_writer(&mut out, &record)?;
+                writeln!(out)?;
+            }
+            Ok(None) => {}
+            Err(e) if args.strict => return Err(Error::Parse(format!("line {}: {e}", i + 1))),
+            Err(e) => eprintln!("line {}: {e}", i + 1),
+        }
+    }
+    Ok(())
+}
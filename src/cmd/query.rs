@@ -0,0 +1,109 @@
+use crate::args::{Endpoint, QueryArgs};
+use hostapd_api::Error;
+use serde_json::Value;
+use std::io::{self, Write};
+
+/// Requests an endpoint of a running server and prints the response.
+pub async fn run(args: &QueryArgs) -> Result<(), Error> {
+    let base = args.server.trim_end_matches('/');
+    let (path, query) = match args.endpoint {
+        Endpoint::Devices(ref filters) => ("/api/v2".to_string(), filters.to_query()),
+        Endpoint::Mac { mac } => (format!("/api/v2/mac/{mac}"), Vec::new()),
+        Endpoint::Aps => ("/api/v2/ap".to_string(), Vec::new()),
+        Endpoint::Stations => ("/api/v2/stations".to_string(), Vec::new()),
+        Endpoint::Status => ("/status".to_string(), Vec::new()),
+    };
+
+    let mut request = reqwest::Client::new()
+        .get(format!("{base}{path}"))
+        .query(&query);
+    if let Some(ref token) = args.token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::Request(e.to_string()))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| Error::Request(e.to_string()))?;
+    // `/status` describes what is wrong when the service isn't ready
+    if !status.is_success() && !matches!(args.endpoint, Endpoint::Status) {
+        let error = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(ToString::to_string))
+            .unwrap_or(body);
+        return Err(Error::Request(format!("{status}: {error}")));
+    }
+    let body: Value = serde_json::from_str(&body)?;
+
+    let mut out = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer_pretty(&mut out, &body)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    match args.endpoint {
+        Endpoint::Devices(_) => {
+            let devices = body["devices"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            super::write_devices(&mut out, devices)?;
+            if body["total"].as_u64() != Some(devices.len() as u64) {
+                eprintln!("{} of {} devices", devices.len(), body["total"]);
+            }
+        }
+        Endpoint::Mac { .. } => super::write_fields(&mut out, &body["device"])?,
+        Endpoint::Aps => {
+            let rows: Vec<_> = list(&body["access_points"])
+                .map(|ap| {
+                    let interfaces: Vec<_> = list(&ap["interfaces"]).map(super::cell).collect();
+                    vec![
+                        super::cell(&ap["name"]),
+                        super::cell(&ap["clients"]),
+                        interfaces.join(","),
+                    ]
+                })
+                .collect();
+            super::write_table(&mut out, &["ACCESS POINT", "CLIENTS", "INTERFACES"], &rows)?;
+        }
+        Endpoint::Stations => {
+            let rows: Vec<_> = list(&body["stations"])
+                .map(|station| {
+                    [
+                        "access_point",
+                        "interface",
+                        "ssid",
+                        "band",
+                        "channel",
+                        "clients",
+                    ]
+                    .iter()
+                    .map(|field| super::cell(&station[field]))
+                    .collect()
+                })
+                .collect();
+            super::write_table(
+                &mut out,
+                &[
+                    "ACCESS POINT",
+                    "INTERFACE",
+                    "SSID",
+                    "BAND",
+                    "CHANNEL",
+                    "CLIENTS",
+                ],
+                &rows,
+            )?;
+        }
+        Endpoint::Status => super::write_fields(&mut out, &body)?,
+    }
+    Ok(())
+}
+
+fn list(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
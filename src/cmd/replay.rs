@@ -0,0 +1,60 @@
+use crate::args::ReplayArgs;
+use hostapd_api::{
+    api::v2::DeviceList,
+    db::{Database, DeviceQuery},
+    parser, Error,
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+};
+
+/// Applies every line of a log to an empty database, then prints the devices.
+pub fn run(args: &ReplayArgs) -> Result<(), Error> {
+    let mut db = Database::new();
+    if let Some(ref interfaces) = args.interfaces {
+        db.configure_interfaces(serde_json::from_str(&std::fs::read_to_string(interfaces)?)?);
+    }
+
+    let (mut lines, mut records, mut errors) = (0, 0, 0);
+    for line in BufReader::new(File::open(&args.file)?).lines() {
+        lines += 1;
+        match parser::parse(&line?) {
+            Ok(Some(record)) => db.apply(record),
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::debug!("line {lines}: {e}");
+                errors += 1;
+                continue;
+            }
+        }
+        records += 1;
+    }
+
+    let mut query = DeviceQuery::default();
+    if args.online {
+        query = query.online(true);
+    }
+    let page = db.device_page(&query);
+    let devices = DeviceList::from(&page);
+    let devices = serde_json::to_value(devices)?;
+    eprintln!(
+        "replayed {lines} lines: {records} records, {errors} errors, {} devices",
+        devices["total"]
+    );
+
+    let mut out = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer_pretty(&mut out, &devices)?;
+        writeln!(out)?;
+    } else {
+        super::write_devices(
+            &mut out,
+            devices["devices"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use crate::args::ServeArgs;
+use chrono::{Duration, Utc};
+#[cfg(feature = "sqlite")]
+use hostapd_api::storage::sqlite::{SqliteDeviceStore, SqliteStorage};
+use hostapd_api::{
+    api,
+    audit::AuditLog,
+    auth::Auth,
+    db::{Database, Retention, DB},
+    history::History,
+    ingest,
+    series::{SharedStore, Store},
+    server::{self, Listener, SystemdSockets},
+    status::{SharedStatus, Status},
+    storage::{DeviceStoreMut, Storage},
+    Error,
+};
+#[cfg(feature = "export")]
+use hostapd_api::{export, sessions};
+use linemux::MuxedLines;
+use std::{collections::BTreeSet, io::IsTerminal, sync::Arc};
+use tokio::{
+    signal,
+    sync::{mpsc, Mutex},
+    time::interval,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower_http::trace::TraceLayer;
+
+/// Follows the log and serves the HTTP API until a shutdown signal, listening on the
+/// `systemd` sockets if they are asked for.
+pub async fn run(args: ServeArgs, mut systemd: SystemdSockets) -> Result<(), Error> {
+    init_tracing(args.json_logs);
+
+    let base = load_database(&args).await?;
+    let (devices, storage) = open_storage(&args, base)?;
+    let db = DB::new(devices, storage)?;
+    let tracker = TaskTracker::new();
+    let shutdown = CancellationToken::new();
+
+    let mut status = Status::new(
+        Duration::minutes(args.max_event_age.into()),
+        cfg!(feature = "notifier") && args.watchdog_url.is_some(),
+    );
+    status.require_fresh = args.require_fresh;
+    let mut lines = MuxedLines::new()?;
+    status.add_source(lines.add_file_from_start(&args.file).await?);
+    let status = Arc::new(status);
+
+    // before ingestion, so that they see the replay of the log too
+    spawn_exporters(&args, &tracker, &db, &status, &shutdown)?;
+    {
+        let (queue, queued) = mpsc::channel(ingest::QUEUE_CAPACITY);
+        tracker.spawn(ingest::read_loop(
+            lines,
+            queue,
+            status.clone(),
+            shutdown.clone(),
+        ));
+        tracker.spawn(ingest::apply_loop(queued, db.clone(), status.clone()));
+    }
+
+    let auth = Auth::load(args.auth_file.as_deref(), args.api_tokens.as_deref()).await?;
+    if !auth.is_enabled() {
+        tracing::warn!("no credentials configured, the API is open to anyone who can reach it");
+    }
+    let audit = AuditLog::open(args.audit_log.as_deref()).await?;
+    let series = match args.series_dir {
+        Some(ref dir) => Some(Arc::new(Mutex::new(Store::open(dir, &args.series_tiers)?))),
+        None => None,
+    };
+    let router = api::router(
+        db.clone(),
+        status.clone(),
+        auth,
+        audit,
+        series.clone(),
+        args.unprefixed_sunset,
+    )
+    .layer(TraceLayer::new_for_http());
+    let mut listeners = Vec::new();
+    for addr in &args.listen {
+        listeners.extend(Listener::bind(addr, &mut systemd).await?);
+        tracing::info!("listening on {addr}");
+    }
+    {
+        let shutdown = shutdown.clone();
+        let tls = args.tls();
+        tracker.spawn(async move {
+            if let Err(e) = server::serve(listeners, router, tls, shutdown).await {
+                tracing::error!("server error: {}", e);
+            }
+        });
+    }
+
+    tracker.spawn(prune_loop(db.clone(), status.clone(), shutdown.clone()));
+
+    if let Some(series) = series {
+        let every = std::time::Duration::from_secs(args.series_interval.max(1).into());
+        tracker.spawn(series_loop(
+            db.clone(),
+            series,
+            every,
+            status.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    #[cfg(not(feature = "notifier"))]
+    if args.watchdog_url.is_some() {
+        tracing::warn!("built without the notifier feature, so the watchdog is disabled");
+    }
+
+    #[cfg(feature = "notifier")]
+    if let Some(ref watchdog_url) = args.watchdog_url {
+        let db = db.clone();
+        let status = status.clone();
+        let shutdown = shutdown.clone();
+        let watchdog_url = watchdog_url.clone();
+        tracker.spawn(async move {
+            hostapd_api::watchdog::watchdog_loop(&watchdog_url, db, status, shutdown).await;
+        });
+    }
+
+    tracker.close();
+
+    tokio::select! {
+        () = shutdown_signal() => {
+            tracing::info!("shutting down");
+            shutdown.cancel();
+        }
+        () = shutdown.cancelled() => {
+            tracing::info!("got shutdown event");
+        }
+    }
+
+    tracker.wait().await;
+
+    Ok(())
+}
+
+/// The devices and their history, starting from `base`. Both are kept in the configured
+/// SQLite database, or in memory.
+type Stores = (Box<dyn DeviceStoreMut>, Box<dyn Storage>);
+
+#[cfg(feature = "sqlite")]
+fn open_storage(args: &ServeArgs, base: Database) -> Result<Stores, Error> {
+    let max_age = Some(Duration::days(args.history_days.into()));
+    Ok(match args.sqlite {
+        Some(ref path) => (
+            Box::new(SqliteDeviceStore::open(path, &base)?),
+            Box::new(SqliteStorage::open(path, base, max_age)?),
+        ),
+        None => (
+            Box::new(base.clone()),
+            Box::new(History::new(base, max_age)),
+        ),
+    })
+}
+
+#[cfg(not(feature = "sqlite"))]
+#[allow(clippy::unnecessary_wraps)]
+fn open_storage(args: &ServeArgs, base: Database) -> Result<Stores, Error> {
+    if args.sqlite.is_some() {
+        tracing::warn!("built without the sqlite feature, so everything is kept in memory");
+    }
+    let max_age = Some(Duration::days(args.history_days.into()));
+    Ok((
+        Box::new(base.clone()),
+        Box::new(History::new(base, max_age)),
+    ))
+}
+
+/// Starts pushing what is applied to the database to the configured stores.
+#[cfg(feature = "export")]
+fn spawn_exporters(
+    args: &ServeArgs,
+    tracker: &TaskTracker,
+    db: &DB,
+    status: &SharedStatus,
+    shutdown: &CancellationToken,
+) -> Result<(), Error> {
+    if let Some(ref url) = args.influx_url {
+        let config = export::influx::Config {
+            target: url.parse()?,
+            token: args.influx_token.clone(),
+            interval: std::time::Duration::from_secs(args.influx_interval.max(1).into()),
+        };
+        tracker.spawn(export::influx::export_loop(
+            config,
+            db.subscribe(),
+            db.clone(),
+            status.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let loki = args
+        .loki_url
+        .clone()
+        .map(|url| export::logs::Target::Loki { url });
+    let elasticsearch =
+        args.elasticsearch_url
+            .clone()
+            .map(|url| export::logs::Target::Elasticsearch {
+                url,
+                index: args.elasticsearch_index.clone(),
+                api_key: args.elasticsearch_api_key.clone(),
+            });
+    if loki.is_none() && elasticsearch.is_none() {
+        return Ok(());
+    }
+    // one session tracker for both
+    let (followed, _) = tokio::sync::broadcast::channel(sessions::TRACKED_CAPACITY);
+    for target in loki.into_iter().chain(elasticsearch) {
+        tracker.spawn(export::logs::export_loop(
+            target,
+            followed.subscribe(),
+            db.clone(),
+            args.export_state_dir.clone(),
+            shutdown.clone(),
+        ));
+    }
+    tracker.spawn(sessions::track_loop(
+        db.subscribe(),
+        followed,
+        shutdown.clone(),
+    ));
+    Ok(())
+}
+
+#[cfg(not(feature = "export"))]
+#[allow(clippy::unnecessary_wraps)]
+fn spawn_exporters(
+    args: &ServeArgs,
+    _: &TaskTracker,
+    _: &DB,
+    _: &SharedStatus,
+    _: &CancellationToken,
+) -> Result<(), Error> {
+    if args.influx_url.is_some() || args.loki_url.is_some() || args.elasticsearch_url.is_some() {
+        tracing::warn!("built without the export feature, so nothing is exported");
+    }
+    Ok(())
+}
+
+fn init_tracing(json_logs: bool) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stdout);
+
+    if json_logs {
+        subscriber.json().init();
+    } else if std::io::stdout().is_terminal() {
+        subscriber.with_ansi(true).pretty().init();
+    } else {
+        subscriber.with_ansi(false).init();
+    }
+}
+
+async fn load_database(args: &ServeArgs) -> Result<Database, Error> {
+    let mut db = Database::new();
+    let keep = match args.keep_devices {
+        Some(ref keep_devices) => {
+            serde_json::from_str(&tokio::fs::read_to_string(keep_devices).await?)?
+        }
+        None => BTreeSet::new(),
+    };
+    db.configure_retention(Retention {
+        max_age: args.retention_days.map(|days| Duration::days(days.into())),
+        max_devices: args.max_devices,
+        keep,
+    });
+    if let Some(ref interfaces) = args.interfaces {
+        let config = tokio::fs::read_to_string(interfaces).await?;
+        db.configure_interfaces(serde_json::from_str(&config)?);
+    }
+    Ok(db)
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        #[allow(clippy::expect_used)]
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        #[allow(clippy::expect_used)]
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+}
+
+async fn prune_loop(db: DB, status: SharedStatus, shutdown: CancellationToken) {
+    let mut ticker = interval(std::time::Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            _ = ticker.tick() => {
+                let now = Utc::now();
+                match db.prune(now).await {
+                    Ok(pruned) => status.record_pruned(pruned),
+                    Err(e) => tracing::error!("error pruning the devices: {}", e),
+                }
+                match db.prune_history(now).await {
+                    Ok(0) => {}
+                    Ok(folded) => tracing::debug!("dropped {folded} records from the history"),
+                    Err(e) => tracing::error!("error pruning the history: {}", e),
+                }
+            }
+        }
+    }
+}
+
+async fn series_loop(
+    db: DB,
+    series: SharedStore,
+    every: std::time::Duration,
+    status: SharedStatus,
+    shutdown: CancellationToken,
+) {
+    let mut ticker = interval(every);
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            _ = ticker.tick() => {
+                // the counts while the log is being replayed are of an earlier moment
+                if !status.replayed() {
+                    continue;
+                }
+                let now = Utc::now();
+                let live = db.read();
+                write_series(&series, move |series| {
+                    series.sample(&*live, now)?;
+                    series.prune(now).map(|_| ())
+                })
+                .await;
+            }
+        }
+    }
+    write_series(&series, Store::flush).await;
+}
+
+/// Runs `f` on the time series on a blocking thread, since it writes them to disk, so that
+/// neither the runtime nor the requests waiting for the lock are held up by the disk.
+async fn write_series(
+    series: &SharedStore,
+    f: impl FnOnce(&mut Store) -> Result<(), Error> + Send + 'static,
+) {
+    let series = Arc::clone(series);
+    let written = tokio::task::spawn_blocking(move || f(&mut series.blocking_lock())).await;
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!("error writing time series: {}", e),
+        Err(e) => tracing::error!("writing time series failed: {}", e),
+    }
+}
@@ -1,21 +1,422 @@
-use crate::parser::{Action, Event};
-use chrono::{DateTime, Utc};
-use serde::{ser::SerializeMap, Serialize};
+use crate::{
+    interface::{Band, InterfaceConfig, InterfaceInfo, Interfaces},
+    mac::{MacAddr, Oui},
+    parser::{Action, AdminEvent, Change, Event, InterfaceEvent, Record},
+};
+#[cfg(feature = "runtime")]
+use crate::{
+    storage::{DeviceStore, DeviceStoreMut, Position, Replay, Storage},
+    Error,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
     collections::{BTreeMap, BTreeSet},
     sync::Arc,
 };
-use tokio::sync::Mutex;
+#[cfg(feature = "runtime")]
+use tokio::sync::{broadcast, watch, Mutex, OwnedRwLockWriteGuard, RwLock};
+#[cfg(feature = "server")]
+use utoipa::{IntoParams, ToSchema};
+
+/// The database shared by ingestion, the API and the background tasks.
+///
+/// Writers take turns through a mutex, on a blocking thread since the database may be on
+/// disk, and publish an immutable snapshot of the database when they are done. Readers only ever look at the latest snapshot, so a slow request
+/// never holds up ingestion, and ingestion never holds up a request.
+#[cfg(feature = "runtime")]
+#[derive(Debug, Clone)]
+pub struct DB {
+    inner: Arc<Shared>,
+}
+
+#[cfg(feature = "runtime")]
+#[derive(Debug)]
+struct Shared {
+    writer: Arc<Mutex<Box<dyn DeviceStoreMut>>>,
+    snapshot: watch::Sender<Arc<dyn DeviceStore>>,
+    storage: Arc<RwLock<Box<dyn Storage>>>,
+    applied: broadcast::Sender<Applied>,
+}
+
+#[cfg(feature = "runtime")]
+/// A batch of records passed on to the subscribers once it is applied, each with where in
+/// the log it was read from, if it was.
+pub type Applied = Arc<Vec<(Record, Option<Position>)>>;
+
+#[cfg(feature = "runtime")]
+/// How many applied batches may wait for a slow subscriber before it misses some.
+const APPLIED_CAPACITY: usize = 1024;
+
+#[cfg(feature = "runtime")]
+/// Exclusive access to the history, which can be moved onto a blocking thread.
+type StorageGuard = OwnedRwLockWriteGuard<Box<dyn Storage>>;
+
+#[cfg(feature = "runtime")]
+impl DB {
+    /// Shares a database, usually a [`Database`], keeping the history of what is applied to
+    /// it in `storage`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the first snapshot of the database couldn't be taken.
+    pub fn new(mut db: Box<dyn DeviceStoreMut>, storage: Box<dyn Storage>) -> Result<Self, Error> {
+        let (snapshot, _) = watch::channel(db.snapshot()?);
+        let (applied, _) = broadcast::channel(APPLIED_CAPACITY);
+        Ok(Self {
+            inner: Arc::new(Shared {
+                storage: Arc::new(RwLock::new(storage)),
+                writer: Arc::new(Mutex::new(db)),
+                snapshot,
+                applied,
+            }),
+        })
+    }
+
+    /// The latest snapshot. It doesn't change, so it can be held for as long as needed.
+    #[must_use]
+    pub fn read(&self) -> Arc<dyn DeviceStore> {
+        self.inner.snapshot.borrow().clone()
+    }
+
+    /// Waits for exclusive write access, and runs `f` on a blocking thread. The changes are
+    /// published as a new snapshot when it returns, so make a batch of changes in one call
+    /// rather than one call for each.
+    async fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut dyn DeviceStoreMut) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        let mut db = Arc::clone(&self.inner.writer).lock_owned().await;
+        let (db, written, snapshot) = tokio::task::spawn_blocking(move || {
+            let written = f(&mut **db);
+            let snapshot = db.snapshot();
+            (db, written, snapshot)
+        })
+        .await
+        .map_err(|e| Error::Storage(format!("writing to the database failed: {e}")))?;
+        match snapshot {
+            Ok(snapshot) => {
+                self.inner.snapshot.send_replace(snapshot);
+            }
+            // the readers keep the previous snapshot until the next write
+            Err(e) => tracing::error!("error taking a snapshot of the database: {}", e),
+        }
+        drop(db);
+        Ok(written)
+    }
+
+    /// Applies a batch of records under one write, and adds them to the history. The batch
+    /// is then passed on to the subscribers.
+    ///
+    /// Admin changes the storage kept from before a restart are applied in between, once
+    /// the records have caught up with them.
+    pub async fn apply(&self, records: Vec<Record>) {
+        let records = records.into_iter().map(|record| (record, None)).collect();
+        self.apply_positioned(records).await;
+    }
+
+    /// Like [`DB::apply`], for records read from the log along with where they were read
+    /// from, so that a storage that outlives a restart can skip those it has already.
+    pub async fn apply_read(&self, records: Vec<(Record, Position)>) {
+        let records = records
+            .into_iter()
+            .map(|(record, position)| (record, Some(position)))
+            .collect();
+        self.apply_positioned(records).await;
+    }
+
+    async fn apply_positioned(&self, records: Vec<(Record, Option<Position>)>) {
+        let mut storage = self.storage().await;
+        let mut batch = Vec::with_capacity(records.len());
+        for (record, position) in records {
+            let restored = storage.restore(Some(record.timestamp()));
+            batch.extend(restored.into_iter().map(|record| (record, None)));
+            batch.push((record, position));
+        }
+        self.commit(storage, batch).await;
+    }
 
-pub type DB = Arc<Mutex<Database>>;
+    /// Applies the admin changes the storage kept from before a restart that the records
+    /// applied so far haven't caught up with. Ingestion calls this once the log has been
+    /// replayed, since they were made after everything in it.
+    pub async fn restore(&self) {
+        let mut storage = self.storage().await;
+        let batch: Vec<_> = storage
+            .restore(None)
+            .into_iter()
+            .map(|record| (record, None))
+            .collect();
+        if !batch.is_empty() {
+            self.commit(storage, batch).await;
+        }
+    }
 
-#[derive(Debug, Default, Serialize)]
+    /// Makes an admin change, and adds it to the history unless it changed nothing.
+    /// Returns how many devices it changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database couldn't make the change.
+    pub async fn change(&self, change: Change) -> Result<usize, Error> {
+        let event = AdminEvent {
+            timestamp: Utc::now(),
+            change,
+        };
+        let storage = self.storage().await;
+        let changed = {
+            let event = event.clone();
+            self.write(move |db| db.change(event)).await??
+        };
+        if changed == 0 {
+            return Ok(0);
+        }
+        if let Some((storage, records)) =
+            record_blocking(storage, vec![(Record::Admin(event), None)]).await
+        {
+            drop(storage);
+            self.publish(records);
+        }
+        Ok(changed)
+    }
+
+    async fn storage(&self) -> StorageGuard {
+        Arc::clone(&self.inner.storage).write_owned().await
+    }
+
+    async fn commit(&self, storage: StorageGuard, records: Vec<(Record, Option<Position>)>) {
+        let Some((storage, records)) = record_blocking(storage, records).await else {
+            return;
+        };
+        let subscribed = (self.inner.applied.receiver_count() > 0).then(|| records.clone());
+        let applied = self
+            .write(move |db| {
+                for (record, _) in records {
+                    if let Err(e) = db.apply(record) {
+                        tracing::error!("error applying a record: {}", e);
+                    }
+                }
+            })
+            .await;
+        if let Err(e) = applied {
+            tracing::error!("{}", e);
+        }
+        drop(storage);
+        if let Some(records) = subscribed {
+            self.publish(records);
+        }
+    }
+
+    /// Passes applied records on to the subscribers.
+    fn publish(&self, records: Vec<(Record, Option<Position>)>) {
+        // the subscribers may have gone away since
+        let _ = self.inner.applied.send(Arc::new(records));
+    }
+
+    /// Receives every batch of records applied from now on, after the snapshot with them
+    /// is published. A subscriber that falls too far behind misses batches, and is told how
+    /// many.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<Applied> {
+        self.inner.applied.subscribe()
+    }
+
+    /// The database as it was at `at`, rebuilt by replaying the history, or `None` if the
+    /// history doesn't go back that far. Retention isn't part of the history, so it doesn't
+    /// apply to it.
+    ///
+    /// The replay runs on a blocking thread, without holding the history, so ingestion
+    /// carries on meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history couldn't be read from its storage.
+    pub async fn at(&self, at: DateTime<Utc>) -> Result<Option<Database>, Error> {
+        let Some(replay) = self.inner.storage.read().await.at(at)? else {
+            return Ok(None);
+        };
+        replay_blocking(replay, Replay::database).await.map(Some)
+    }
+
+    /// The earliest moment the history can be rebuilt at, or `None` if it goes back to
+    /// the start.
+    pub async fn history_start(&self) -> Option<DateTime<Utc>> {
+        self.inner.storage.read().await.start()
+    }
+
+    /// Takes a replay of the history up to and including `until`. It doesn't hold the
+    /// history, so run it with [`replay_blocking`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history couldn't be read from its storage.
+    pub async fn replay(&self, until: DateTime<Utc>) -> Result<Replay, Error> {
+        self.inner.storage.read().await.replay(until)
+    }
+
+    /// Drops offline devices according to the retention policy.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be dropped from the database.
+    pub async fn prune(&self, now: DateTime<Utc>) -> Result<Pruned, Error> {
+        self.write(move |db| db.prune(now)).await?
+    }
+
+    /// Drops the history that is older than its maximum age. Returns how many records
+    /// were dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be dropped from the storage.
+    pub async fn prune_history(&self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let mut storage = self.storage().await;
+        tokio::task::spawn_blocking(move || storage.prune(now))
+            .await
+            .map_err(|e| Error::Storage(format!("pruning the history failed: {e}")))?
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Adds records to the history on a blocking thread, since the storage may write them to
+/// disk, and hands back the storage, so that it is held until they are applied too, along
+/// with the records. Returns `None` if the storage panicked, and the records are lost.
+async fn record_blocking(
+    mut storage: StorageGuard,
+    records: Vec<(Record, Option<Position>)>,
+) -> Option<(StorageGuard, Vec<(Record, Option<Position>)>)> {
+    let recorded = tokio::task::spawn_blocking(move || {
+        if let Err(e) = storage.record(&records) {
+            tracing::error!("error storing the history: {}", e);
+        }
+        (storage, records)
+    })
+    .await;
+    match recorded {
+        Ok(recorded) => Some(recorded),
+        Err(e) => {
+            tracing::error!("storing the history failed, dropping a batch: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Runs `f` on a replay on a blocking thread, since replaying a long history takes a while.
+///
+/// # Errors
+///
+/// Returns the error `f` returns, or an error if it panicked.
+pub async fn replay_blocking<T: Send + 'static>(
+    replay: Replay,
+    f: impl FnOnce(Replay) -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(move || f(replay))
+        .await
+        .map_err(|e| Error::Storage(format!("the replay failed: {e}")))?
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Database {
-    devices: BTreeMap<String, Device>,
+    /// Shared with the snapshots published before the device last changed, so publishing
+    /// a snapshot doesn't copy every device.
+    devices: BTreeMap<MacAddr, Arc<Device>>,
+    #[serde(skip)]
+    stations: Stations,
+    #[serde(skip)]
+    retention: Retention,
     pub last_event_timestamp: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+/// What is known about the stations, apart from the devices on them: their metadata, and
+/// which have been seen. It's small, so stores that keep the devices elsewhere keep it in
+/// memory too.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Stations {
+    pub(crate) interfaces: Interfaces,
+    /// Every station (access point and interface) any event has mentioned, including
+    /// those that no longer have any devices associated with them.
+    pub(crate) seen: BTreeSet<Station>,
+}
+
+impl Stations {
+    pub(crate) fn knows_ap(&self, ap: &str) -> bool {
+        self.seen.iter().any(|s| s.hostname == ap) || self.interfaces.knows_ap(ap)
+    }
+
+    pub(crate) fn knows_interface(&self, interface: &str) -> bool {
+        self.seen.iter().any(|s| s.interface == interface)
+            || self.interfaces.knows_interface(interface)
+    }
+
+    pub(crate) fn knows_station(&self, ap: &str, interface: &str) -> bool {
+        self.seen
+            .iter()
+            .any(|s| s.hostname == ap && s.interface == interface)
+            || self.interfaces.get(ap, interface).is_some()
+    }
+
+    pub(crate) fn info(&self, station: &Station) -> Option<InterfaceInfo> {
+        self.interfaces.get(&station.hostname, &station.interface)
+    }
+
+    /// Remembers that a station was mentioned.
+    pub(crate) fn see(&mut self, station: &Station) {
+        if !self.seen.contains(station) {
+            self.seen.insert(station.clone());
+        }
+    }
+
+    pub(crate) fn learn(
+        &mut self,
+        InterfaceEvent {
+            timestamp,
+            hostname,
+            interface,
+            ssid,
+            frequency,
+            channel,
+        }: InterfaceEvent,
+    ) {
+        tracing::info!(
+            "interface {hostname}@{interface} {timestamp} ssid={ssid:?} freq={frequency:?}"
+        );
+        if let Some(ssid) = ssid {
+            self.interfaces.learn_ssid(&hostname, &interface, ssid);
+        }
+        if let Some(frequency) = frequency {
+            self.interfaces
+                .learn_frequency(&hostname, &interface, frequency, channel);
+        }
+        self.seen.insert(Station {
+            hostname,
+            interface,
+        });
+    }
+}
+
+/// Limits on how many offline devices are remembered. Online devices are never pruned.
+#[derive(Debug, Default, Clone)]
+pub struct Retention {
+    /// Offline devices not seen for this long are dropped.
+    pub max_age: Option<Duration>,
+    /// When there are more devices than this, the offline devices seen least recently are
+    /// dropped.
+    pub max_devices: Option<usize>,
+    /// Devices that are never dropped.
+    pub keep: BTreeSet<MacAddr>,
+}
+
+/// How many devices a call to [`Database::prune`] dropped.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Pruned {
+    /// Dropped for being older than [`Retention::max_age`].
+    pub expired: usize,
+    /// Dropped to stay within [`Retention::max_devices`].
+    pub evicted: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct Station {
     pub hostname: String,
     pub interface: String,
@@ -27,72 +428,321 @@ impl std::fmt::Display for Station {
     }
 }
 
-#[derive(Debug, Default, Serialize)]
-struct Device {
-    stations: BTreeSet<Station>,
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct StationItem {
+    #[serde(flatten)]
+    pub station: Station,
 
-    last_associated: Option<DateTime<Utc>>,
-    last_disassociated: Option<DateTime<Utc>>,
-    last_observed: Option<DateTime<Utc>>,
+    // flattened, so a station without any is just its name, never null
+    #[serde(flatten)]
+    #[cfg_attr(feature = "server", schema(value_type = InterfaceInfo))]
+    pub info: Option<InterfaceInfo>,
 }
 
+#[derive(Debug, Default, Clone, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Device {
+    #[serde(skip)]
+    pub stations: BTreeSet<Station>,
+
+    pub last_associated: Option<DateTime<Utc>>,
+    pub last_disassociated: Option<DateTime<Utc>>,
+    pub last_observed: Option<DateTime<Utc>>,
+
+    /// The EAP identity (username) the device last authenticated with, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+
+    /// The RADIUS accounting session of the current association, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accounting_session: Option<String>,
+
+    /// The VLAN the current association was assigned to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan: Option<u16>,
+
+    /// The per-VLAN interface the station was bound to, e.g. `wlan0.20`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_interface: Option<String>,
+}
+
+/// A device as a store answers it, with the metadata of the stations it is on. It doesn't
+/// borrow from the store, so stores that read it from elsewhere can hand it out too.
 #[derive(Debug, Serialize)]
-pub struct DeviceListItem<'a> {
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct DeviceListItem {
     #[serde(rename = "hardware_ethernet")]
-    mac: &'a str,
+    pub mac: MacAddr,
 
-    access_points: BTreeSet<&'a str>,
+    pub access_points: BTreeSet<String>,
+
+    pub stations: Vec<StationItem>,
 
     #[serde(flatten)]
-    device: &'a Device,
+    #[cfg_attr(feature = "server", schema(value_type = Device))]
+    pub device: Arc<Device>,
 
-    online: bool,
+    pub online: bool,
 }
 
-#[derive(Debug)]
-struct DeviceWithoutStations<'a>(&'a Device);
-
-impl<'a> Serialize for DeviceWithoutStations<'a> {
-    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut map = serde::ser::Serializer::serialize_map(serializer, Some(3))?;
-        if let Some(last_associated) = self.0.last_associated {
-            map.serialize_entry("last_associated", &last_associated)?;
-        }
-        if let Some(last_disassociated) = self.0.last_disassociated {
-            map.serialize_entry("last_disassociated", &last_disassociated)?;
+impl DeviceListItem {
+    pub(crate) fn new(mac: MacAddr, device: Arc<Device>, interfaces: &Interfaces) -> Self {
+        Self {
+            mac,
+            access_points: device.stations.iter().map(|s| s.hostname.clone()).collect(),
+            stations: device
+                .stations
+                .iter()
+                .map(|station| StationItem {
+                    station: station.clone(),
+                    info: interfaces.get(&station.hostname, &station.interface),
+                })
+                .collect(),
+            online: !device.stations.is_empty(),
+            device,
         }
-        if let Some(last_observed) = self.0.last_observed {
-            map.serialize_entry("last_observed", &last_observed)?;
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+struct DeviceWithoutStations<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_associated: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_disassociated: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_observed: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accounting_session: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan_interface: Option<&'a str>,
+}
+
+impl<'a> From<&'a Device> for DeviceWithoutStations<'a> {
+    fn from(device: &'a Device) -> Self {
+        Self {
+            last_associated: device.last_associated,
+            last_disassociated: device.last_disassociated,
+            last_observed: device.last_observed,
+            identity: device.identity.as_deref(),
+            accounting_session: device.accounting_session.as_deref(),
+            vlan: device.vlan,
+            vlan_interface: device.vlan_interface.as_deref(),
         }
-        map.end()
     }
 }
 
 #[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct DeviceMapItem<'a> {
     #[serde(rename = "hardware_ethernet")]
-    mac: &'a str,
+    mac: MacAddr,
 
     #[serde(flatten)]
     device: DeviceWithoutStations<'a>,
 }
 
-pub enum StationQuery {
-    Hostname(String),
-    Interface(String),
-    HostnameInterface(String, String),
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Mac,
+    LastAssociated,
+    LastDisassociated,
+    LastObserved,
+    LastSeen,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A composable filter over the device list. Every field that is set must match for a
+/// device to be included. The station filters (`ap`, `interface`, `ssid` and `band`) must
+/// all match the same station.
+///
+/// This deserializes from the query string of the list endpoints, e.g.
+/// `?online=true&ap=bedroom-ap&sort=last_observed&order=desc&limit=10`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[cfg_attr(feature = "server", derive(IntoParams))]
+#[serde(default)]
+#[cfg_attr(feature = "server", into_params(parameter_in = Query))]
+pub struct DeviceQuery {
+    pub online: Option<bool>,
+    pub ap: Option<String>,
+    pub interface: Option<String>,
+    pub ssid: Option<String>,
+    pub band: Option<Band>,
+    pub identity: Option<String>,
+    pub vlan: Option<u16>,
+    pub seen_since: Option<DateTime<Utc>>,
+    /// Either an OUI prefix such as `04:17:b6`, or `random` for locally administered
+    /// (randomized) addresses.
+    #[cfg_attr(feature = "server", param(value_type = Option<String>))]
+    pub vendor: Option<Vendor>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl DeviceQuery {
+    #[must_use]
+    pub fn online(mut self, online: bool) -> Self {
+        self.online.replace(online);
+        self
+    }
+
+    #[must_use]
+    pub fn ap(mut self, ap: String) -> Self {
+        self.ap.replace(ap);
+        self
+    }
+
+    #[must_use]
+    pub fn interface(mut self, interface: String) -> Self {
+        self.interface.replace(interface);
+        self
+    }
+
+    #[must_use]
+    pub fn ssid(mut self, ssid: String) -> Self {
+        self.ssid.replace(ssid);
+        self
+    }
+
+    #[must_use]
+    pub fn identity(mut self, identity: String) -> Self {
+        self.identity.replace(identity);
+        self
+    }
+
+    #[must_use]
+    pub fn vlan(mut self, vlan: u16) -> Self {
+        self.vlan.replace(vlan);
+        self
+    }
+
+    fn matches(&self, mac: MacAddr, device: &Device, interfaces: &Interfaces) -> bool {
+        self.online != Some(device.stations.is_empty())
+            && self
+                .identity
+                .as_ref()
+                .map_or(true, |identity| device.identity.as_ref() == Some(identity))
+            && self.vlan.map_or(true, |vlan| device.vlan == Some(vlan))
+            && self
+                .seen_since
+                .map_or(true, |since| device.last_seen().is_some_and(|t| t >= since))
+            && self.vendor.map_or(true, |vendor| vendor.matches(mac))
+            && (!self.filters_stations()
+                || device.stations.iter().any(|station| {
+                    let info = interfaces.get(&station.hostname, &station.interface);
+                    self.matches_station(station, info.as_ref())
+                }))
+    }
+
+    pub(crate) fn filters_stations(&self) -> bool {
+        self.ap.is_some() || self.interface.is_some() || self.ssid.is_some() || self.band.is_some()
+    }
+
+    /// Whether a station, with the metadata `info`, matches the station filters.
+    pub(crate) fn matches_station(&self, station: &Station, info: Option<&InterfaceInfo>) -> bool {
+        self.ap.as_ref().map_or(true, |ap| &station.hostname == ap)
+            && self
+                .interface
+                .as_ref()
+                .map_or(true, |interface| &station.interface == interface)
+            && self.ssid.as_deref().map_or(true, |ssid| {
+                info.and_then(|info| info.ssid.as_deref()) == Some(ssid)
+            })
+            && self
+                .band
+                .map_or(true, |band| info.and_then(|info| info.band) == Some(band))
+    }
+
+    fn sort(&self, devices: &mut [(MacAddr, &Arc<Device>)]) {
+        match self.sort {
+            SortKey::Mac => devices.sort_by_key(|(mac, _)| *mac),
+            SortKey::LastAssociated => devices.sort_by_key(|(_, d)| d.last_associated),
+            SortKey::LastDisassociated => devices.sort_by_key(|(_, d)| d.last_disassociated),
+            SortKey::LastObserved => devices.sort_by_key(|(_, d)| d.last_observed),
+            SortKey::LastSeen => devices.sort_by_key(|(_, d)| d.last_seen()),
+        }
+        if self.order == SortOrder::Desc {
+            devices.reverse();
+        }
+    }
+}
+
+/// The `vendor` filter of a [`DeviceQuery`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    /// Addresses the vendor with this OUI assigned.
+    Oui(Oui),
+    /// Locally administered (randomized) addresses.
+    Random,
+}
+
+impl Vendor {
+    fn matches(self, mac: MacAddr) -> bool {
+        match self {
+            Vendor::Oui(oui) => mac.oui() == oui,
+            Vendor::Random => mac.is_locally_administered(),
+        }
+    }
 }
 
-pub enum DeviceQuery {
-    All,
-    Online,
-    Offline,
-    Station(StationQuery),
+impl<'de> Deserialize<'de> for Vendor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let vendor = String::deserialize(deserializer)?;
+        if vendor.eq_ignore_ascii_case("random") {
+            return Ok(Vendor::Random);
+        }
+        vendor.parse().map(Vendor::Oui).map_err(de::Error::custom)
+    }
 }
 
 impl Device {
-    fn access_points(&self) -> BTreeSet<&str> {
-        self.stations.iter().map(|s| s.hostname.as_str()).collect()
+    /// The most recent time the device was associated, disassociated or observed.
+    #[must_use]
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        [
+            self.last_associated,
+            self.last_disassociated,
+            self.last_observed,
+        ]
+        .into_iter()
+        .max()
+        .flatten()
+    }
+
+    /// Applies what a station logged about the device.
+    pub(crate) fn witness(&mut self, timestamp: DateTime<Utc>, station: Station, action: Action) {
+        match action {
+            Action::Associated => self.associate(timestamp, station),
+            Action::Observed => self.observe(timestamp, station),
+            Action::Disassociated => self.disassociate(timestamp, &station),
+            Action::AccountingStarted { session_id } => {
+                self.start_accounting(timestamp, &station, session_id);
+            }
+            Action::Identified { identity } => self.identify(timestamp, &station, identity),
+            Action::VlanAssigned { vlan } => self.assign_vlan(timestamp, &station, vlan),
+            Action::VlanBound { vlan_interface } => {
+                self.bind_vlan(timestamp, &station, vlan_interface);
+            }
+        }
     }
 
     fn associate(&mut self, timestamp: DateTime<Utc>, ap: Station) {
@@ -111,150 +761,268 @@ impl Device {
         tracing::info!("disassociate {timestamp} {ap}");
         self.last_disassociated.replace(timestamp);
         self.stations.remove(ap);
+        if self.stations.is_empty() {
+            self.accounting_session = None;
+            self.vlan = None;
+            self.vlan_interface = None;
+        }
     }
 
-    fn list_item<'a>(&'a self, mac: &'a str) -> DeviceListItem<'a> {
-        DeviceListItem {
-            mac,
-            device: self,
-            access_points: self.access_points(),
-            online: !self.stations.is_empty(),
+    fn start_accounting(&mut self, timestamp: DateTime<Utc>, ap: &Station, session_id: String) {
+        tracing::info!("accounting session {session_id} {timestamp} {ap}");
+        self.accounting_session.replace(session_id);
+    }
+
+    fn identify(&mut self, timestamp: DateTime<Utc>, ap: &Station, identity: String) {
+        tracing::info!("identity {identity} {timestamp} {ap}");
+        self.identity.replace(identity);
+    }
+
+    fn assign_vlan(&mut self, timestamp: DateTime<Utc>, ap: &Station, vlan: u16) {
+        tracing::info!("vlan {vlan} {timestamp} {ap}");
+        self.vlan.replace(vlan);
+    }
+
+    /// Records the per-VLAN interface a station was bound to. hostapd names these
+    /// `<interface>.<vlan>`, so the VLAN is inferred from the name when it was not logged.
+    fn bind_vlan(&mut self, timestamp: DateTime<Utc>, ap: &Station, vlan_interface: String) {
+        tracing::info!("bind {vlan_interface} {timestamp} {ap}");
+        if self.vlan.is_none() {
+            self.vlan = vlan_interface
+                .strip_prefix(ap.interface.as_str())
+                .and_then(|suffix| suffix.strip_prefix('.'))
+                .and_then(|vlan| vlan.parse().ok());
+        }
+        self.vlan_interface.replace(vlan_interface);
+    }
+
+    /// Disassociates the device from every station it is on, or just those on `ap`. Returns
+    /// whether it was on any.
+    pub(crate) fn disconnect(&mut self, timestamp: DateTime<Utc>, ap: Option<&str>) -> bool {
+        let stations: Vec<Station> = self
+            .stations
+            .iter()
+            .filter(|station| ap.map_or(true, |ap| station.hostname == ap))
+            .cloned()
+            .collect();
+        for station in &stations {
+            self.disassociate(timestamp, station);
         }
+        !stations.is_empty()
+    }
+
+    /// Folds another record for the same physical device into this one, keeping the most
+    /// recent timestamps, and this record's details where both have them.
+    pub(crate) fn merge(&mut self, other: Device) {
+        self.stations.extend(other.stations);
+        self.last_associated = self.last_associated.max(other.last_associated);
+        self.last_disassociated = self.last_disassociated.max(other.last_disassociated);
+        self.last_observed = self.last_observed.max(other.last_observed);
+        self.identity = self.identity.take().or(other.identity);
+        self.accounting_session = self.accounting_session.take().or(other.accounting_session);
+        self.vlan = self.vlan.or(other.vlan);
+        self.vlan_interface = self.vlan_interface.take().or(other.vlan_interface);
     }
 
-    fn map_item<'a>(&'a self, mac: &'a str) -> DeviceMapItem<'a> {
+    /// The device as listed in the maps of access points, stations and VLANs.
+    #[must_use]
+    pub fn map_item(&self, mac: MacAddr) -> DeviceMapItem<'_> {
         DeviceMapItem {
             mac,
-            device: DeviceWithoutStations(self),
+            device: self.into(),
         }
     }
 }
 
-impl<'b, 'a: 'b> Database {
+impl Database {
+    #[must_use]
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn get(&'a self, mac: &'a str) -> Option<DeviceListItem<'b>> {
-        if let Some(device) = self.devices.get(mac) {
-            return Some(device.list_item(mac));
+    pub fn configure_interfaces(&mut self, config: InterfaceConfig) {
+        self.stations.interfaces.configure(config);
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn stations(&self) -> &Stations {
+        &self.stations
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    pub fn configure_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    /// Drops offline devices according to the retention policy.
+    pub fn prune(&mut self, now: DateTime<Utc>) -> Pruned {
+        let Retention {
+            max_age,
+            max_devices,
+            ref keep,
+        } = self.retention;
+        let prunable =
+            |mac: &MacAddr, device: &Arc<Device>| device.stations.is_empty() && !keep.contains(mac);
+        let mut pruned = Pruned::default();
+
+        if let Some(max_age) = max_age {
+            let before = self.devices.len();
+            self.devices.retain(|mac, device| {
+                !prunable(mac, device) || device.last_seen().is_some_and(|t| now - t <= max_age)
+            });
+            pruned.expired = before - self.devices.len();
         }
-        None
+
+        if let Some(excess) = max_devices.and_then(|max| self.devices.len().checked_sub(max)) {
+            let mut candidates: Vec<(Option<DateTime<Utc>>, MacAddr)> = self
+                .devices
+                .iter()
+                .filter(|(mac, device)| prunable(mac, device))
+                .map(|(mac, device)| (device.last_seen(), *mac))
+                .collect();
+            candidates.sort_unstable();
+            for (_, mac) in candidates.into_iter().take(excess) {
+                self.devices.remove(&mac);
+                pruned.evicted += 1;
+            }
+        }
+
+        if pruned.expired > 0 || pruned.evicted > 0 {
+            tracing::info!(
+                "pruned {} expired and {} evicted devices",
+                pruned.expired,
+                pruned.evicted
+            );
+        }
+        pruned
     }
 
-    pub fn access_points(&self) -> BTreeSet<&str> {
-        self.devices
-            .iter()
-            .flat_map(|(_, device)| device.stations.iter())
-            .map(|s| s.hostname.as_str())
-            .collect()
+    /// Every device, by MAC address.
+    #[must_use]
+    pub fn devices(&self) -> &BTreeMap<MacAddr, Arc<Device>> {
+        &self.devices
     }
 
-    pub fn stations(&self) -> BTreeMap<String, BTreeSet<&str>> {
-        let mut map = BTreeMap::new();
+    #[must_use]
+    pub fn get(&self, mac: MacAddr) -> Option<DeviceListItem> {
+        let device = self.devices.get(&mac)?;
+        Some(DeviceListItem::new(
+            mac,
+            Arc::clone(device),
+            &self.stations.interfaces,
+        ))
+    }
+
+    #[must_use]
+    pub fn knows_ap(&self, ap: &str) -> bool {
+        self.stations.knows_ap(ap)
+    }
+
+    #[must_use]
+    pub fn knows_interface(&self, interface: &str) -> bool {
+        self.stations.knows_interface(interface)
+    }
+
+    #[must_use]
+    pub fn knows_station(&self, ap: &str, interface: &str) -> bool {
+        self.stations.knows_station(ap, interface)
+    }
+
+    /// The number of devices currently associated with every station that has been seen,
+    /// including those without any devices.
+    #[must_use]
+    pub fn station_clients(&self) -> BTreeMap<Station, usize> {
+        let mut map: BTreeMap<Station, usize> =
+            self.stations.seen.iter().map(|s| (s.clone(), 0)).collect();
         for device in self.devices.values() {
             for sta in &device.stations {
-                map.entry(sta.hostname.clone())
-                    .or_insert_with(BTreeSet::new)
-                    .insert(sta.interface.as_str());
+                *map.entry(sta.clone()).or_default() += 1;
             }
         }
         map
     }
 
-    pub fn device_map(&'a self) -> BTreeMap<&'a str, Vec<DeviceMapItem<'a>>> {
-        let mut map = BTreeMap::new();
-        for (mac, device) in &self.devices {
-            for ap in &device.stations {
-                map.entry(ap.hostname.as_str())
-                    .or_insert_with(Vec::new)
-                    .push(device.map_item(mac));
+    /// The number of devices currently associated with every access point that has been
+    /// seen, including those without any devices. A device on several of an access point's
+    /// interfaces counts once.
+    #[must_use]
+    pub fn access_point_clients(&self) -> BTreeMap<String, usize> {
+        let mut map: BTreeMap<String, usize> = self
+            .stations
+            .seen
+            .iter()
+            .map(|s| (s.hostname.clone(), 0))
+            .collect();
+        for device in self.devices.values() {
+            let aps: BTreeSet<&str> = device
+                .stations
+                .iter()
+                .map(|s| s.hostname.as_str())
+                .collect();
+            for ap in aps {
+                *map.entry(ap.to_string()).or_default() += 1;
             }
         }
         map
     }
 
-    pub fn station_map(&'a self) -> BTreeMap<&'a str, BTreeMap<&'a str, Vec<DeviceMapItem<'a>>>> {
-        let mut map = BTreeMap::new();
-        for (mac, device) in &self.devices {
-            for ap in &device.stations {
-                map.entry(ap.hostname.as_str())
-                    .or_insert_with(BTreeMap::new)
-                    .entry(ap.interface.as_str())
-                    .or_insert_with(Vec::new)
-                    .push(device.map_item(mac));
+    #[must_use]
+    pub fn interface_info(&self, station: &Station) -> Option<InterfaceInfo> {
+        self.stations.info(station)
+    }
+
+    #[must_use]
+    pub fn device_list(&self, query: &DeviceQuery) -> Vec<DeviceListItem> {
+        self.device_page(query).1
+    }
+
+    /// Like [`Database::device_list`], but also returns the number of devices that
+    /// matched the query before `limit` and `offset` were applied.
+    #[must_use]
+    pub fn device_page(&self, query: &DeviceQuery) -> (usize, Vec<DeviceListItem>) {
+        let mut devices: Vec<_> = self
+            .devices
+            .iter()
+            .map(|(mac, device)| (*mac, device))
+            .filter(|(mac, device)| query.matches(*mac, device, &self.stations.interfaces))
+            .collect();
+        query.sort(&mut devices);
+        let total = devices.len();
+
+        // only the page is turned into list items
+        let devices = devices
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(mac, device)| {
+                DeviceListItem::new(mac, Arc::clone(device), &self.stations.interfaces)
+            })
+            .collect();
+        (total, devices)
+    }
+
+    /// Applies a record from the log.
+    pub fn apply(&mut self, record: Record) {
+        match record {
+            Record::Station(event) => self.witness(event),
+            Record::Interface(event) => self.stations.learn(event),
+            Record::Admin(event) => {
+                self.change(event);
             }
         }
-        map
     }
 
-    pub fn device_list(&self, query: DeviceQuery) -> Vec<DeviceListItem> {
-        match query {
-            DeviceQuery::All => self
-                .devices
-                .iter()
-                .map(|(mac, device)| device.list_item(mac))
-                .collect(),
-            DeviceQuery::Station(StationQuery::Hostname(ap)) => self
-                .devices
-                .iter()
-                .filter_map(|(mac, device)| {
-                    if device.stations.iter().any(|s| s.hostname == ap) {
-                        Some(device.list_item(mac))
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-            DeviceQuery::Station(StationQuery::Interface(ap)) => self
-                .devices
-                .iter()
-                .filter_map(|(mac, device)| {
-                    if device.stations.iter().any(|s| s.interface == ap) {
-                        Some(device.list_item(mac))
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-            DeviceQuery::Station(StationQuery::HostnameInterface(ap, interface)) => self
-                .devices
-                .iter()
-                .filter_map(|(mac, device)| {
-                    if device
-                        .stations
-                        .iter()
-                        .any(|s| s.hostname == ap && s.interface == interface)
-                    {
-                        Some(device.list_item(mac))
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-            DeviceQuery::Online => self
-                .devices
-                .iter()
-                .filter_map(|(mac, device)| {
-                    if device.stations.is_empty() {
-                        None
-                    } else {
-                        Some(device.list_item(mac))
-                    }
-                })
-                .collect(),
-
-            DeviceQuery::Offline => self
-                .devices
-                .iter()
-                .filter_map(|(mac, device)| {
-                    if device.stations.is_empty() {
-                        Some(device.list_item(mac))
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
+    /// Makes an admin change. Returns how many devices it changed.
+    pub fn change(&mut self, AdminEvent { timestamp, change }: AdminEvent) -> usize {
+        match change {
+            Change::Forget { mac } => usize::from(self.forget(mac).is_some()),
+            Change::Merge { from, into } => usize::from(self.merge(from, into)),
+            Change::ForceOffline { mac } => usize::from(self.force_offline(mac, timestamp)),
+            Change::ClearAp { ap } => self.clear_ap(&ap, timestamp),
         }
     }
 
@@ -274,25 +1042,207 @@ impl<'b, 'a: 'b> Database {
             interface,
         };
         self.last_event_timestamp.replace(timestamp);
-        match action {
-            Action::Associated => {
-                self.devices
-                    .entry(mac)
-                    .or_default()
-                    .associate(timestamp, station);
-            }
-            Action::Observed => {
-                self.devices
-                    .entry(mac)
-                    .or_default()
-                    .observe(timestamp, station);
-            }
-            Action::Disassociated => {
-                self.devices
-                    .entry(mac)
-                    .or_default()
-                    .disassociate(timestamp, &station);
+        self.stations.see(&station);
+        self.device_mut(mac).witness(timestamp, station, action);
+    }
+
+    /// The device with `mac`, added if it is new, and copied first if a snapshot still
+    /// shares it.
+    fn device_mut(&mut self, mac: MacAddr) -> &mut Device {
+        Arc::make_mut(self.devices.entry(mac).or_default())
+    }
+
+    /// Removes a device. It comes back if it is seen again.
+    fn forget(&mut self, mac: MacAddr) -> Option<Device> {
+        tracing::info!("forget {mac}");
+        self.devices
+            .remove(&mac)
+            .map(|device| Arc::try_unwrap(device).unwrap_or_else(|device| (*device).clone()))
+    }
+
+    /// Moves everything known about `from` into `into`, e.g. after a NIC was replaced.
+    /// Returns false if `from` is unknown.
+    fn merge(&mut self, from: MacAddr, into: MacAddr) -> bool {
+        let Some(device) = self.devices.remove(&from) else {
+            return false;
+        };
+        tracing::info!("merge {from} into {into}");
+        let device = Arc::try_unwrap(device).unwrap_or_else(|device| (*device).clone());
+        self.device_mut(into).merge(device);
+        true
+    }
+
+    /// Marks a device as disassociated from every station. Returns false if it is unknown.
+    fn force_offline(&mut self, mac: MacAddr, timestamp: DateTime<Utc>) -> bool {
+        match self.devices.get_mut(&mac) {
+            Some(device) => {
+                Arc::make_mut(device).disconnect(timestamp, None);
+                true
             }
+            None => false,
         }
     }
+
+    /// Marks every device on an access point as disassociated from it. Returns how many
+    /// devices were on it.
+    fn clear_ap(&mut self, ap: &str, timestamp: DateTime<Utc>) -> usize {
+        self.devices
+            .values_mut()
+            .filter(|device| device.stations.iter().any(|s| s.hostname == ap))
+            .map(|device| Arc::make_mut(device).disconnect(timestamp, Some(ap)))
+            .filter(|&disconnected| disconnected)
+            .count()
+    }
+
+    pub fn learn(&mut self, event: InterfaceEvent) {
+        self.stations.learn(event);
+    }
+}
+
+/// The VLAN breakdown of `devices`: their MAC addresses on each access point and interface
+/// they are on, keyed by VLAN (or `untagged`).
+#[must_use]
+pub fn station_vlans(
+    devices: &[DeviceListItem],
+) -> BTreeMap<&str, BTreeMap<&str, BTreeMap<String, Vec<MacAddr>>>> {
+    let mut map = BTreeMap::new();
+    for item in devices {
+        let vlan = item
+            .device
+            .vlan
+            .map_or_else(|| "untagged".to_string(), |vlan| vlan.to_string());
+        for station in &item.stations {
+            map.entry(station.station.hostname.as_str())
+                .or_insert_with(BTreeMap::new)
+                .entry(station.station.interface.as_str())
+                .or_insert_with(BTreeMap::new)
+                .entry(vlan.clone())
+                .or_insert_with(Vec::new)
+                .push(item.mac);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ONLINE: &str = "00:00:00:00:00:01";
+    const KEPT: &str = "00:00:00:00:00:02";
+    const OLDEST: &str = "00:00:00:00:00:03";
+    const OLDER: &str = "00:00:00:00:00:04";
+    const NEWEST: &str = "00:00:00:00:00:05";
+    const UNSEEN: &str = "00:00:00:00:00:06";
+
+    fn mac(mac: &str) -> MacAddr {
+        mac.parse().unwrap()
+    }
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 2, 12, 0, 0).unwrap() + Duration::minutes(minutes)
+    }
+
+    fn event(device: &str, minutes: i64, action: Action) -> Event {
+        Event {
+            timestamp: at(minutes),
+            hostname: "garage-ap".to_string(),
+            interface: "wlan0".to_string(),
+            mac: mac(device),
+            action,
+        }
+    }
+
+    /// A device that stays online since minute 0, and offline devices last seen at minutes
+    /// 1 (kept), 10, 30 and 50, plus one that was never associated, so was never seen.
+    fn database(retention: Retention) -> Database {
+        let mut db = Database::new();
+        db.configure_retention(retention);
+        db.witness(event(ONLINE, 0, Action::Associated));
+        for (device, associated, disassociated) in [
+            (KEPT, 0, 1),
+            (OLDEST, 0, 10),
+            (OLDER, 20, 30),
+            (NEWEST, 40, 50),
+        ] {
+            db.witness(event(device, associated, Action::Associated));
+            db.witness(event(device, disassociated, Action::Disassociated));
+        }
+        db.witness(event(
+            UNSEEN,
+            5,
+            Action::AccountingStarted {
+                session_id: "5F3F4F6F-00000000".to_string(),
+            },
+        ));
+        db
+    }
+
+    fn remaining(db: &Database) -> Vec<String> {
+        db.devices().keys().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn prunes_offline_devices_older_than_the_max_age() {
+        let mut db = database(Retention {
+            max_age: Some(Duration::minutes(40)),
+            keep: [mac(KEPT)].into(),
+            ..Retention::default()
+        });
+
+        let pruned = db.prune(at(60));
+        assert_eq!((pruned.expired, pruned.evicted), (2, 0));
+        assert_eq!(remaining(&db), [ONLINE, KEPT, OLDER, NEWEST]);
+
+        let pruned = db.prune(at(60));
+        assert_eq!(
+            (pruned.expired, pruned.evicted),
+            (0, 0),
+            "nothing is left to prune"
+        );
+    }
+
+    #[test]
+    fn evicts_the_offline_devices_seen_least_recently() {
+        let mut db = database(Retention {
+            max_devices: Some(4),
+            keep: [mac(KEPT)].into(),
+            ..Retention::default()
+        });
+
+        let pruned = db.prune(at(60));
+        assert_eq!((pruned.expired, pruned.evicted), (0, 2));
+        assert_eq!(remaining(&db), [ONLINE, KEPT, OLDER, NEWEST]);
+
+        db.configure_retention(Retention {
+            max_devices: Some(3),
+            keep: [mac(KEPT)].into(),
+            ..Retention::default()
+        });
+        db.prune(at(60));
+        assert_eq!(remaining(&db), [ONLINE, KEPT, NEWEST]);
+    }
+
+    #[test]
+    fn never_prunes_online_or_kept_devices() {
+        let mut db = database(Retention {
+            max_age: Some(Duration::zero()),
+            max_devices: Some(0),
+            keep: [mac(KEPT), mac(OLDEST)].into(),
+        });
+
+        let pruned = db.prune(at(60));
+        assert_eq!((pruned.expired, pruned.evicted), (3, 0));
+        assert_eq!(remaining(&db), [ONLINE, KEPT, OLDEST]);
+    }
+
+    #[test]
+    fn keeps_everything_without_a_retention_policy() {
+        let mut db = database(Retention::default());
+
+        let pruned = db.prune(at(60 * 24 * 365));
+        assert_eq!((pruned.expired, pruned.evicted), (0, 0));
+        assert_eq!(db.devices().len(), 6);
+    }
 }
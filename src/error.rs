@@ -0,0 +1,40 @@
+/// Errors from reading logs and configuration, and from running the server.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("error parsing message: {0}")]
+    Parse(String),
+
+    #[error("no files were added to the file reader")]
+    NoFilesAdded,
+
+    #[error("auth configuration error: {0}")]
+    Auth(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("listen error: {0}")]
+    Listen(String),
+
+    #[error("time series error: {0}")]
+    Series(String),
+
+    #[error("export error: {0}")]
+    Export(String),
+
+    #[error("request error: {0}")]
+    Request(String),
+
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
@@ -0,0 +1,96 @@
+//! Pushes what is applied to the database to external stores, as it happens: time series
+//! databases through [`influx`], and log stores through [`logs`].
+//!
+//! Exporters subscribe to the batches applied through [`DB::apply`](crate::db::DB::apply),
+//! so they see the same records as the database, including the replay of the log at
+//! startup. The log stores get them through the one
+//! [`track_loop`](crate::sessions::track_loop), with the sessions and roams they make.
+//! Exporters collect what they send into batches, and retry a batch that fails or times
+//! out with growing delays before giving up on it.
+
+use std::time::Duration;
+
+pub mod influx;
+pub mod logs;
+
+/// How many times a batch is sent before it is dropped.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry, which doubles with every attempt.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+/// How long connecting to a store may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a request may take in all, so that a store that stops responding fails the
+/// attempt rather than holding up every batch behind it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The HTTP client exporters send with.
+///
+/// # Panics
+///
+/// Panics if the TLS backend can't be initialized, like [`reqwest::Client::new`].
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_else(|e| panic!("the HTTP client can't be built: {e}"))
+}
+
+/// Whether a failed request may succeed if it is sent again.
+#[derive(Debug)]
+enum Failure {
+    Transient(String),
+    Permanent(String),
+}
+
+/// Sends a batch with `send` until it succeeds, fails permanently, or runs out of attempts.
+/// Returns what a successful `send` returned.
+async fn with_retry<T, F, Fut>(what: &str, mut send: F) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Failure>>,
+{
+    let mut delay = FIRST_RETRY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match send().await {
+            Ok(response) => return Some(response),
+            Err(Failure::Permanent(e)) => {
+                tracing::error!("{what} rejected a batch, dropping it: {e}");
+                return None;
+            }
+            Err(Failure::Transient(e)) if attempt < MAX_ATTEMPTS => {
+                tracing::warn!("error sending to {what}, retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(Failure::Transient(e)) => {
+                tracing::error!("error sending to {what}, dropping the batch: {e}");
+            }
+        }
+    }
+    None
+}
+
+/// Sends `body` to `request` and returns the response body, treating server errors, rate
+/// limiting and connection errors as transient.
+async fn post(request: reqwest::RequestBuilder, body: String) -> Result<String, Failure> {
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| Failure::Transient(e.to_string()))?;
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    if status.is_success() {
+        return Ok(text);
+    }
+    let error = format!("{status}: {}", text.trim());
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(Failure::Transient(error))
+    } else {
+        Err(Failure::Permanent(error))
+    }
+}
@@ -0,0 +1,300 @@
+//! InfluxDB line protocol, which VictoriaMetrics and other time series databases accept too.
+//!
+//! Every station event becomes a `hostapd_event` point tagged with the access point,
+//! interface and action, with the MAC address as a field rather than a tag, so that every
+//! device seen doesn't add series. The number of clients on every access point is written
+//! as a `hostapd_clients` point at a fixed interval.
+
+use super::{client, post, with_retry, Failure};
+use crate::{
+    db::{Applied, DB},
+    parser::{Action, Event, Record},
+    status::SharedStatus,
+    storage::DeviceStore,
+    Error,
+};
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, fmt::Write, str::FromStr, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::broadcast::{error::RecvError, Receiver},
+    time::{interval, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+/// The most lines sent in one request.
+const MAX_LINES: usize = 5000;
+
+/// How often the lines collected so far are sent.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest datagram sent over UDP. Lines are never split across datagrams.
+const MAX_DATAGRAM: usize = 8192;
+
+/// Where the lines are written.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// An HTTP write endpoint, e.g. `http://influxdb:8086/api/v2/write?org=home&bucket=wifi`
+    /// or `http://victoriametrics:8428/write`.
+    Http(String),
+    /// A UDP listener, given as `udp://host:port`.
+    Udp(String),
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("udp://") {
+            Ok(Target::Udp(addr.trim_end_matches('/').to_string()))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Target::Http(s.to_string()))
+        } else {
+            Err(Error::Export(format!(
+                "expected an http://, https:// or udp:// URL, got {s}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub target: Target,
+    /// Sent as `Authorization: Token ...` over HTTP.
+    pub token: Option<String>,
+    /// How often the client counts are written.
+    pub interval: Duration,
+}
+
+/// Writes line protocol for the batches received from `applied`, which should be subscribed
+/// to `db` before ingestion starts, until shutdown. Client counts are only written once the
+/// log has been replayed, since until then they are of an earlier moment.
+pub async fn export_loop(
+    config: Config,
+    mut applied: Receiver<Applied>,
+    db: DB,
+    status: SharedStatus,
+    shutdown: CancellationToken,
+) {
+    let mut sink = match Sink::new(&config).await {
+        Ok(sink) => sink,
+        Err(e) => {
+            tracing::error!("error setting up the InfluxDB exporter: {}", e);
+            return;
+        }
+    };
+    let mut flush = interval(FLUSH_INTERVAL);
+    let mut counts = interval(config.interval);
+    counts.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut batch = Batch::default();
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            received = applied.recv() => match received {
+                Ok(records) => {
+                    for (record, _) in records.iter() {
+                        if let Record::Station(event) = record {
+                            batch.event(event);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("the InfluxDB exporter fell behind and missed {missed} batches of events");
+                }
+                Err(RecvError::Closed) => {
+                    break;
+                }
+            },
+            _ = counts.tick() => {
+                if status.replayed() {
+                    if let Err(e) = batch.clients(&*db.read(), Utc::now()) {
+                        tracing::error!("error counting the clients for InfluxDB: {}", e);
+                    }
+                }
+            }
+            _ = flush.tick() => {
+                sink.send(batch.take()).await;
+            }
+        }
+        if batch.lines >= MAX_LINES {
+            sink.send(batch.take()).await;
+        }
+    }
+    sink.send(batch.take()).await;
+}
+
+/// Lines waiting to be sent.
+#[derive(Debug, Default)]
+struct Batch {
+    body: String,
+    lines: usize,
+    /// The timestamp of the last event of each series, and the one it was written with.
+    /// Points of a series with the same timestamp overwrite each other, so events logged
+    /// in the same second are written a nanosecond apart.
+    written: BTreeMap<String, (i64, i64)>,
+}
+
+impl Batch {
+    fn take(&mut self) -> String {
+        self.lines = 0;
+        std::mem::take(&mut self.body)
+    }
+
+    fn event(&mut self, event: &Event) {
+        let Some(logged) = event.timestamp.timestamp_nanos_opt() else {
+            return;
+        };
+        let series = format!(
+            "hostapd_event,ap={},interface={},action={}",
+            tag(&event.hostname),
+            tag(&event.interface),
+            event.action.name(),
+        );
+        let timestamp = match self.written.get(&series) {
+            Some(&(last, written)) if last == logged => written + 1,
+            _ => logged,
+        };
+        let mut fields = format!("count=1i,mac=\"{}\"", event.mac);
+        match event.action {
+            Action::AccountingStarted { ref session_id } => {
+                let _ = write!(fields, ",session_id={}", string_field(session_id));
+            }
+            Action::Identified { ref identity } => {
+                let _ = write!(fields, ",identity={}", string_field(identity));
+            }
+            Action::VlanAssigned { vlan } => {
+                let _ = write!(fields, ",vlan={vlan}i");
+            }
+            Action::VlanBound { ref vlan_interface } => {
+                let _ = write!(fields, ",vlan_interface={}", string_field(vlan_interface));
+            }
+            Action::Associated | Action::Disassociated | Action::Observed => {}
+        }
+        let _ = writeln!(self.body, "{series} {fields} {timestamp}");
+        self.written.insert(series, (logged, timestamp));
+        self.lines += 1;
+    }
+
+    /// Adds the number of devices on every access point, counting a device on several of
+    /// an access point's interfaces once.
+    fn clients(&mut self, db: &dyn DeviceStore, now: DateTime<Utc>) -> Result<(), Error> {
+        let Some(timestamp) = now.timestamp_nanos_opt() else {
+            return Ok(());
+        };
+        for (ap, clients) in db.access_point_clients()? {
+            let _ = writeln!(
+                self.body,
+                "hostapd_clients,ap={} clients={clients}i {timestamp}",
+                tag(&ap)
+            );
+            self.lines += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a tag value.
+fn tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Quotes and escapes a string field value.
+fn string_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+enum Sink {
+    Http {
+        client: reqwest::Client,
+        url: String,
+        token: Option<String>,
+    },
+    Udp(UdpSocket),
+}
+
+impl Sink {
+    async fn new(config: &Config) -> Result<Self, Error> {
+        match config.target {
+            Target::Http(ref url) => Ok(Sink::Http {
+                client: client(),
+                url: url.clone(),
+                token: config.token.clone(),
+            }),
+            Target::Udp(ref addr) => {
+                let addr = tokio::net::lookup_host(addr)
+                    .await?
+                    .next()
+                    .ok_or_else(|| Error::Export(format!("can't resolve {addr}")))?;
+                let local = if addr.is_ipv6() {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Ok(Sink::Udp(socket))
+            }
+        }
+    }
+
+    async fn send(&mut self, body: String) {
+        if body.is_empty() {
+            return;
+        }
+        match self {
+            Sink::Http { client, url, token } => {
+                with_retry("InfluxDB", || {
+                    let mut request = client
+                        .post(url.as_str())
+                        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8");
+                    if let Some(token) = token {
+                        request = request
+                            .header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+                    }
+                    post(request, body.clone())
+                })
+                .await;
+            }
+            Sink::Udp(socket) => {
+                for datagram in datagrams(&body) {
+                    let socket = &*socket;
+                    with_retry("InfluxDB", || async move {
+                        socket
+                            .send(datagram.as_bytes())
+                            .await
+                            .map_err(|e| Failure::Transient(e.to_string()))
+                    })
+                    .await;
+                }
+            }
+        }
+    }
+}
+
+/// Splits lines into datagrams of at most [`MAX_DATAGRAM`] bytes, unless a single line is
+/// longer.
+fn datagrams(body: &str) -> Vec<&str> {
+    let mut datagrams = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for line in body.split_inclusive('\n') {
+        if end > start && end - start + line.len() > MAX_DATAGRAM {
+            datagrams.push(&body[start..end]);
+            start = end;
+        }
+        end += line.len();
+    }
+    if end > start {
+        datagrams.push(&body[start..end]);
+    }
+    datagrams
+}
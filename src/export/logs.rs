@@ -0,0 +1,516 @@
+//! Structured entries for log stores: Loki's push API and Elasticsearch's bulk API.
+//!
+//! Every station event is sent as an `event` entry, along with a `session` entry whenever a
+//! device leaves a station and a `roam` entry whenever it moves between stations, as
+//! worked out once for every store by [`track_loop`](crate::sessions::track_loop).
+//! Entries are labelled with the access point, the interface and the interface's label,
+//! when one is configured.
+//!
+//! Batches are sent from a task of their own, so that a slow store doesn't hold up
+//! receiving the records. With a state directory, how far into the log the sent entries
+//! go is kept in a file per store, and the entries replayed from the log after a restart
+//! that were sent before it are skipped.
+
+use super::{client, post, with_retry};
+use crate::{
+    db::{Station, DB},
+    parser::{Event, Record},
+    sessions::{Derived, Roam, Session, Tracked},
+    storage::{DeviceStore, Position},
+    Error,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        mpsc::{self, error::TrySendError},
+    },
+    time::interval,
+};
+use tokio_util::sync::CancellationToken;
+
+/// The most entries sent in one request.
+const MAX_ENTRIES: usize = 1000;
+
+/// How often the entries collected so far are sent.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many batches may wait to be sent before new ones are dropped.
+const QUEUE_CAPACITY: usize = 64;
+
+/// The `job` label of every Loki stream.
+const JOB: &str = "hostapd-api";
+
+/// Where the entries are sent.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// Loki's push endpoint, e.g. `http://loki:3100/loki/api/v1/push`.
+    Loki { url: String },
+    /// An Elasticsearch server, e.g. `http://elasticsearch:9200`, whose `/_bulk` endpoint
+    /// indexes the entries into `index`.
+    Elasticsearch {
+        url: String,
+        index: String,
+        /// Sent as `Authorization: ApiKey ...`.
+        api_key: Option<String>,
+    },
+}
+
+impl Target {
+    fn name(&self) -> &'static str {
+        match self {
+            Target::Loki { .. } => "Loki",
+            Target::Elasticsearch { .. } => "Elasticsearch",
+        }
+    }
+
+    /// Where the entries go, which tells the progress of one target from another's.
+    fn key(&self) -> String {
+        match self {
+            Target::Loki { url } => url.clone(),
+            Target::Elasticsearch { url, index, .. } => {
+                format!("{}/{index}", url.trim_end_matches('/'))
+            }
+        }
+    }
+
+    /// The file in the state directory its progress is kept in.
+    fn state_file(&self) -> &'static str {
+        match self {
+            Target::Loki { .. } => "loki.json",
+            Target::Elasticsearch { .. } => "elasticsearch.json",
+        }
+    }
+}
+
+/// How far into each log file the entries sent to a target go.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Progress {
+    /// The [`Target::key`] it is for, so that a different target is sent everything.
+    target: String,
+    files: BTreeMap<String, Read>,
+}
+
+/// The last line read from a log file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Read {
+    file_id: u64,
+    offset: u64,
+}
+
+impl Progress {
+    fn new(target: &Target) -> Self {
+        Self {
+            target: target.key(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the progress for `target` from `path`, starting afresh if there is none.
+    async fn load(path: &Path, target: &Target) -> Self {
+        let fresh = Self::new(target);
+        let loaded = match tokio::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice::<Self>(&bytes).map_err(Error::from),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return fresh,
+            Err(e) => Err(e.into()),
+        };
+        match loaded {
+            Ok(progress) if progress.target == fresh.target => progress,
+            Ok(_) => fresh,
+            Err(e) => {
+                tracing::warn!(
+                    "error reading {}, sending the whole log to {}: {}",
+                    path.display(),
+                    target.name(),
+                    e
+                );
+                fresh
+            }
+        }
+    }
+
+    /// Whether the line at `position` was sent.
+    fn covers(&self, position: &Position) -> bool {
+        self.files
+            .get(&*position.file)
+            .is_some_and(|read| read.file_id == position.file_id && position.offset <= read.offset)
+    }
+
+    /// Writes the progress to `path`, through a temporary file so that it is never left
+    /// half written.
+    async fn save(&self, path: &Path) -> Result<(), Error> {
+        let temporary = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Entry {
+    Event(Event),
+    Session(Session),
+    Roam(Roam),
+}
+
+impl From<Derived> for Entry {
+    fn from(derived: Derived) -> Self {
+        match derived {
+            Derived::Session(session) => Entry::Session(session),
+            Derived::Roam(roam) => Entry::Roam(roam),
+        }
+    }
+}
+
+impl Entry {
+    fn kind(&self) -> &'static str {
+        match self {
+            Entry::Event(_) => "event",
+            Entry::Session(_) => "session",
+            Entry::Roam(_) => "roam",
+        }
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Entry::Event(event) => event.timestamp,
+            Entry::Session(session) => session.end,
+            Entry::Roam(roam) => roam.at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct Labels {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    ap: String,
+    interface: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+impl Labels {
+    fn new(entry: &Entry, station: Station, db: &dyn DeviceStore) -> Self {
+        Self {
+            kind: entry.kind(),
+            label: db.interface_info(&station).and_then(|info| info.label),
+            ap: station.hostname,
+            interface: station.interface,
+        }
+    }
+}
+
+/// Entries to send, and how far into each log file the records they were made from go.
+#[derive(Debug, Default)]
+struct Batch {
+    entries: Vec<(Entry, Labels)>,
+    read: BTreeMap<String, Read>,
+}
+
+impl Batch {
+    /// Adds the entries for a record and the sessions and roams it made, labelled from `db`.
+    fn add(&mut self, tracked: &Tracked, db: &dyn DeviceStore) {
+        if let Record::Station(ref event) = tracked.record {
+            let entry = Entry::Event(event.clone());
+            let station = Station {
+                hostname: event.hostname.clone(),
+                interface: event.interface.clone(),
+            };
+            let labels = Labels::new(&entry, station, db);
+            self.entries.push((entry, labels));
+        }
+        for derived in &tracked.derived {
+            let station = derived.station();
+            let entry = Entry::from(derived.clone());
+            let labels = Labels::new(&entry, station, db);
+            self.entries.push((entry, labels));
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.read.is_empty()
+    }
+}
+
+/// Sends an entry for every event received from `tracked`, which should be subscribed
+/// before ingestion starts, and for the sessions and roams they make, until shutdown. The
+/// labels are looked up in `db`. With a `state_dir`, the events sent before a restart
+/// aren't sent again.
+pub async fn export_loop(
+    target: Target,
+    mut tracked: Receiver<Arc<Vec<Tracked>>>,
+    db: DB,
+    state_dir: Option<PathBuf>,
+    shutdown: CancellationToken,
+) {
+    let path = state_dir.map(|dir| dir.join(target.state_file()));
+    let sent = match path {
+        Some(ref path) => Progress::load(path, &target).await,
+        None => Progress::new(&target),
+    };
+    let (queue, queued) = mpsc::channel(QUEUE_CAPACITY);
+    let sender = tokio::spawn(send_loop(target.clone(), queued, sent.clone(), path));
+    let mut flush = interval(FLUSH_INTERVAL);
+    let mut batch = Batch::default();
+    // whether the records were sent before a restart, which the admin changes restored in
+    // between them were too
+    let mut resent = false;
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            received = tracked.recv() => match received {
+                Ok(records) => {
+                    let live = db.read();
+                    for record in records.iter() {
+                        if let Some(ref position) = record.position {
+                            resent = sent.covers(position);
+                            if !resent {
+                                let read = Read {
+                                    file_id: position.file_id,
+                                    offset: position.offset,
+                                };
+                                batch.read.insert(position.file.to_string(), read);
+                            }
+                        }
+                        if !resent {
+                            batch.add(record, &*live);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("the {} exporter fell behind and missed {missed} batches of events", target.name());
+                }
+                Err(RecvError::Closed) => {
+                    break;
+                }
+            },
+            _ = flush.tick() => {
+                enqueue(&queue, &target, std::mem::take(&mut batch));
+            }
+        }
+        while batch.entries.len() >= MAX_ENTRIES {
+            let rest = batch.entries.split_off(MAX_ENTRIES);
+            let full = Batch {
+                entries: std::mem::replace(&mut batch.entries, rest),
+                read: BTreeMap::new(),
+            };
+            enqueue(&queue, &target, full);
+        }
+    }
+    // wait for room for the last batch rather than drop it
+    if !batch.is_empty() {
+        let _ = queue.send(batch).await;
+    }
+    drop(queue);
+    if let Err(e) = sender.await {
+        tracing::error!("the {} exporter failed: {}", target.name(), e);
+    }
+}
+
+/// Queues a batch to be sent, or drops it if too many are waiting already, so that a slow
+/// store doesn't hold up receiving the records until some are missed anyway.
+fn enqueue(queue: &mpsc::Sender<Batch>, target: &Target, batch: Batch) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(TrySendError::Full(batch)) = queue.try_send(batch) {
+        tracing::warn!(
+            "{} can't keep up, dropping {} entries",
+            target.name(),
+            batch.entries.len()
+        );
+    }
+}
+
+/// Sends the batches received from `queued` in turn, and keeps how far into the log the
+/// sent ones go in `path`.
+async fn send_loop(
+    target: Target,
+    mut queued: mpsc::Receiver<Batch>,
+    mut sent: Progress,
+    path: Option<PathBuf>,
+) {
+    let client = client();
+    while let Some(batch) = queued.recv().await {
+        if !send(&client, &target, batch.entries).await || batch.read.is_empty() {
+            continue;
+        }
+        let Some(ref path) = path else {
+            continue;
+        };
+        sent.files.extend(batch.read);
+        if let Err(e) = sent.save(path).await {
+            tracing::error!("error saving {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Sends a batch, and returns whether it was sent.
+async fn send(client: &reqwest::Client, target: &Target, batch: Vec<(Entry, Labels)>) -> bool {
+    if batch.is_empty() {
+        return true;
+    }
+    match *target {
+        Target::Loki { ref url } => {
+            let body = match loki_body(batch) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("error encoding entries for Loki: {}", e);
+                    return false;
+                }
+            };
+            let request = || {
+                client
+                    .post(url.as_str())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+            };
+            with_retry("Loki", || post(request(), body.clone()))
+                .await
+                .is_some()
+        }
+        Target::Elasticsearch {
+            ref url,
+            ref index,
+            ref api_key,
+        } => {
+            let body = match bulk_body(&batch, index) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("error encoding entries for Elasticsearch: {}", e);
+                    return false;
+                }
+            };
+            let url = format!("{}/_bulk", url.trim_end_matches('/'));
+            let request = || {
+                let request = client
+                    .post(url.as_str())
+                    .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson");
+                match api_key {
+                    Some(key) => {
+                        request.header(reqwest::header::AUTHORIZATION, format!("ApiKey {key}"))
+                    }
+                    None => request,
+                }
+            };
+            let sent = with_retry("Elasticsearch", || post(request(), body.clone())).await;
+            if let Some(ref response) = sent {
+                check_bulk_response(response);
+            }
+            sent.is_some()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Stream<'a> {
+    stream: &'a Labels,
+    values: Vec<[String; 2]>,
+}
+
+/// The JSON body of a Loki push: one stream per set of labels, each with its lines in
+/// order.
+fn loki_body(batch: Vec<(Entry, Labels)>) -> Result<String, serde_json::Error> {
+    let mut streams: BTreeMap<Labels, Vec<(DateTime<Utc>, String)>> = BTreeMap::new();
+    for (entry, labels) in batch {
+        let line = serde_json::to_string(&entry)?;
+        streams
+            .entry(labels)
+            .or_default()
+            .push((entry.timestamp(), line));
+    }
+
+    let mut push = Vec::new();
+    for (labels, mut lines) in streams {
+        lines.sort_by_key(|(timestamp, _)| *timestamp);
+        let mut stream = serde_json::to_value(Stream {
+            stream: &labels,
+            values: lines
+                .into_iter()
+                .map(|(timestamp, line)| {
+                    [
+                        timestamp
+                            .timestamp_nanos_opt()
+                            .unwrap_or_default()
+                            .to_string(),
+                        line,
+                    ]
+                })
+                .collect(),
+        })?;
+        stream["stream"]["job"] = JOB.into();
+        push.push(stream);
+    }
+    serde_json::to_string(&serde_json::json!({ "streams": push }))
+}
+
+#[derive(Debug, Serialize)]
+struct Document<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: DateTime<Utc>,
+    labels: &'a Labels,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+/// The NDJSON body of an Elasticsearch bulk request. Each document's ID is a hash of its
+/// contents, so the entries sent again when the log is replayed at startup replace the
+/// earlier copies rather than adding to them.
+fn bulk_body(batch: &[(Entry, Labels)], index: &str) -> Result<String, serde_json::Error> {
+    let mut body = String::new();
+    for (entry, labels) in batch {
+        let document = serde_json::to_string(&Document {
+            timestamp: entry.timestamp(),
+            labels,
+            entry,
+        })?;
+        let action = serde_json::json!({
+            "index": { "_index": index, "_id": format!("{:016x}", fnv1a(document.as_bytes())) }
+        });
+        let _ = writeln!(body, "{action}\n{document}");
+    }
+    Ok(body)
+}
+
+/// A 64-bit FNV-1a hash, which unlike the standard library's hasher is stable across
+/// releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Logs the documents that a bulk request accepted but failed to index.
+fn check_bulk_response(response: &str) {
+    let Ok(response) = serde_json::from_str::<Value>(response) else {
+        return;
+    };
+    if response["errors"] != Value::Bool(true) {
+        return;
+    }
+    let failed: Vec<&Value> = response["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|item| item["index"].get("error"))
+        .collect();
+    if let Some(first) = failed.first() {
+        tracing::error!(
+            "Elasticsearch failed to index {} entries, e.g. {first}",
+            failed.len()
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{
+    db::Database,
+    parser::Record,
+    storage::{quietly, Position, Replay, Storage},
+    Error,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{collections::VecDeque, sync::Arc};
+
+/// Every record applied to the database, kept in memory, so that its state at an earlier
+/// moment can be rebuilt by replaying them through [`Database::apply`], just as ingestion
+/// applied them.
+///
+/// Records older than the maximum age are folded into a base database, which the replay
+/// starts from, so the history can't go back further than that. The records are shared
+/// with the replays taken from the history, so taking one doesn't copy them.
+#[derive(Debug)]
+pub struct History {
+    base: Database,
+    /// The newest record folded into `base`.
+    start: Option<DateTime<Utc>>,
+    records: VecDeque<Arc<Record>>,
+    max_age: Option<Duration>,
+}
+
+impl History {
+    /// Starts a history from `base`, usually an empty, configured database.
+    #[must_use]
+    pub fn new(base: Database, max_age: Option<Duration>) -> Self {
+        Self {
+            base,
+            start: None,
+            records: VecDeque::new(),
+            max_age,
+        }
+    }
+
+    /// The number of records kept.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl Storage for History {
+    fn record(&mut self, records: &[(Record, Option<Position>)]) -> Result<(), Error> {
+        // it doesn't outlive a restart, so it never sees a record twice
+        self.records
+            .extend(records.iter().map(|(record, _)| Arc::new(record.clone())));
+        Ok(())
+    }
+
+    fn start(&self) -> Option<DateTime<Utc>> {
+        self.start
+    }
+
+    fn base(&self) -> &Database {
+        &self.base
+    }
+
+    fn replay(&self, until: DateTime<Utc>) -> Result<Replay, Error> {
+        let records: Vec<Arc<Record>> = self
+            .records
+            .iter()
+            .filter(|record| record.timestamp() <= until)
+            .cloned()
+            .collect();
+        Ok(Replay::new(self.base.clone(), move |f| {
+            for record in records {
+                f(Arc::try_unwrap(record).unwrap_or_else(|record| (*record).clone()));
+            }
+            Ok(())
+        }))
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let Some(max_age) = self.max_age else {
+            return Ok(0);
+        };
+        let horizon = now - max_age;
+        let pruned = self
+            .records
+            .iter()
+            .take_while(|record| record.timestamp() < horizon)
+            .count();
+        quietly(|| {
+            for record in self.records.drain(..pruned) {
+                self.start = self.start.max(Some(record.timestamp()));
+                self.base
+                    .apply(Arc::try_unwrap(record).unwrap_or_else(|record| (*record).clone()));
+            }
+        });
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{
+    db::DB,
+    parser::{self, Record},
+    status::{SharedStatus, Status},
+    storage::Position,
+    Error,
+};
+use linemux::{Line, MuxedLines};
+use std::{
+    fs::File,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How many parsed records may wait to be applied before reading is held back.
+pub const QUEUE_CAPACITY: usize = 8192;
+
+/// The most records applied under one write of the database.
+const MAX_BATCH: usize = QUEUE_CAPACITY;
+
+/// Where [`read_loop`] reads log lines from, which is [`MuxedLines`] outside of tests.
+pub trait Source: Send {
+    type Line: Send;
+
+    /// Waits for the next line, or returns `None` once there are no files left to follow.
+    fn next_line(&mut self) -> impl Future<Output = io::Result<Option<Self::Line>>> + Send;
+
+    /// The path of the file `line` was read from, and the line itself.
+    fn split(line: &Self::Line) -> (&Path, &str);
+}
+
+impl Source for MuxedLines {
+    type Line = Line;
+
+    fn next_line(&mut self) -> impl Future<Output = io::Result<Option<Line>>> + Send {
+        MuxedLines::next_line(self)
+    }
+
+    fn split(line: &Line) -> (&Path, &str) {
+        (line.source(), line.line())
+    }
+}
+
+/// Reads and parses log lines, and queues the records for [`apply_loop`]. When the queue
+/// is full, reading waits for the database to catch up.
+pub async fn read_loop<S: Source>(
+    mut lines: S,
+    queue: mpsc::Sender<(Record, Position)>,
+    status: SharedStatus,
+    shutdown: CancellationToken,
+) {
+    let mut positions = Positions::default();
+    status.set_tailing(true);
+    loop {
+        let next_line = tokio::select! {
+            next_line = lines.next_line() => next_line,
+            () = shutdown.cancelled() => {
+                break;
+            }
+        };
+        let record = match process::<S>(next_line, &status, &mut positions) {
+            Ok(Some(record)) => record,
+            Ok(None) => continue,
+            Err(e) => {
+                shutdown.cancel();
+                tracing::error!("error parsing log: {}", e);
+                break;
+            }
+        };
+
+        // counted before it is sent so the apply stage never sees a negative depth
+        status.queued();
+        let record = match queue.try_send(record) {
+            Ok(()) => continue,
+            Err(TrySendError::Full(record)) => record,
+            Err(TrySendError::Closed(_)) => break,
+        };
+        let waiting = Instant::now();
+        tokio::select! {
+            sent = queue.send(record) => {
+                if sent.is_err() {
+                    break;
+                }
+            }
+            () = shutdown.cancelled() => {
+                break;
+            }
+        }
+        status.held_back(waiting.elapsed());
+    }
+    status.set_tailing(false);
+}
+
+/// Applies queued records to the database until the queue is closed. Everything that is
+/// waiting is applied in one batch, so a snapshot is published per batch rather than per
+/// record. As soon as the log has been replayed, the admin changes from before a restart
+/// that it didn't catch up with are restored.
+pub async fn apply_loop(
+    mut queue: mpsc::Receiver<(Record, Position)>,
+    db: DB,
+    status: SharedStatus,
+) {
+    let mut restored = false;
+    loop {
+        let received = tokio::select! {
+            biased;
+            () = status.until_replayed(), if !restored => {
+                db.restore().await;
+                restored = true;
+                continue;
+            }
+            received = queue.recv() => received,
+        };
+        let Some(record) = received else {
+            break;
+        };
+        let mut batch = vec![record];
+        while batch.len() < MAX_BATCH {
+            match queue.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(_) => break,
+            }
+        }
+        let applied = batch.len();
+        // the snapshot is published before the records are reported as applied
+        db.apply_read(batch).await;
+        status.applied(applied);
+    }
+}
+
+fn process<S: Source>(
+    next_line: io::Result<Option<S::Line>>,
+    status: &Status,
+    positions: &mut Positions,
+) -> Result<Option<(Record, Position)>, Error> {
+    match next_line {
+        Ok(Some(line)) => {
+            let (source, line) = S::split(&line);
+            let (position, length) = positions.next(source, line);
+            let record = parser::parse(line);
+            status.line_read(source, length, record.is_err());
+            match record {
+                Ok(Some(event)) => Ok(Some((event, position))),
+                Ok(None) => Ok(None),
+                // one bad line shouldn't stop ingestion, so it is skipped and counted
+                Err(e) => {
+                    tracing::warn!("skipping a line that couldn't be parsed: {}", e);
+                    Ok(None)
+                }
+            }
+        }
+        Ok(None) => Err(Error::NoFilesAdded),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Works out where in its file each line was read from, since linemux doesn't say.
+#[derive(Debug, Default)]
+struct Positions {
+    files: Vec<FilePosition>,
+}
+
+#[derive(Debug)]
+struct FilePosition {
+    path: PathBuf,
+    name: Arc<str>,
+    id: u64,
+    /// The size of the file when it was last looked at.
+    size: u64,
+    /// Where the next line starts.
+    offset: u64,
+    /// The file, to tell how each line ends, since linemux strips `\n` and `\r\n` alike.
+    opened: Option<File>,
+}
+
+impl Positions {
+    /// The position of `line`, the next line read from `path`, and its length in the file
+    /// with the line break.
+    fn next(&mut self, path: &Path, line: &str) -> (Position, u64) {
+        let i = self
+            .files
+            .iter()
+            .position(|file| file.path == path)
+            .unwrap_or_else(|| {
+                self.files.push(FilePosition {
+                    path: path.to_path_buf(),
+                    name: path.to_string_lossy().into(),
+                    id: 0,
+                    size: 0,
+                    offset: 0,
+                    opened: None,
+                });
+                self.files.len() - 1
+            });
+        let file = &mut self.files[i];
+        // read past what the file had the last time it was looked at, so it grew, or a
+        // new file was put in its place
+        if file.offset >= file.size {
+            if let Some((opened, id, size)) = identify(path) {
+                if id != file.id || size < file.offset {
+                    file.id = id;
+                    file.offset = 0;
+                }
+                file.size = size;
+                file.opened = Some(opened);
+            }
+        }
+        let position = Position {
+            file: file.name.clone(),
+            file_id: file.id,
+            offset: file.offset,
+        };
+        let end = file.offset + line.len() as u64;
+        let length = line.len() as u64 + file.opened.as_ref().map_or(1, |f| line_break(f, end));
+        file.offset += length;
+        (position, length)
+    }
+}
+
+/// The file at `path`, with its inode and size, or `None` if there is no file there, e.g.
+/// while it is being rotated.
+#[cfg(unix)]
+fn identify(path: &Path) -> Option<(File, u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    Some((file, metadata.ino(), metadata.len()))
+}
+
+/// Files have no inode to tell them apart here, so a new file is only told apart from the
+/// earlier one if it is shorter than what was read from that.
+#[cfg(not(unix))]
+fn identify(path: &Path) -> Option<(File, u64, u64)> {
+    let file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    Some((file, 1, size))
+}
+
+/// The length of the line break at `at` in `file`: none at the end of the file, where the
+/// last line isn't finished yet.
+#[cfg(unix)]
+fn line_break(file: &File, at: u64) -> u64 {
+    use std::os::unix::fs::FileExt;
+    let mut bytes = [0; 1];
+    match file.read_at(&mut bytes, at) {
+        Ok(0) => 0,
+        Ok(_) if bytes[0] == b'\r' => 2,
+        // a newline, or the file was replaced since the line was read
+        _ => 1,
+    }
+}
+
+/// Without positioned reads, lines are taken to end with a newline.
+#[cfg(not(unix))]
+fn line_break(_: &File, _: u64) -> u64 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{Database, DeviceQuery},
+        history::History,
+    };
+    use serde_json::Value;
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+
+    /// Lines sent by the test, as if they had been read from one file.
+    struct FakeSource(mpsc::UnboundedReceiver<String>);
+
+    impl Source for FakeSource {
+        type Line = (PathBuf, String);
+
+        async fn next_line(&mut self) -> io::Result<Option<Self::Line>> {
+            Ok(self
+                .0
+                .recv()
+                .await
+                .map(|line| (PathBuf::from("/var/log/hostapd.json"), line)))
+        }
+
+        fn split(line: &Self::Line) -> (&Path, &str) {
+            (&line.0, &line.1)
+        }
+    }
+
+    /// The loops over a fake source, with their queue holding at most `capacity` records.
+    struct Fixture {
+        lines: mpsc::UnboundedSender<String>,
+        db: DB,
+        status: SharedStatus,
+        shutdown: CancellationToken,
+        reading: JoinHandle<()>,
+        queued: Option<mpsc::Receiver<(Record, Position)>>,
+    }
+
+    impl Fixture {
+        fn new(capacity: usize) -> Self {
+            let (lines, source) = mpsc::unbounded_channel();
+            let (queue, queued) = mpsc::channel(capacity);
+            let status = Arc::new(Status::new(chrono::Duration::minutes(5), false));
+            let shutdown = CancellationToken::new();
+            let reading = tokio::spawn(read_loop(
+                FakeSource(source),
+                queue,
+                status.clone(),
+                shutdown.clone(),
+            ));
+            Self {
+                lines,
+                db: DB::new(
+                    Box::new(Database::new()),
+                    Box::new(History::new(Database::new(), None)),
+                )
+                .unwrap(),
+                status,
+                shutdown,
+                reading,
+                queued: Some(queued),
+            }
+        }
+
+        /// Logs a different device associating `count` times.
+        fn associate(&self, count: u8) {
+            for i in 0..count {
+                self.lines
+                    .send(format!(
+                        r#"{{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:00:{i:02}Z","message":"wlan0: STA 02:00:00:00:00:{i:02x} IEEE 802.11: associated"}}"#
+                    ))
+                    .unwrap();
+            }
+        }
+
+        fn apply(&mut self) -> JoinHandle<()> {
+            tokio::spawn(apply_loop(
+                self.queued.take().unwrap(),
+                self.db.clone(),
+                self.status.clone(),
+            ))
+        }
+
+        fn counter(&self, name: &str) -> u64 {
+            let report = serde_json::to_value(self.status.report(None)).unwrap();
+            report["ingest"][name].as_u64().unwrap()
+        }
+
+        fn devices(&self) -> usize {
+            let db = self.db.read();
+            db.device_list(&DeviceQuery::default()).unwrap().len()
+        }
+
+        /// Waits for the counter `name` to reach `value`.
+        async fn until(&self, name: &str, value: u64) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.counter(name) != value {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{name} stayed at {}", self.counter(name)));
+        }
+
+        /// Stops reading, which closes the queue and so stops applying too.
+        async fn stop(self, applying: JoinHandle<()>) {
+            self.shutdown.cancel();
+            self.reading.await.unwrap();
+            applying.await.unwrap();
+            let report = serde_json::to_value(self.status.report(None)).unwrap();
+            assert_eq!(report["readiness"]["tailing"], Value::Bool(false));
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_everything_waiting_in_one_batch() {
+        let mut fixture = Fixture::new(16);
+        fixture.associate(5);
+        fixture.until("queue_depth", 5).await;
+        assert_eq!(fixture.counter("records_applied"), 0);
+
+        let applying = fixture.apply();
+        fixture.until("records_applied", 5).await;
+        assert_eq!(fixture.counter("batches"), 1);
+        assert_eq!(fixture.counter("queue_depth"), 0);
+        assert_eq!(fixture.counter("held_back"), 0);
+        assert_eq!(fixture.devices(), 5);
+        fixture.stop(applying).await;
+    }
+
+    #[tokio::test]
+    async fn holds_reading_back_while_the_queue_is_full() {
+        let mut fixture = Fixture::new(2);
+        fixture.associate(5);
+        // two records are queued, and the third waits for room
+        fixture.until("queue_depth", 3).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(fixture.counter("queue_depth"), 3);
+        assert_eq!(fixture.counter("held_back"), 0);
+
+        let applying = fixture.apply();
+        fixture.until("records_applied", 5).await;
+        assert!(fixture.counter("held_back") >= 1);
+        assert!(fixture.counter("batches") < 5);
+        assert_eq!(fixture.counter("queue_depth"), 0);
+        assert_eq!(fixture.devices(), 5);
+        fixture.stop(applying).await;
+    }
+
+    #[tokio::test]
+    async fn skips_lines_that_are_not_records() {
+        let mut fixture = Fixture::new(16);
+        let applying = fixture.apply();
+        fixture.lines.send("not json".to_owned()).unwrap();
+        fixture.associate(1);
+        fixture.until("records_applied", 1).await;
+        assert_eq!(fixture.counter("batches"), 1);
+        assert_eq!(fixture.counter("parse_errors"), 1);
+        fixture.stop(applying).await;
+    }
+
+    #[test]
+    fn measures_lines_with_their_line_breaks() {
+        let path =
+            std::env::temp_dir().join(format!("hostapd-api-crlf-{}.log", std::process::id()));
+        std::fs::write(&path, "one\r\ntwo\nthree").unwrap();
+        let mut positions = Positions::default();
+        let read: Vec<(u64, u64)> = ["one", "two", "three"]
+            .into_iter()
+            .map(|line| {
+                let (position, length) = positions.next(&path, line);
+                (position.offset, length)
+            })
+            .collect();
+        let _ = std::fs::remove_file(path);
+        assert_eq!(read, [(0, 5), (5, 4), (9, 5)]);
+    }
+}
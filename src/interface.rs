@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+/// Matches any access point in the interface configuration file.
+const ANY_HOST: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum Band {
+    #[serde(rename = "2.4")]
+    TwoPointFour,
+
+    #[serde(rename = "5")]
+    Five,
+
+    #[serde(rename = "6")]
+    Six,
+}
+
+impl Band {
+    #[must_use]
+    pub fn from_frequency(frequency: u32) -> Option<Self> {
+        match frequency {
+            2400..=2500 => Some(Self::TwoPointFour),
+            5150..=5925 => Some(Self::Five),
+            5926..=7125 => Some(Self::Six),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a frequency in MHz to its IEEE 802.11 channel number.
+#[must_use]
+pub fn channel_from_frequency(frequency: u32) -> Option<u16> {
+    let channel = match Band::from_frequency(frequency)? {
+        Band::TwoPointFour if frequency == 2484 => 14,
+        Band::TwoPointFour => (frequency.checked_sub(2407)?) / 5,
+        Band::Five => (frequency - 5000) / 5,
+        Band::Six if frequency == 5935 => 2,
+        Band::Six => (frequency.checked_sub(5950)?) / 5,
+    };
+    u16::try_from(channel).ok()
+}
+
+/// Metadata about the radio behind an access point interface, either configured
+/// or learned from hostapd startup messages.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct InterfaceInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub band: Option<Band>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl InterfaceInfo {
+    /// Fills in any fields that are unset in `self` from `other`.
+    fn or(mut self, other: &Self) -> Self {
+        if self.ssid.is_none() {
+            self.ssid.clone_from(&other.ssid);
+        }
+        if self.band.is_none() {
+            self.band = other.band;
+        }
+        if self.channel.is_none() {
+            self.channel = other.channel;
+        }
+        if self.frequency.is_none() {
+            self.frequency = other.frequency;
+        }
+        if self.label.is_none() {
+            self.label.clone_from(&other.label);
+        }
+        self
+    }
+
+    fn learn_frequency(&mut self, frequency: u32, channel: Option<u16>) {
+        self.frequency.replace(frequency);
+        self.band = Band::from_frequency(frequency);
+        self.channel = channel.or_else(|| channel_from_frequency(frequency));
+    }
+}
+
+/// The interface configuration file, keyed by access point hostname (or `*` for any
+/// access point) and then interface name.
+pub type InterfaceConfig = BTreeMap<String, BTreeMap<String, InterfaceInfo>>;
+
+#[derive(Debug, Default, Clone)]
+pub struct Interfaces {
+    configured: InterfaceConfig,
+    learned: BTreeMap<String, BTreeMap<String, InterfaceInfo>>,
+}
+
+impl Interfaces {
+    pub fn configure(&mut self, config: InterfaceConfig) {
+        self.configured = config;
+    }
+
+    pub fn learn_ssid(&mut self, hostname: &str, interface: &str, ssid: String) {
+        self.learned_mut(hostname, interface).ssid.replace(ssid);
+    }
+
+    pub fn learn_frequency(
+        &mut self,
+        hostname: &str,
+        interface: &str,
+        frequency: u32,
+        channel: Option<u16>,
+    ) {
+        self.learned_mut(hostname, interface)
+            .learn_frequency(frequency, channel);
+    }
+
+    /// Returns the metadata for an interface. Configured values take precedence over
+    /// learned ones, and configuration for a specific access point takes precedence
+    /// over the `*` wildcard.
+    pub fn get(&self, hostname: &str, interface: &str) -> Option<InterfaceInfo> {
+        let configured = |host: &str| self.configured.get(host)?.get(interface);
+        let sources = [
+            configured(hostname),
+            configured(ANY_HOST),
+            self.learned.get(hostname).and_then(|l| l.get(interface)),
+        ];
+
+        let mut sources = sources.into_iter().flatten();
+        let info = sources.next()?.clone();
+        let info = sources.fold(info, InterfaceInfo::or);
+
+        if info.band.is_none() {
+            let band = info.frequency.and_then(Band::from_frequency);
+            return Some(InterfaceInfo { band, ..info });
+        }
+        Some(info)
+    }
+
+    /// Whether an access point appears in the configuration, ignoring the `*` wildcard.
+    #[must_use]
+    pub fn knows_ap(&self, hostname: &str) -> bool {
+        hostname != ANY_HOST && self.configured.contains_key(hostname)
+    }
+
+    #[must_use]
+    pub fn knows_interface(&self, interface: &str) -> bool {
+        self.configured
+            .values()
+            .any(|interfaces| interfaces.contains_key(interface))
+    }
+
+    fn learned_mut(&mut self, hostname: &str, interface: &str) -> &mut InterfaceInfo {
+        self.learned
+            .entry(hostname.to_string())
+            .or_default()
+            .entry(interface.to_string())
+            .or_default()
+    }
+}
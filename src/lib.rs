@@ -0,0 +1,79 @@
+//! Tracks the devices associated with hostapd access points by following their logs.
+//!
+//! The pieces can be used on their own:
+//!
+//! - [`parser`] turns a JSON log line from syslog into a [`parser::Record`].
+//! - [`db`] keeps the current state of every device and station in a [`db::Database`], and
+//!   shares it between a writer and any number of readers through [`db::DB`].
+//! - [`ingest`] follows log files and applies what they contain to a [`db::DB`].
+//! - [`storage`] has the traits [`db::DB`] keeps the current state and its history behind,
+//!   with a conformance suite for other implementations behind the `conformance` feature.
+//!   The history is kept in memory with [`history`], or in SQLite with the `sqlite` feature.
+//! - [`presence`] works out from the history when devices were online on a given day.
+//! - [`stats`] works out from the history how busy each access point and station has been.
+//! - [`series`] samples client counts into downsampled time series kept on disk.
+//! - [`sessions`] derives sessions and roams from records as they are applied.
+//!
+//! Everything but the parser and the [`db::Database`] itself is behind the `runtime` feature,
+//! which brings in tokio and linemux. The HTTP API and server are behind the `server`
+//! feature, the watchdog that alerts when events stop arriving is behind the `notifier`
+//! feature, and the [`export`]ers that push events to other stores are behind the `export`
+//! feature, and the SQLite storage is behind the `sqlite` feature. All are enabled by
+//! default, and all of them turn on `runtime`.
+//!
+//! ```no_run
+//! use hostapd_api::{db::Database, parser::{self, Record}};
+//!
+//! let mut db = Database::new();
+//! let line = r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:34:56Z","message":"wlan0: STA 04:17:b6:37:96:dc IEEE 802.11: associated"}"#;
+//! match parser::parse(line) {
+//!     Ok(Some(Record::Station(event))) => db.witness(event),
+//!     Ok(Some(Record::Interface(event))) => db.learn(event),
+//!     Ok(Some(record)) => db.apply(record),
+//!     Ok(None) => {}
+//!     Err(e) => eprintln!("{e}"),
+//! }
+//! ```
+
+#![warn(
+    clippy::all,
+    clippy::pedantic,
+    clippy::unwrap_used,
+    clippy::expect_used
+)]
+
+#[cfg(feature = "server")]
+pub mod api;
+#[cfg(feature = "server")]
+pub mod audit;
+#[cfg(feature = "server")]
+pub mod auth;
+pub mod db;
+mod error;
+#[cfg(feature = "export")]
+pub mod export;
+#[cfg(feature = "runtime")]
+pub mod history;
+#[cfg(feature = "runtime")]
+pub mod ingest;
+pub mod interface;
+pub mod mac;
+pub mod parser;
+#[cfg(feature = "runtime")]
+pub mod presence;
+#[cfg(feature = "runtime")]
+pub mod series;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "runtime")]
+pub mod sessions;
+#[cfg(feature = "runtime")]
+pub mod stats;
+#[cfg(feature = "runtime")]
+pub mod status;
+#[cfg(feature = "runtime")]
+pub mod storage;
+#[cfg(feature = "notifier")]
+pub mod watchdog;
+
+pub use error::Error;
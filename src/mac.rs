@@ -0,0 +1,232 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+#[cfg(feature = "server")]
+use utoipa::{
+    openapi::{schema::Type, ObjectBuilder, RefOr, Schema},
+    PartialSchema, ToSchema,
+};
+
+/// A 48-bit hardware address. It is always displayed in the lowercase, colon separated
+/// form hostapd logs, but parses from all of the common notations:
+///
+/// - `aa:bb:cc:dd:ee:ff`
+/// - `AA-BB-CC-DD-EE-FF`
+/// - `aabb.ccdd.eeff`
+/// - `aabbccddeeff`
+///
+/// Each address uses one separator throughout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid MAC address: {0}")]
+pub struct InvalidMacAddr(String);
+
+/// The first three bytes of a [`MacAddr`], which identify the vendor that assigned it. It
+/// parses from the same notations, cut short: `aa:bb:cc`, `AA-BB-CC`, `aabb.cc` or `aabbcc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Oui(pub [u8; 3]);
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid OUI: {0}")]
+pub struct InvalidOui(String);
+
+impl MacAddr {
+    /// Locally administered addresses are what phones use for MAC randomization.
+    #[must_use]
+    pub fn is_locally_administered(self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    #[must_use]
+    pub fn oui(self) -> Oui {
+        let [x1, x2, x3, ..] = self.0;
+        Oui([x1, x2, x3])
+    }
+}
+
+/// Parses bytes written as groups of hex digits with one separator throughout. `groups`
+/// gives the length of each group for the separator used, or for none.
+fn parse_groups<const N: usize>(
+    s: &str,
+    groups: impl Fn(Option<char>) -> &'static [usize],
+) -> Option<[u8; N]> {
+    // any other separator in the address then fails as a non-hex digit
+    let separator = s.chars().find(|c| matches!(c, ':' | '-' | '.'));
+    let lengths = groups(separator);
+    let groups: Vec<&str> =
+        separator.map_or_else(|| vec![s], |separator| s.split(separator).collect());
+    if groups.len() != lengths.len() {
+        return None;
+    }
+
+    let mut digits = String::with_capacity(N * 2);
+    for (group, &len) in groups.into_iter().zip(lengths) {
+        if group.len() != len || !group.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        digits.push_str(group);
+    }
+    if digits.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl FromStr for MacAddr {
+    type Err = InvalidMacAddr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_groups(s, |separator| match separator {
+            Some(':' | '-') => &[2; 6],
+            Some(_) => &[4; 3],
+            None => &[12],
+        })
+        .map(Self)
+        .ok_or_else(|| InvalidMacAddr(s.to_string()))
+    }
+}
+
+impl FromStr for Oui {
+    type Err = InvalidOui;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_groups(s, |separator| match separator {
+            Some(':' | '-') => &[2; 3],
+            Some(_) => &[4, 2],
+            None => &[6],
+        })
+        .map(Self)
+        .ok_or_else(|| InvalidOui(s.to_string()))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [x1, x2, x3, x4, x5, x6] = self.0;
+        write!(f, "{x1:02x}:{x2:02x}:{x3:02x}:{x4:02x}:{x5:02x}:{x6:02x}")
+    }
+}
+
+impl fmt::Display for Oui {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [x1, x2, x3] = self.0;
+        write!(f, "{x1:02x}:{x2:02x}:{x3:02x}")
+    }
+}
+
+impl Serialize for MacAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(feature = "server")]
+impl PartialSchema for MacAddr {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some("A MAC address in lowercase, colon separated form"))
+            .examples(["04:17:b6:37:96:dc"])
+            .into()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ToSchema for MacAddr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x04, 0x17, 0xb6, 0x37, 0x96, 0xdc]);
+
+    #[test]
+    fn parses_every_notation() {
+        for s in [
+            "04:17:b6:37:96:dc",
+            "04:17:B6:37:96:DC",
+            "04-17-b6-37-96-dc",
+            "04-17-B6-37-96-DC",
+            "0417.b637.96dc",
+            "0417.B637.96DC",
+            "0417b63796dc",
+            "0417B63796DC",
+        ] {
+            assert_eq!(s.parse::<MacAddr>().unwrap(), MAC, "{s}");
+        }
+    }
+
+    #[test]
+    fn displays_lowercase_and_colon_separated() {
+        assert_eq!(MAC.to_string(), "04:17:b6:37:96:dc");
+        assert_eq!(MAC.to_string().parse::<MacAddr>().unwrap(), MAC);
+    }
+
+    #[test]
+    fn rejects_mixed_separators() {
+        for s in [
+            "04:17-b6:37-96:dc",
+            "04-17-b6-37-96:dc",
+            "0417.b637:96dc",
+            "04:17:b6:37.96dc",
+            "0417.b637-96dc",
+        ] {
+            assert!(s.parse::<MacAddr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_ouis_in_every_notation() {
+        for s in [
+            "04:17:b6", "04:17:B6", "04-17-b6", "0417.b6", "0417.B6", "0417b6",
+        ] {
+            assert_eq!(s.parse::<Oui>().unwrap(), MAC.oui(), "{s}");
+        }
+        assert_eq!(MAC.oui().to_string(), "04:17:b6");
+        for s in [
+            "",
+            "04:17",
+            "04:17:b6:37",
+            "04:17-b6",
+            "0417:b6",
+            "04.17.b6",
+            "0417b",
+            "0417b6c",
+        ] {
+            assert!(s.parse::<Oui>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for s in [
+            "",
+            "04:17:b6:37:96",
+            "04:17:b6:37:96:dc:00",
+            "4:17:b6:37:96:dc",
+            "0417:b637:96dc",
+            "04.17.b6.37.96.dc",
+            "0417b63796d",
+            "0417b63796dcd",
+            "04:17:b6:37:96:dg",
+            "+4:17:b6:37:96:dc",
+            "04:17:b6:37:96:dc ",
+        ] {
+            assert!(s.parse::<MacAddr>().is_err(), "{s}");
+        }
+    }
+}
@@ -1,27 +1,49 @@
-use crate::Error;
+use crate::{mac::MacAddr, Error};
 use chrono::{DateTime, Utc};
-use nom::character::complete::{char, one_of, space1};
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{char, one_of, space1, u16 as val_u16};
 use nom::multi::count;
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_until},
-    combinator::map,
-    sequence::terminated,
+    combinator::{map, rest},
+    sequence::{delimited, preceded, terminated},
 };
 use nom::{Finish, IResult};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize)]
+/// A record from the log, or an admin change. It is serialized with its `type`: `station`,
+/// `interface` or `admin`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Station(Event),
+    Interface(InterfaceEvent),
+    Admin(AdminEvent),
+}
+
+impl Record {
+    #[must_use]
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Record::Station(event) => event.timestamp,
+            Record::Interface(event) => event.timestamp,
+            Record::Admin(event) => event.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub timestamp: DateTime<Utc>,
     pub hostname: String,
     pub interface: String,
-    pub mac: String,
+    pub mac: MacAddr,
     #[serde(flatten)]
     pub action: Action,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action")]
 pub enum Action {
     #[serde(rename = "associated")]
@@ -32,6 +54,84 @@ pub enum Action {
 
     #[serde(rename = "observed")]
     Observed,
+
+    #[serde(rename = "accounting_started")]
+    AccountingStarted { session_id: String },
+
+    #[serde(rename = "identified")]
+    Identified { identity: String },
+
+    #[serde(rename = "vlan_assigned")]
+    VlanAssigned { vlan: u16 },
+
+    #[serde(rename = "vlan_bound")]
+    VlanBound { vlan_interface: String },
+}
+
+impl Action {
+    /// The name the action is serialized with.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Associated => "associated",
+            Action::Disassociated => "disassociated",
+            Action::Observed => "observed",
+            Action::AccountingStarted { .. } => "accounting_started",
+            Action::Identified { .. } => "identified",
+            Action::VlanAssigned { .. } => "vlan_assigned",
+            Action::VlanBound { .. } => "vlan_bound",
+        }
+    }
+}
+
+/// Something hostapd told us about one of its interfaces, usually at startup or after
+/// a channel switch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceEvent {
+    pub timestamp: DateTime<Utc>,
+    pub hostname: String,
+    pub interface: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u16>,
+}
+
+/// A change made through the admin API. It is kept in the history along with the logged
+/// records, so that replaying the history makes it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// Removes a device.
+    Forget { mac: MacAddr },
+    /// Moves everything known about `from` into `into`.
+    Merge { from: MacAddr, into: MacAddr },
+    /// Marks a device as disassociated from every station.
+    ForceOffline { mac: MacAddr },
+    /// Marks every device on an access point as disassociated from it.
+    ClearAp { ap: String },
+}
+
+impl Change {
+    /// The name the change is serialized with.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Change::Forget { .. } => "forget",
+            Change::Merge { .. } => "merge",
+            Change::ForceOffline { .. } => "force_offline",
+            Change::ClearAp { .. } => "clear_ap",
+        }
+    }
 }
 
 /// This matches the syslog-ng format
@@ -44,7 +144,15 @@ struct Log {
     message: String,
 }
 
-pub fn parse(input: &str) -> Result<Option<Event>, Error> {
+/// Parses a JSON log line as written by syslog, with `host`, `program`, `timestamp` and
+/// `message` keys. Lines from programs other than hostapd, and hostapd messages that don't
+/// describe a station or an interface, are `Ok(None)`.
+///
+/// # Errors
+///
+/// Returns an error if the line isn't JSON in the expected shape, or a hostapd message
+/// about a station can't be parsed.
+pub fn parse(input: &str) -> Result<Option<Record>, Error> {
     let log: Log = serde_json::from_str(input)?;
 
     // for now, only parse hostapd logs
@@ -54,15 +162,27 @@ pub fn parse(input: &str) -> Result<Option<Event>, Error> {
     }
 
     match parse_message(&log.message).finish() {
-        Ok((_, (interface, mac, Some(action)))) => Ok(Some(Event {
+        Ok((_, (interface, mac, Some(action)))) => Ok(Some(Record::Station(Event {
             timestamp: log.timestamp,
             hostname: log.host,
             interface,
             mac,
             action,
-        })),
+        }))),
         Ok((_, (_, _, None))) => Ok(None),
-        Err(e) => Err(Error::Parse(e.to_string())),
+        Err(e) => match parse_interface_message(&log.message).finish() {
+            Ok((_, (interface, ssid, frequency, channel))) => {
+                Ok(Some(Record::Interface(InterfaceEvent {
+                    timestamp: log.timestamp,
+                    hostname: log.host,
+                    interface,
+                    ssid,
+                    frequency,
+                    channel,
+                })))
+            }
+            Err(_) => Err(Error::Parse(e.to_string())),
+        },
     }
 }
 
@@ -71,8 +191,11 @@ pub fn parse(input: &str) -> Result<Option<Event>, Error> {
 // wl1.1: STA 32:42:fd:88:86:0c WPA: pairwise key handshake completed (RSN)
 // eth10: STA 04:17:b6:37:96:dc WPA: group key handshake completed (RSN)
 // eth10: STA 04:17:b6:37:96:dc RADIUS: starting accounting session 5F3F4F6F-00000000
+// eth10: STA 04:17:b6:37:96:dc IEEE 802.1X: STA identity 'alice@example.com'
+// wlan0: STA 04:17:b6:37:96:dc RADIUS: VLAN ID 20
+// wlan0: STA 04:17:b6:37:96:dc IEEE 802.11: binding station to interface 'wlan0.20'
 
-fn parse_message(input: &str) -> IResult<&str, (String, String, Option<Action>)> {
+fn parse_message(input: &str) -> IResult<&str, (String, MacAddr, Option<Action>)> {
     let (input, interface) = terminated(take_until(": "), tag(": "))(input)?;
     let (input, _) = tag("STA ")(input)?;
     let (input, mac) = terminated(val_macaddr, space1)(input)?;
@@ -87,12 +210,106 @@ fn parse_message(input: &str) -> IResult<&str, (String, String, Option<Action>)>
         map(tag("WPA: group key handshake completed (RSN)"), |_| {
             Some(Action::Observed)
         }),
-        map(tag("RADIUS: starting accounting session"), |_| None),
+        map(
+            preceded(tag("RADIUS: starting accounting session "), rest),
+            |session_id: &str| {
+                Some(Action::AccountingStarted {
+                    session_id: session_id.trim().to_string(),
+                })
+            },
+        ),
+        map(
+            delimited(
+                tag("IEEE 802.1X: STA identity '"),
+                take_until("'"),
+                tag("'"),
+            ),
+            |identity: &str| {
+                Some(Action::Identified {
+                    identity: identity.to_string(),
+                })
+            },
+        ),
+        map(
+            preceded(
+                alt((tag("RADIUS: VLAN ID "), tag("IEEE 802.1X: VLAN ID "))),
+                val_u16,
+            ),
+            |vlan| Some(Action::VlanAssigned { vlan }),
+        ),
+        map(
+            delimited(
+                tag("IEEE 802.11: binding station to interface '"),
+                take_until("'"),
+                tag("'"),
+            ),
+            |vlan_interface: &str| {
+                Some(Action::VlanBound {
+                    vlan_interface: vlan_interface.to_string(),
+                })
+            },
+        ),
     ))(input)?;
 
     Ok((input, (interface.to_string(), mac, action)))
 }
 
+// Using interface wlan0 with hwaddr 04:17:b6:37:96:dc and ssid "home"
+// wlan0: ACS-COMPLETED freq=5180 channel=36
+// wlan0: AP-CSA-FINISHED freq=5500 dfs=1
+// wlan0: CTRL-EVENT-CHANNEL-SWITCH freq=5180 ht_enabled=1 ch_offset=1 ch_width=80 MHz cf1=5210 cf2=0
+
+type InterfaceMessage = (String, Option<String>, Option<u32>, Option<u16>);
+
+fn parse_interface_message(input: &str) -> IResult<&str, InterfaceMessage> {
+    alt((parse_interface_ssid, parse_interface_frequency))(input)
+}
+
+fn parse_interface_ssid(input: &str) -> IResult<&str, InterfaceMessage> {
+    let (input, interface) = delimited(
+        tag("Using interface "),
+        take_until(" "),
+        tag(" with hwaddr "),
+    )(input)?;
+    let (input, _) = val_macaddr(input)?;
+    let (input, ssid) = delimited(tag(" and ssid \""), take_until("\""), tag("\""))(input)?;
+
+    Ok((
+        input,
+        (interface.to_string(), Some(ssid.to_string()), None, None),
+    ))
+}
+
+fn parse_interface_frequency(input: &str) -> IResult<&str, InterfaceMessage> {
+    let (input, interface) = terminated(take_until(": "), tag(": "))(input)?;
+    let (input, _) = terminated(
+        take_while1(|c: char| c.is_ascii_uppercase() || c == '-'),
+        space1,
+    )(input)?;
+    let (input, params) = rest(input)?;
+
+    let param = |key: &str| {
+        params
+            .split_whitespace()
+            .filter_map(|kv| kv.split_once('='))
+            .find_map(|(k, v)| (k == key).then_some(v))
+    };
+    let Some(frequency) = param("freq").and_then(|f| f.parse().ok()) else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    };
+    let channel = param("channel")
+        .or_else(|| param("chan"))
+        .and_then(|c| c.parse().ok());
+
+    Ok((
+        input,
+        (interface.to_string(), None, Some(frequency), channel),
+    ))
+}
+
 const HEX: &str = "0123456789abcdefABCDEF";
 
 fn val_hexbyte(input: &str) -> IResult<&str, u8> {
@@ -110,7 +327,7 @@ fn val_hexbyte(input: &str) -> IResult<&str, u8> {
     )
 }
 
-fn val_macaddr(input: &str) -> IResult<&str, String> {
+fn val_macaddr(input: &str) -> IResult<&str, MacAddr> {
     let (input, x1) = val_hexbyte(input)?;
     let (input, _) = char(':')(input)?;
     let (input, x2) = val_hexbyte(input)?;
@@ -123,7 +340,97 @@ fn val_macaddr(input: &str) -> IResult<&str, String> {
     let (input, _) = char(':')(input)?;
     let (input, x6) = val_hexbyte(input)?;
 
-    let mac = format!("{x1:02x}:{x2:02x}:{x3:02x}:{x4:02x}:{x5:02x}:{x6:02x}");
+    Ok((input, MacAddr([x1, x2, x3, x4, x5, x6])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log line with a hostapd message logged by `garage-ap`.
+    fn line(message: &str) -> String {
+        serde_json::json!({
+            "host": "garage-ap",
+            "program": "hostapd",
+            "timestamp": "2024-01-02T12:00:00Z",
+            "message": message,
+        })
+        .to_string()
+    }
 
-    Ok((input, mac))
+    fn event(message: &str) -> Event {
+        match parse(&line(message)) {
+            Ok(Some(Record::Station(event))) => event,
+            other => panic!("{message} parsed into {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_accounting_sessions() {
+        for message in [
+            "eth10: STA 04:17:b6:37:96:dc RADIUS: starting accounting session 5F3F4F6F-00000000",
+            "eth10: STA 04:17:b6:37:96:dc RADIUS: starting accounting session 5F3F4F6F-00000000 ",
+        ] {
+            let event = event(message);
+            assert_eq!(event.interface, "eth10");
+            assert_eq!(event.mac.to_string(), "04:17:b6:37:96:dc");
+            assert!(
+                matches!(
+                    event.action,
+                    Action::AccountingStarted { ref session_id } if session_id == "5F3F4F6F-00000000"
+                ),
+                "{message}: {:?}",
+                event.action
+            );
+        }
+    }
+
+    #[test]
+    fn parses_identities() {
+        for (message, expected) in [
+            (
+                "eth10: STA 04:17:b6:37:96:dc IEEE 802.1X: STA identity 'alice@example.com'",
+                "alice@example.com",
+            ),
+            (
+                r"wl1.1: STA 32:42:fd:88:86:0c IEEE 802.1X: STA identity 'EXAMPLE\bob smith'",
+                r"EXAMPLE\bob smith",
+            ),
+        ] {
+            let event = event(message);
+            assert!(
+                matches!(event.action, Action::Identified { ref identity } if identity == expected),
+                "{message}: {:?}",
+                event.action
+            );
+        }
+    }
+
+    #[test]
+    fn records_are_tagged_with_their_type() {
+        let station = Record::Station(event(
+            "wlan0: STA 04:17:b6:37:96:dc IEEE 802.1X: STA identity 'alice@example.com'",
+        ));
+        let admin = Record::Admin(AdminEvent {
+            timestamp: station.timestamp(),
+            change: Change::ClearAp {
+                ap: "garage-ap".to_string(),
+            },
+        });
+        for (record, kind) in [(station, "station"), (admin, "admin")] {
+            let json = serde_json::to_string(&record).unwrap();
+            assert!(
+                json.starts_with(&format!(r#"{{"type":"{kind}","#)),
+                "{json}"
+            );
+            let parsed: Record = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn rejects_unterminated_identities() {
+        let message = "eth10: STA 04:17:b6:37:96:dc IEEE 802.1X: STA identity 'alice";
+        assert!(parse(&line(message)).is_err());
+    }
 }
@@ -0,0 +1,245 @@
+use crate::{db::Device, mac::MacAddr, parser::Record, storage::Replay, Error};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+/// A stretch of time a device was associated with at least one access point. Roaming
+/// between access points doesn't interrupt it.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Interval {
+    pub start: DateTime<Utc>,
+    /// `None` while the device is still online.
+    pub end: Option<DateTime<Utc>>,
+    /// The access points the device was associated with during the interval.
+    pub access_points: BTreeSet<String>,
+}
+
+impl Interval {
+    fn duration(&self, now: DateTime<Utc>) -> Duration {
+        self.end.unwrap_or(now) - self.start
+    }
+
+    /// The part of the interval within `[start, end)`, if any.
+    fn clip(&self, start: DateTime<Utc>, end: DateTime<Utc>, now: DateTime<Utc>) -> Option<Self> {
+        let clipped = Self {
+            start: self.start.max(start),
+            // still online, and the day isn't over yet
+            end: match self.end {
+                None if now < end => None,
+                other => Some(other.unwrap_or(end).min(end)),
+            },
+            access_points: self.access_points.clone(),
+        };
+        (clipped.end.unwrap_or(now) > clipped.start).then_some(clipped)
+    }
+}
+
+/// A summary of a device's, or an owner's, presence on a day.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Summary {
+    /// When the device was first seen or online that day.
+    pub first_seen: Option<DateTime<Utc>>,
+    /// When the device was last seen or online that day.
+    pub last_seen: Option<DateTime<Utc>>,
+    /// The total time online that day. Overlapping intervals are only counted once.
+    pub online_seconds: i64,
+    /// Every access point the device was seen on or associated with that day.
+    pub access_points: BTreeSet<String>,
+}
+
+/// The presence of one device on a day.
+#[derive(Debug, Clone)]
+pub struct DeviceDay {
+    pub mac: MacAddr,
+    /// The EAP identity the device last authenticated with.
+    pub identity: Option<String>,
+    pub summary: Summary,
+    /// The times the device was online, clipped to the day.
+    pub intervals: Vec<Interval>,
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    open: Option<Interval>,
+    closed: Vec<Interval>,
+    first_event: Option<DateTime<Utc>>,
+    last_event: Option<DateTime<Utc>>,
+    access_points: BTreeSet<String>,
+}
+
+fn access_points(device: &Device) -> impl Iterator<Item = String> + '_ {
+    device.stations.iter().map(|s| s.hostname.clone())
+}
+
+/// The start and end of a day, in UTC.
+#[must_use]
+pub fn day_bounds(day: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start = day.and_time(NaiveTime::MIN).and_utc();
+    (start, start + Duration::days(1))
+}
+
+/// Works out the presence of every device on `day` (in UTC) by replaying the history up to
+/// the end of the day, as given by [`day_bounds`], so that a device is online exactly when
+/// [`crate::db::Database::witness`] considers it online. Devices that weren't seen or
+/// online that day are left out.
+///
+/// # Errors
+///
+/// Returns an error if the history couldn't be read.
+pub fn day(
+    history: Replay,
+    day: NaiveDate,
+    now: DateTime<Utc>,
+) -> Result<BTreeMap<MacAddr, DeviceDay>, Error> {
+    let (start, end) = day_bounds(day);
+    if start > now {
+        return Ok(BTreeMap::new());
+    }
+
+    let mut trackers: BTreeMap<MacAddr, Tracker> = BTreeMap::new();
+    // devices that were already online when the history starts
+    for (mac, device) in history.base().devices() {
+        if let Some(since) = device
+            .last_associated
+            .filter(|_| !device.stations.is_empty())
+        {
+            trackers.entry(*mac).or_default().open = Some(Interval {
+                start: since,
+                end: None,
+                access_points: access_points(device).collect(),
+            });
+        }
+    }
+
+    let db = history.run(&mut |record, db| {
+        let Record::Station(event) = record else {
+            return;
+        };
+        let Some(device) = db.devices().get(&event.mac) else {
+            return;
+        };
+        let tracker = trackers.entry(event.mac).or_default();
+        if (start..end).contains(&event.timestamp) {
+            let timestamp = event.timestamp;
+            tracker.first_event = Some(tracker.first_event.map_or(timestamp, |t| t.min(timestamp)));
+            tracker.last_event = Some(tracker.last_event.map_or(timestamp, |t| t.max(timestamp)));
+            tracker.access_points.insert(event.hostname.clone());
+        }
+        match (tracker.open.as_mut(), device.stations.is_empty()) {
+            (None, false) => {
+                tracker.open = Some(Interval {
+                    start: event.timestamp,
+                    end: None,
+                    access_points: access_points(device).collect(),
+                });
+            }
+            (Some(open), false) => open.access_points.extend(access_points(device)),
+            (Some(_), true) => {
+                if let Some(mut closed) = tracker.open.take() {
+                    closed.end = Some(event.timestamp);
+                    // intervals that ended before the day are of no interest
+                    if event.timestamp > start {
+                        tracker.closed.push(closed);
+                    }
+                }
+            }
+            (None, true) => {}
+        }
+    })?;
+
+    Ok(trackers
+        .into_iter()
+        .filter_map(|(mac, tracker)| {
+            let intervals: Vec<Interval> = tracker
+                .closed
+                .iter()
+                .chain(&tracker.open)
+                .filter_map(|interval| interval.clip(start, end, now))
+                .collect();
+            if intervals.is_empty() && tracker.first_event.is_none() {
+                return None;
+            }
+            let summary = summarize(
+                &intervals,
+                tracker.first_event,
+                tracker.last_event,
+                tracker.access_points,
+                now,
+            );
+            let identity = db.devices().get(&mac).and_then(|d| d.identity.clone());
+            Some((
+                mac,
+                DeviceDay {
+                    mac,
+                    identity,
+                    summary,
+                    intervals,
+                },
+            ))
+        })
+        .collect())
+}
+
+/// Combines the presence of several devices, e.g. those of one owner, into one timeline
+/// where the owner is online while any of their devices is.
+#[must_use]
+pub fn combine<'a>(
+    days: impl IntoIterator<Item = &'a DeviceDay>,
+    now: DateTime<Utc>,
+) -> (Summary, Vec<Interval>) {
+    let mut intervals: Vec<Interval> = Vec::new();
+    let (mut first_event, mut last_event, mut seen) = (None, None, BTreeSet::new());
+    for day in days {
+        intervals.extend(day.intervals.iter().cloned());
+        first_event = [first_event, day.summary.first_seen]
+            .into_iter()
+            .flatten()
+            .min();
+        last_event = last_event.max(day.summary.last_seen);
+        seen.extend(day.summary.access_points.iter().cloned());
+    }
+
+    intervals.sort_by_key(|interval| interval.start);
+    let mut merged: Vec<Interval> = Vec::new();
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if last.end.map_or(true, |end| interval.start <= end) => {
+                last.end = last.end.zip(interval.end).map(|(a, b)| a.max(b));
+                last.access_points.extend(interval.access_points);
+            }
+            _ => merged.push(interval),
+        }
+    }
+
+    let summary = summarize(&merged, first_event, last_event, seen, now);
+    (summary, merged)
+}
+
+fn summarize(
+    intervals: &[Interval],
+    first_event: Option<DateTime<Utc>>,
+    last_event: Option<DateTime<Utc>>,
+    mut access_points: BTreeSet<String>,
+    now: DateTime<Utc>,
+) -> Summary {
+    let first_online = intervals.iter().map(|interval| interval.start).min();
+    let last_online = intervals
+        .iter()
+        .map(|interval| interval.end.unwrap_or(now))
+        .max();
+    let mut online = Duration::zero();
+    for interval in intervals {
+        online = online + interval.duration(now);
+        access_points.extend(interval.access_points.iter().cloned());
+    }
+    Summary {
+        first_seen: [first_event, first_online].into_iter().flatten().min(),
+        last_seen: [last_event, last_online].into_iter().flatten().max(),
+        online_seconds: online.num_seconds(),
+        access_points,
+    }
+}
@@ -0,0 +1,501 @@
+use crate::{storage::DeviceStore, Error};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+pub type SharedStore = Arc<Mutex<Store>>;
+
+/// The tiers kept when none are configured: one minute for a day and one hour for a year.
+pub const DEFAULT_TIERS: &str = "1m:1d,1h:365d";
+
+/// The longest duration [`parse_duration`] accepts, in seconds, so that it can be taken
+/// from any moment without leaving the range of dates.
+const MAX_DURATION_SECONDS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parses a duration such as `30s`, `5m`, `1h`, `7d`, `2w` or `1y` (365 days), of up to a
+/// hundred years.
+///
+/// # Errors
+///
+/// Returns an error if the number or the unit is missing or invalid, or the duration is
+/// zero or too long.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::Series(format!("invalid duration: {s}"));
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let n: i64 = s[..split].parse().map_err(|_| invalid())?;
+    let unit: i64 = match &s[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let seconds = n
+        .checked_mul(unit)
+        .filter(|seconds| *seconds <= MAX_DURATION_SECONDS)
+        .ok_or_else(|| Error::Series(format!("duration too long: {s}")))?;
+    if seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+/// A resolution the samples are downsampled to, and how long it is kept, written as
+/// `STEP:RETENTION`, e.g. `1m:1d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tier {
+    pub step: Duration,
+    pub retention: Duration,
+}
+
+impl FromStr for Tier {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (step, retention) = s
+            .split_once(':')
+            .ok_or_else(|| Error::Series(format!("expected STEP:RETENTION, got {s}")))?;
+        let tier = Self {
+            step: parse_duration(step)?,
+            retention: parse_duration(retention)?,
+        };
+        if tier.retention < tier.step {
+            return Err(Error::Series(format!("{s} keeps less than one step")));
+        }
+        Ok(tier)
+    }
+}
+
+/// What a series counts the clients of: a station, and the SSID it had when sampled.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Key {
+    pub access_point: String,
+    pub interface: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+}
+
+/// Which series a query covers. Every field that is set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub access_point: Option<String>,
+    pub interface: Option<String>,
+    pub ssid: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, key: &Key) -> bool {
+        self.access_point
+            .as_ref()
+            .map_or(true, |ap| *ap == key.access_point)
+            && self
+                .interface
+                .as_ref()
+                .map_or(true, |interface| *interface == key.interface)
+            && self
+                .ssid
+                .as_ref()
+                .map_or(true, |ssid| key.ssid.as_ref() == Some(ssid))
+    }
+}
+
+/// The samples that fell into one step of a tier.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Bucket {
+    min: u32,
+    max: u32,
+    sum: u64,
+    samples: u32,
+}
+
+impl Bucket {
+    fn new(clients: u32) -> Self {
+        Self {
+            min: clients,
+            max: clients,
+            sum: clients.into(),
+            samples: 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.samples += other.samples;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn mean(&self) -> f64 {
+        self.sum as f64 / f64::from(self.samples.max(1))
+    }
+}
+
+/// A line of a tier's file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    key: Key,
+    #[serde(flatten)]
+    bucket: Bucket,
+}
+
+/// One tier, with its buckets in memory and on disk.
+#[derive(Debug)]
+struct Level {
+    tier: Tier,
+    path: PathBuf,
+    buckets: BTreeMap<Key, BTreeMap<DateTime<Utc>, Bucket>>,
+    /// The bucket of each series that is still filling up, and not yet written.
+    open: BTreeMap<Key, DateTime<Utc>>,
+    /// The lines in the file, some of which may have been superseded or expired.
+    lines: usize,
+}
+
+impl Level {
+    fn load(dir: &Path, tier: Tier) -> Result<Self, Error> {
+        let path = dir.join(format!("clients-{}s.jsonl", tier.step.num_seconds()));
+        let mut level = Self {
+            tier,
+            path,
+            buckets: BTreeMap::new(),
+            open: BTreeMap::new(),
+            lines: 0,
+        };
+        let file = match File::open(&level.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(level),
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            level.lines += 1;
+            // a bucket written again after a restart supersedes the earlier line
+            match serde_json::from_str::<Line>(&line?) {
+                Ok(line) => {
+                    level
+                        .buckets
+                        .entry(line.key)
+                        .or_default()
+                        .insert(line.time, line.bucket);
+                }
+                Err(e) => tracing::warn!("{}:{}: {e}", level.path.display(), level.lines),
+            }
+        }
+        Ok(level)
+    }
+
+    fn bucket_start(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        at.duration_trunc(self.tier.step).unwrap_or(at)
+    }
+
+    fn record(&mut self, at: DateTime<Utc>, key: &Key, clients: u32) -> Result<(), Error> {
+        let start = self.bucket_start(at);
+        if let Some(open) = self.open.insert(key.clone(), start) {
+            if open != start {
+                self.write(&[(key.clone(), open)])?;
+            }
+        }
+        let sample = Bucket::new(clients);
+        self.buckets
+            .entry(key.clone())
+            .or_default()
+            .entry(start)
+            .and_modify(|bucket| bucket.merge(&sample))
+            .or_insert(sample);
+        Ok(())
+    }
+
+    /// Appends buckets to the file.
+    fn write(&mut self, buckets: &[(Key, DateTime<Utc>)]) -> Result<(), Error> {
+        let mut out = Vec::new();
+        for (key, time) in buckets {
+            let Some(bucket) = self.buckets.get(key).and_then(|b| b.get(time)) else {
+                continue;
+            };
+            serde_json::to_writer(
+                &mut out,
+                &Line {
+                    time: *time,
+                    key: key.clone(),
+                    bucket: *bucket,
+                },
+            )?;
+            out.push(b'\n');
+            self.lines += 1;
+        }
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&out)?;
+        Ok(())
+    }
+
+    /// Writes the open buckets that ended by `now`, or all of them if `now` is `None`.
+    fn close(&mut self, now: Option<DateTime<Utc>>) -> Result<(), Error> {
+        let step = self.tier.step;
+        let closed: Vec<(Key, DateTime<Utc>)> = self
+            .open
+            .iter()
+            .filter(|(_, start)| now.map_or(true, |now| **start + step <= now))
+            .map(|(key, start)| (key.clone(), *start))
+            .collect();
+        for (key, _) in &closed {
+            self.open.remove(key);
+        }
+        if closed.is_empty() {
+            return Ok(());
+        }
+        self.write(&closed)
+    }
+
+    /// Drops the buckets older than the retention, and rewrites the file once most of its
+    /// lines are stale. Returns how many buckets were dropped.
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let horizon = now - self.tier.retention;
+        let mut pruned = 0;
+        self.buckets.retain(|_, buckets| {
+            let before = buckets.len();
+            buckets.retain(|time, _| *time >= horizon);
+            pruned += before - buckets.len();
+            !buckets.is_empty()
+        });
+
+        let kept: usize = self.buckets.values().map(BTreeMap::len).sum();
+        if self.lines > 2 * kept.max(1) {
+            self.compact()?;
+        }
+        Ok(pruned)
+    }
+
+    /// Rewrites the file with only the current buckets, through a temporary file so that a
+    /// crash can't lose it.
+    fn compact(&mut self) -> Result<(), Error> {
+        let temp = self.path.with_extension("jsonl.tmp");
+        let (mut out, mut lines) = (Vec::new(), 0);
+        for (key, buckets) in &self.buckets {
+            for (time, bucket) in buckets {
+                if self.open.get(key) == Some(time) {
+                    continue;
+                }
+                serde_json::to_writer(
+                    &mut out,
+                    &Line {
+                        time: *time,
+                        key: key.clone(),
+                        bucket: *bucket,
+                    },
+                )?;
+                out.push(b'\n');
+                lines += 1;
+            }
+        }
+        let mut file = File::create(&temp)?;
+        file.write_all(&out)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        self.lines = lines;
+        Ok(())
+    }
+}
+
+/// A point of a queried series.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Point {
+    /// The start of the step.
+    pub time: DateTime<Utc>,
+    /// The average number of clients during the step.
+    pub clients: f64,
+    /// The fewest clients sampled during the step. Across several stations, this is the sum
+    /// of their minimums.
+    pub min: u32,
+    /// The most clients sampled during the step. Across several stations, this is the sum of
+    /// their maximums, so it can overstate the peak.
+    pub max: u32,
+}
+
+/// Client counts sampled per station and downsampled into tiers, each kept in memory and in
+/// a JSON lines file in a directory. Buckets are appended to the file as they fill up, and
+/// the file is rewritten once most of it has expired.
+#[derive(Debug)]
+pub struct Store {
+    /// Ordered from the finest step to the coarsest.
+    levels: Vec<Level>,
+}
+
+impl Store {
+    /// Opens the store in `dir`, creating it if needed, and loads the tiers kept there.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no tiers are given, two have the same step, or the directory or
+    /// its files can't be read.
+    pub fn open(dir: &Path, tiers: &[Tier]) -> Result<Self, Error> {
+        if tiers.is_empty() {
+            return Err(Error::Series("no tiers configured".to_string()));
+        }
+        let mut tiers = tiers.to_vec();
+        tiers.sort_by_key(|tier| tier.step);
+        if tiers.windows(2).any(|pair| pair[0].step == pair[1].step) {
+            return Err(Error::Series("two tiers have the same step".to_string()));
+        }
+        fs::create_dir_all(dir)?;
+        let levels = tiers
+            .into_iter()
+            .map(|tier| Level::load(dir, tier))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { levels })
+    }
+
+    /// Records the number of clients on every station of `db` at `at`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a finished bucket can't be written.
+    pub fn sample(&mut self, db: &dyn DeviceStore, at: DateTime<Utc>) -> Result<(), Error> {
+        for (station, clients) in db.station_clients()? {
+            let key = Key {
+                ssid: db.interface_info(&station).and_then(|info| info.ssid),
+                access_point: station.hostname,
+                interface: station.interface,
+            };
+            let clients = u32::try_from(clients).unwrap_or(u32::MAX);
+            for level in &mut self.levels {
+                level.record(at, &key, clients)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the buckets that ended by `now` and drops those past their tier's retention.
+    /// Returns how many were dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file can't be written.
+    pub fn prune(&mut self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let mut pruned = 0;
+        for level in &mut self.levels {
+            level.close(Some(now))?;
+            pruned += level.prune(now)?;
+        }
+        Ok(pruned)
+    }
+
+    /// Writes every bucket that is still filling up, e.g. before shutting down. Samples
+    /// recorded into them later are merged in and written again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file can't be written.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for level in &mut self.levels {
+            level.close(None)?;
+        }
+        Ok(())
+    }
+
+    /// The finest tier kept as far back as `from`, or the coarsest if none are.
+    fn level_for(&self, from: DateTime<Utc>, now: DateTime<Utc>) -> Option<&Level> {
+        self.levels
+            .iter()
+            .find(|level| now - level.tier.retention <= from)
+            .or_else(|| self.levels.last())
+    }
+
+    /// The clients on the matching stations in `[from, to)`, in steps of `step`, from the
+    /// finest tier that goes back to `from`. `step` is rounded up to a multiple of the tier's
+    /// step. Returns the step used and the points, leaving out steps without samples.
+    #[must_use]
+    pub fn query(
+        &self,
+        filter: &Filter,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step: Option<Duration>,
+        now: DateTime<Utc>,
+    ) -> (Duration, Vec<Point>) {
+        let Some(level) = self.level_for(from, now).filter(|_| from < to) else {
+            return (step.unwrap_or_else(Duration::zero), Vec::new());
+        };
+        let tier_step = level.tier.step.num_seconds();
+        let step = step.map_or(tier_step, |step| {
+            let n = step.num_seconds().max(1);
+            (n + tier_step - 1) / tier_step * tier_step
+        });
+        let step = Duration::seconds(step);
+        let start = from.duration_trunc(step).unwrap_or(from);
+
+        let mut points: BTreeMap<DateTime<Utc>, Point> = BTreeMap::new();
+        let series = level.buckets.iter().filter(|(key, _)| filter.matches(key));
+        for (_, buckets) in series {
+            let mut steps: BTreeMap<DateTime<Utc>, Bucket> = BTreeMap::new();
+            for (time, bucket) in buckets.range(start..to) {
+                let start = time.duration_trunc(step).unwrap_or(*time);
+                steps
+                    .entry(start)
+                    .and_modify(|b| b.merge(bucket))
+                    .or_insert(*bucket);
+            }
+            for (time, bucket) in steps {
+                let point = points.entry(time).or_insert(Point {
+                    time,
+                    clients: 0.0,
+                    min: 0,
+                    max: 0,
+                });
+                point.clients += bucket.mean();
+                point.min += bucket.min;
+                point.max += bucket.max;
+            }
+        }
+        (step, points.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::seconds(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::minutes(5));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_duration("1y").unwrap(), Duration::days(365));
+        assert_eq!(parse_duration("100y").unwrap(), Duration::days(36500));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in ["", "5", "m", "-5m", "0s", "5x", "1.5h"] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_durations_too_long_to_represent() {
+        for s in [
+            "101y",
+            "9223372036854775807d",
+            "25269512338830y",
+            "99999999999999999999s",
+        ] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+}
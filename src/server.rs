@@ -0,0 +1,491 @@
+use crate::Error;
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::{
+    fmt,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+    time::interval,
+};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// How often the certificate files are checked for changes.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long accepting waits after the first error that isn't about a single connection,
+/// such as running out of file descriptors. The wait doubles with every further error, up
+/// to [`MAX_ACCEPT_BACKOFF`].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// When set, clients must present a certificate signed by one of these CAs.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn files(&self) -> impl Iterator<Item = &Path> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// The modification times of the certificate files, used to notice when they are
+    /// renewed.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|file| file.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn load(&self) -> Result<Arc<ServerConfig>, Error> {
+        let certs = read_certs(&self.cert)?;
+        let key = read_key(&self.key)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca {
+            Some(ref client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| Error::Tls(format!("{}: {e}", client_ca.display())))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| Error::Tls(e.to_string()))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Arc::new(config))
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    use rustls_pemfile::Item;
+
+    rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::Tls(format!("no private key in {}", path.display())))
+}
+
+/// An address to accept HTTP connections on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A UNIX domain socket, written as `unix:/path/to/socket`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// The sockets passed in by systemd socket activation (`LISTEN_FDS`).
+    #[cfg(unix)]
+    Systemd,
+}
+
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if s == "systemd" {
+            return Ok(ListenAddr::Systemd);
+        }
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|e| Error::Listen(format!("{s}: {e}")))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            ListenAddr::Systemd => write!(f, "systemd"),
+        }
+    }
+}
+
+/// A bound socket that accepts connections.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    /// A UNIX domain socket, and the path it was bound to, which is removed when the
+    /// listener stops. Sockets passed in by systemd have no path, since systemd owns them.
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// A connection accepted from any kind of [`Listener`], before or after the TLS handshake.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
+impl Listener {
+    /// Binds the address. This may result in several listeners for systemd socket activation,
+    /// which are taken from `systemd`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if binding fails, or systemd didn't pass any sockets.
+    pub async fn bind(
+        addr: &ListenAddr,
+        #[cfg_attr(not(unix), allow(unused_variables))] systemd: &mut SystemdSockets,
+    ) -> Result<Vec<Self>, Error> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(vec![Listener::Tcp(TcpListener::bind(addr).await?)]),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // a socket left behind by a previous run would make bind fail
+                if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(vec![Listener::Unix(
+                    UnixListener::bind(path)?,
+                    Some(path.clone()),
+                )])
+            }
+            #[cfg(unix)]
+            ListenAddr::Systemd => systemd.listeners(),
+        }
+    }
+
+    /// Accepts a connection, returning it along with a description of the peer for logging.
+    async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = match listener.local_addr()?.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_string(),
+                };
+                Ok((Box::new(stream), peer))
+            }
+        }
+    }
+}
+
+/// The sockets systemd passed to this process by socket activation, following
+/// `sd_listen_fds(3)`. They may be TCP or UNIX stream sockets.
+#[derive(Debug)]
+pub struct SystemdSockets {
+    #[cfg(unix)]
+    fds: Result<Vec<std::os::fd::OwnedFd>, &'static str>,
+}
+
+impl SystemdSockets {
+    /// Takes ownership of the sockets. Like `sd_listen_fds(1)` with `unset_environment`, this
+    /// removes the variables describing them, so child processes don't take them for their
+    /// own, and marks them close-on-exec, so they aren't inherited at all.
+    ///
+    /// Changing the environment while another thread reads it is undefined behaviour, so this
+    /// has to be called before any threads are started, i.e. before the tokio runtime.
+    #[must_use]
+    pub fn take() -> Self {
+        Self {
+            #[cfg(unix)]
+            fds: take_systemd_fds(),
+        }
+    }
+
+    /// Listeners for the sockets. They can only be listened on once, so later calls fail.
+    #[cfg(unix)]
+    fn listeners(&mut self) -> Result<Vec<Listener>, Error> {
+        use std::os::fd::OwnedFd;
+
+        let fds = match self.fds {
+            Ok(ref mut fds) if !fds.is_empty() => std::mem::take(fds),
+            Ok(_) => {
+                return Err(Error::Listen(
+                    "the systemd sockets are already in use".into(),
+                ))
+            }
+            Err(e) => return Err(Error::Listen(e.into())),
+        };
+        fds.into_iter()
+            .map(|fd| {
+                let tcp = std::net::TcpListener::from(fd);
+                // getsockname only succeeds as a TCP address for TCP sockets
+                if tcp.local_addr().is_ok() {
+                    tcp.set_nonblocking(true)?;
+                    return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+                }
+                let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+                unix.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(unix)?, None))
+            })
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+fn take_systemd_fds() -> Result<Vec<std::os::fd::OwnedFd>, &'static str> {
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    fn var<T: FromStr>(name: &str) -> Option<T> {
+        std::env::var(name).ok()?.parse().ok()
+    }
+
+    let pid = var("LISTEN_PID");
+    let count: RawFd = var("LISTEN_FDS").unwrap_or_default();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if pid != Some(std::process::id()) {
+        return Err("no sockets were passed by systemd (LISTEN_PID is not set to this process)");
+    }
+    if count <= 0 {
+        return Err("no sockets were passed by systemd");
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            set_cloexec(fd).map_err(|_| "a socket passed by systemd isn't open")?;
+            // SAFETY: systemd hands these descriptors to this process, and nothing else in it
+            // uses them
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// Marks a descriptor close-on-exec, which systemd doesn't do for the sockets it passes.
+#[cfg(unix)]
+fn set_cloexec(fd: std::os::fd::RawFd) -> std::io::Result<()> {
+    // SAFETY: fcntl with F_GETFD and F_SETFD only reads and sets the descriptor's flags
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Serves the router on every listener until `shutdown` is cancelled, terminating TLS if it
+/// is configured.
+///
+/// # Errors
+///
+/// Returns an error if the TLS certificate or key can't be loaded.
+pub async fn serve(
+    listeners: Vec<Listener>,
+    router: Router,
+    tls: Option<TlsConfig>,
+    shutdown: CancellationToken,
+) -> Result<(), Error> {
+    let connections = TaskTracker::new();
+    let acceptor = match tls {
+        Some(tls) => {
+            let (acceptor, acceptor_rx) = watch::channel(TlsAcceptor::from(tls.load()?));
+            connections.spawn(reload_loop(tls, acceptor, shutdown.clone()));
+            Some(acceptor_rx)
+        }
+        None => None,
+    };
+
+    for listener in listeners {
+        connections.spawn(accept_loop(
+            listener,
+            router.clone(),
+            acceptor.clone(),
+            connections.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    connections.close();
+    connections.wait().await;
+
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: Listener,
+    router: Router,
+    acceptor: Option<watch::Receiver<TlsAcceptor>>,
+    connections: TaskTracker,
+    shutdown: CancellationToken,
+) {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                // the peer went away before it was accepted, which says nothing about
+                // the listener
+                Err(e) if is_connection_error(&e) => {
+                    tracing::debug!("error accepting connection: {}", e);
+                    continue;
+                }
+                // e.g. out of file descriptors: accepting again right away would fail
+                // the same way, so give connections time to close
+                Err(e) => {
+                    tracing::error!("error accepting connection, retrying in {backoff:?}: {}", e);
+                    tokio::select! {
+                        () = tokio::time::sleep(backoff) => {},
+                        () = shutdown.cancelled() => break,
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            },
+            () = shutdown.cancelled() => break,
+        };
+        let acceptor = acceptor.as_ref().map(|acceptor| acceptor.borrow().clone());
+        connections.spawn(serve_connection(
+            stream,
+            peer,
+            router.clone(),
+            acceptor,
+            shutdown.clone(),
+        ));
+    }
+
+    #[cfg(unix)]
+    if let Listener::Unix(_, Some(path)) = listener {
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::warn!("error removing {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+async fn serve_connection(
+    stream: Box<dyn Connection>,
+    peer: String,
+    router: Router,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
+) {
+    let stream: Box<dyn Connection> = match acceptor {
+        Some(acceptor) => {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Box::new(stream),
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {peer} failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {peer} timed out");
+                    return;
+                }
+            }
+        }
+        None => stream,
+    };
+
+    let service = TowerToHyperService::new(router);
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        () = shutdown.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        tracing::debug!("error serving {peer}: {e}");
+    }
+}
+
+/// Reloads the certificate and key whenever they change on disk, e.g. after an ACME
+/// renewal. New connections use the new certificate; existing ones are unaffected.
+async fn reload_loop(
+    tls: TlsConfig,
+    acceptor: watch::Sender<TlsAcceptor>,
+    shutdown: CancellationToken,
+) {
+    let mut modified = tls.modified();
+    let mut ticker = interval(TLS_RELOAD_INTERVAL);
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => break,
+            _ = ticker.tick() => {
+                let now = tls.modified();
+                if now == modified {
+                    continue;
+                }
+                match tls.load() {
+                    Ok(config) => {
+                        tracing::info!("reloaded TLS certificate from {}", tls.cert.display());
+                        acceptor.send_replace(TlsAcceptor::from(config));
+                        modified = now;
+                    }
+                    // the files may be mid-update, so try again next time
+                    Err(e) => tracing::warn!("error reloading TLS certificate: {}", e),
+                }
+            }
+        }
+    }
+}
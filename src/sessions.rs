@@ -0,0 +1,282 @@
+use crate::{
+    db::{Applied, Database, Station},
+    mac::MacAddr,
+    parser::{Change, Record},
+    stats::ROAM_WINDOW_SECONDS,
+    storage::{quietly, Position},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver};
+use tokio_util::sync::CancellationToken;
+
+/// A stretch of time a device was associated with one station.
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub mac: MacAddr,
+    /// The EAP identity the device last authenticated with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    pub access_point: String,
+    pub interface: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub duration_seconds: i64,
+}
+
+/// A device moving from one station to another, either directly or within
+/// [`ROAM_WINDOW_SECONDS`] of leaving the first.
+#[derive(Debug, Clone, Serialize)]
+pub struct Roam {
+    pub mac: MacAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    pub from_access_point: String,
+    pub from_interface: String,
+    pub to_access_point: String,
+    pub to_interface: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Something worked out from the records rather than logged by hostapd.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Derived {
+    Session(Session),
+    Roam(Roam),
+}
+
+impl Derived {
+    /// When it ended or happened.
+    #[must_use]
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Derived::Session(session) => session.end,
+            Derived::Roam(roam) => roam.at,
+        }
+    }
+
+    /// The station it ended on, or moved to.
+    #[must_use]
+    pub fn station(&self) -> Station {
+        match self {
+            Derived::Session(session) => Station {
+                hostname: session.access_point.clone(),
+                interface: session.interface.clone(),
+            },
+            Derived::Roam(roam) => Station {
+                hostname: roam.to_access_point.clone(),
+                interface: roam.to_interface.clone(),
+            },
+        }
+    }
+}
+
+/// What a record changed for a device.
+#[derive(Debug, Clone)]
+pub struct Moved {
+    pub mac: MacAddr,
+    /// The stations it was on before the record.
+    pub was_on: BTreeSet<Station>,
+    /// The stations it is on after the record.
+    pub now_on: BTreeSet<Station>,
+    /// The sessions it ended and the roams it made. Admin changes end neither.
+    pub derived: Vec<Derived>,
+}
+
+/// Derives sessions and roams from records as they are applied, by following the stations
+/// each device is on in the database they are applied to, so that a device is on a station
+/// exactly when [`Database::witness`] considers it on.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    /// The stations each device is on.
+    on: BTreeMap<MacAddr, BTreeSet<Station>>,
+    /// When each device joined each of the stations it is on.
+    started: BTreeMap<(MacAddr, Station), DateTime<Utc>>,
+    /// The station each device last left, and when.
+    last_left: BTreeMap<MacAddr, (Station, DateTime<Utc>)>,
+}
+
+impl Sessions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the devices that are on a station in `base`, as having joined it when they
+    /// last associated.
+    pub fn start_from(&mut self, base: &Database) {
+        for (mac, device) in base.devices() {
+            if device.stations.is_empty() {
+                continue;
+            }
+            if let Some(since) = device.last_associated {
+                for station in &device.stations {
+                    self.started.insert((*mac, station.clone()), since);
+                }
+            }
+            self.on.insert(*mac, device.stations.clone());
+        }
+    }
+
+    /// Follows a record that has just been applied to `db`, and returns what it changed
+    /// for the devices it moved.
+    pub fn follow(&mut self, record: &Record, db: &Database) -> Vec<Moved> {
+        let macs = match record {
+            Record::Station(event) => vec![event.mac],
+            Record::Interface(_) => Vec::new(),
+            Record::Admin(event) => match event.change {
+                Change::Forget { mac } | Change::ForceOffline { mac } => vec![mac],
+                Change::Merge { from, into } => vec![from, into],
+                Change::ClearAp { ref ap } => self
+                    .on
+                    .iter()
+                    .filter(|(_, stations)| stations.iter().any(|s| &s.hostname == ap))
+                    .map(|(mac, _)| *mac)
+                    .collect(),
+            },
+        };
+        let station_event = matches!(record, Record::Station(_));
+        macs.into_iter()
+            .filter_map(|mac| self.moved(mac, record.timestamp(), station_event, db))
+            .collect()
+    }
+
+    fn moved(
+        &mut self,
+        mac: MacAddr,
+        t: DateTime<Utc>,
+        station_event: bool,
+        db: &Database,
+    ) -> Option<Moved> {
+        let device = db.devices().get(&mac);
+        let now_on = device.map(|d| d.stations.clone()).unwrap_or_default();
+        let was_on = if now_on.is_empty() {
+            self.on.remove(&mac)
+        } else {
+            self.on.insert(mac, now_on.clone())
+        }
+        .unwrap_or_default();
+        if was_on == now_on {
+            return None;
+        }
+        let identity = device.and_then(|d| d.identity.clone());
+
+        let mut derived = Vec::new();
+        for left in was_on.difference(&now_on) {
+            let start = self.started.remove(&(mac, left.clone()));
+            if !station_event {
+                continue;
+            }
+            if let Some(start) = start {
+                derived.push(Derived::Session(Session {
+                    mac,
+                    identity: identity.clone(),
+                    access_point: left.hostname.clone(),
+                    interface: left.interface.clone(),
+                    start,
+                    end: t,
+                    duration_seconds: (t - start).num_seconds(),
+                }));
+            }
+            self.last_left.insert(mac, (left.clone(), t));
+        }
+        for joined in now_on.difference(&was_on) {
+            self.started.insert((mac, joined.clone()), t);
+            if !station_event {
+                continue;
+            }
+            // still on another station, or only just left one
+            let from = was_on.iter().next().cloned().or_else(|| {
+                self.last_left
+                    .get(&mac)
+                    .filter(|(s, left)| {
+                        s != joined && (t - *left).num_seconds() <= ROAM_WINDOW_SECONDS
+                    })
+                    .map(|(s, _)| s.clone())
+            });
+            if let Some(from) = from {
+                derived.push(Derived::Roam(Roam {
+                    mac,
+                    identity: identity.clone(),
+                    from_access_point: from.hostname,
+                    from_interface: from.interface,
+                    to_access_point: joined.hostname.clone(),
+                    to_interface: joined.interface.clone(),
+                    at: t,
+                }));
+            }
+        }
+        Some(Moved {
+            mac,
+            was_on,
+            now_on,
+            derived,
+        })
+    }
+}
+
+/// A record passed on by [`track_loop`], with the sessions it ended and the roams it made.
+#[derive(Debug, Clone)]
+pub struct Tracked {
+    pub record: Record,
+    /// Where in the log it was read from, if it was.
+    pub position: Option<Position>,
+    pub derived: Vec<Derived>,
+}
+
+/// How many batches may wait for a slow subscriber to [`track_loop`] before it misses some.
+pub const TRACKED_CAPACITY: usize = 1024;
+
+/// Follows the batches received from `applied`, which should be subscribed to the database
+/// before ingestion starts, and passes them on to `tracked` with the sessions and roams
+/// they make, until shutdown. The records are applied to one copy of the database however
+/// many subscribers there are.
+pub async fn track_loop(
+    mut applied: Receiver<Applied>,
+    tracked: broadcast::Sender<Arc<Vec<Tracked>>>,
+    shutdown: CancellationToken,
+) {
+    let mut db = Database::default();
+    let mut sessions = Sessions::new();
+    loop {
+        let records = tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            received = applied.recv() => match received {
+                Ok(records) => records,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("the session tracker fell behind and missed {missed} batches of events");
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    break;
+                }
+            },
+        };
+        let batch = records
+            .iter()
+            .map(|(record, position)| {
+                // the live database has logged it already
+                quietly(|| db.apply(record.clone()));
+                let derived = sessions
+                    .follow(record, &db)
+                    .into_iter()
+                    .flat_map(|moved| moved.derived)
+                    .collect();
+                Tracked {
+                    record: record.clone(),
+                    position: position.clone(),
+                    derived,
+                }
+            })
+            .collect();
+        // the subscribers may have gone away since
+        let _ = tracked.send(Arc::new(batch));
+    }
+}
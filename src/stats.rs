@@ -0,0 +1,283 @@
+use crate::{
+    db::{Database, Station},
+    mac::MacAddr,
+    parser::Record,
+    sessions::{Derived, Sessions},
+    storage::{DeviceStore, Replay},
+    Error,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+/// How soon after leaving one station a device must join another for it to count as a
+/// roam rather than a new session.
+pub const ROAM_WINDOW_SECONDS: i64 = 60;
+
+/// Activity on an access point or a station over a window of time.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Activity {
+    /// The number of devices associated now.
+    pub clients: usize,
+    /// The most devices associated at once during the window.
+    pub peak_clients: usize,
+    /// When the peak was first reached.
+    pub peak_at: Option<DateTime<Utc>>,
+    /// The number of different devices seen during the window.
+    pub unique_devices: usize,
+    pub associations: usize,
+    pub disassociations: usize,
+    pub associations_per_hour: f64,
+    pub disassociations_per_hour: f64,
+    /// The average length of the sessions that ended during the window.
+    pub average_session_seconds: Option<f64>,
+    /// Devices that moved here from another access point (or station).
+    pub roams_in: usize,
+    /// Devices that moved from here to another access point (or station).
+    pub roams_out: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    clients: usize,
+    peak: Option<(usize, DateTime<Utc>)>,
+    devices: BTreeSet<MacAddr>,
+    associations: usize,
+    disassociations: usize,
+    sessions: usize,
+    session_time_ms: i64,
+    roams_in: usize,
+    roams_out: usize,
+}
+
+impl Counter {
+    fn peak(&mut self, at: DateTime<Utc>) {
+        if self.peak.map_or(true, |(peak, _)| self.clients > peak) {
+            self.peak = Some((self.clients, at));
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn activity(&self, clients: usize, window: Duration) -> Activity {
+        let hours = window.num_seconds().max(1) as f64 / 3600.0;
+        let (peak_clients, peak_at) = self.peak.map_or((0, None), |(n, at)| (n, Some(at)));
+        Activity {
+            clients,
+            peak_clients,
+            peak_at,
+            unique_devices: self.devices.len(),
+            associations: self.associations,
+            disassociations: self.disassociations,
+            associations_per_hour: self.associations as f64 / hours,
+            disassociations_per_hour: self.disassociations as f64 / hours,
+            average_session_seconds: (self.sessions > 0)
+                .then(|| self.session_time_ms as f64 / 1000.0 / self.sessions as f64),
+            roams_in: self.roams_in,
+            roams_out: self.roams_out,
+        }
+    }
+}
+
+/// Activity per access point and per station.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub access_points: BTreeMap<String, Activity>,
+    pub stations: BTreeMap<Station, Activity>,
+}
+
+/// The counters for an access point and for one of its stations, which are always
+/// updated together.
+#[derive(Default)]
+struct Counters {
+    access_points: BTreeMap<String, Counter>,
+    stations: BTreeMap<Station, Counter>,
+}
+
+impl Counters {
+    /// Applies `f` to the station's counter, and to its access point's counter if
+    /// `access_point` is true.
+    fn update(&mut self, station: &Station, access_point: bool, mut f: impl FnMut(&mut Counter)) {
+        f(self.stations.entry(station.clone()).or_default());
+        if access_point {
+            f(self
+                .access_points
+                .entry(station.hostname.clone())
+                .or_default());
+        }
+    }
+}
+
+/// Works out the activity of every access point and station during the `window` before
+/// `now` by replaying the history up to `now`. The current client counts come from `live`.
+///
+/// # Errors
+///
+/// Returns an error if the history couldn't be read.
+pub fn stats(
+    history: Replay,
+    live: &dyn DeviceStore,
+    now: DateTime<Utc>,
+    window: Duration,
+) -> Result<Stats, Error> {
+    let mut tracker = Tracker::new(now - window);
+    tracker.start_from(history.base());
+    history.run(&mut |record, db| tracker.follow(record, db))?;
+    tracker.finish(live, window)
+}
+
+struct Tracker {
+    start: DateTime<Utc>,
+    started: bool,
+    counters: Counters,
+    sessions: Sessions,
+}
+
+impl Tracker {
+    fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            started: false,
+            counters: Counters::default(),
+            sessions: Sessions::new(),
+        }
+    }
+
+    /// Takes the devices that are already online when the replay starts.
+    fn start_from(&mut self, base: &Database) {
+        for device in base.devices().values() {
+            for station in &device.stations {
+                self.counters.update(station, true, |c| c.clients += 1);
+            }
+        }
+        self.sessions.start_from(base);
+    }
+
+    /// Counts what changed with a record, given the database after it was applied. Only
+    /// events count as activity, but admin changes move clients too.
+    fn follow(&mut self, record: &Record, db: &Database) {
+        let t = record.timestamp();
+        let in_window = t >= self.start;
+        if in_window {
+            self.open_window();
+        }
+        let counted = in_window && matches!(record, Record::Station(_));
+
+        if let Record::Station(event) = record {
+            if counted {
+                let station = Station {
+                    hostname: event.hostname.clone(),
+                    interface: event.interface.clone(),
+                };
+                self.counters.update(&station, true, |c| {
+                    c.devices.insert(event.mac);
+                });
+            }
+        }
+
+        for moved in self.sessions.follow(record, db) {
+            let (was_on, now_on) = (&moved.was_on, &moved.now_on);
+            // leaving one station of an access point for another isn't leaving the access point
+            let left_ap = |left: &Station| !now_on.iter().any(|s| s.hostname == left.hostname);
+            for left in was_on.difference(now_on) {
+                self.counters.update(left, left_ap(left), |c| {
+                    c.clients = c.clients.saturating_sub(1);
+                    if counted {
+                        c.disassociations += 1;
+                    }
+                });
+            }
+            for joined in now_on.difference(was_on) {
+                let joined_ap = !was_on.iter().any(|s| s.hostname == joined.hostname);
+                self.counters.update(joined, joined_ap, |c| {
+                    c.clients += 1;
+                    if counted {
+                        c.associations += 1;
+                    }
+                    if in_window {
+                        c.peak(t);
+                    }
+                });
+            }
+            if !counted {
+                continue;
+            }
+            for derived in moved.derived {
+                match derived {
+                    Derived::Session(session) => {
+                        let left = Station {
+                            hostname: session.access_point,
+                            interface: session.interface,
+                        };
+                        self.counters.update(&left, left_ap(&left), |c| {
+                            c.sessions += 1;
+                            c.session_time_ms += (session.end - session.start).num_milliseconds();
+                        });
+                    }
+                    Derived::Roam(roam) => {
+                        let from = Station {
+                            hostname: roam.from_access_point,
+                            interface: roam.from_interface,
+                        };
+                        let to = Station {
+                            hostname: roam.to_access_point,
+                            interface: roam.to_interface,
+                        };
+                        let between_aps = from.hostname != to.hostname;
+                        self.counters.update(&to, between_aps, |c| c.roams_in += 1);
+                        self.counters
+                            .update(&from, between_aps, |c| c.roams_out += 1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the counts as the window opens as the first candidates for the peak.
+    fn open_window(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let start = self.start;
+        let counters = &mut self.counters;
+        for counter in counters
+            .stations
+            .values_mut()
+            .chain(counters.access_points.values_mut())
+        {
+            counter.peak(start);
+        }
+    }
+
+    fn finish(mut self, live: &dyn DeviceStore, window: Duration) -> Result<Stats, Error> {
+        // no events during the window
+        self.open_window();
+        let mut stats = Stats::default();
+        for (station, clients) in live.station_clients()? {
+            let counter = self.counters.stations.remove(&station).unwrap_or_default();
+            self.counters
+                .access_points
+                .entry(station.hostname.clone())
+                .or_default();
+            stats
+                .stations
+                .insert(station, counter.activity(clients, window));
+        }
+        // stations that are only in the history
+        for (station, counter) in self.counters.stations {
+            stats.stations.insert(station, counter.activity(0, window));
+        }
+
+        let ap_clients = live.access_point_clients()?;
+        for (ap, counter) in self.counters.access_points {
+            let clients = ap_clients.get(&ap).copied().unwrap_or_default();
+            stats
+                .access_points
+                .insert(ap, counter.activity(clients, window));
+        }
+        Ok(stats)
+    }
+}
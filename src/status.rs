@@ -0,0 +1,370 @@
+use crate::{db::Pruned, ingest::QUEUE_CAPACITY};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+pub type SharedStatus = Arc<Status>;
+
+/// The state of the ingestion and watchdog tasks, reported by `/status` and used to decide
+/// readiness.
+#[derive(Debug)]
+pub struct Status {
+    started: DateTime<Utc>,
+    /// How old the last event may be before the service is considered stale.
+    pub max_event_age: Duration,
+    /// Whether the service is only ready while the events are fresh.
+    pub require_fresh: bool,
+    sources: Vec<Source>,
+    tailing: AtomicBool,
+    watchdog: Option<AtomicBool>,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+    queue_depth: AtomicU64,
+    batches: AtomicU64,
+    records_applied: AtomicU64,
+    parse_errors: AtomicU64,
+    held_back: AtomicU64,
+    held_back_micros: AtomicU64,
+    /// Woken once the logs have been replayed.
+    replay_done: Notify,
+}
+
+/// A log file being read.
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    /// The size of the file at startup, which has to be read before the replay is finished.
+    initial_size: u64,
+    offset: AtomicU64,
+    lines: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+impl Status {
+    #[must_use]
+    pub fn new(max_event_age: Duration, watchdog: bool) -> Self {
+        Self {
+            started: Utc::now(),
+            max_event_age,
+            require_fresh: false,
+            sources: Vec::new(),
+            tailing: AtomicBool::new(false),
+            watchdog: watchdog.then(|| AtomicBool::new(false)),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            records_applied: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            held_back: AtomicU64::new(0),
+            held_back_micros: AtomicU64::new(0),
+            replay_done: Notify::new(),
+        }
+    }
+
+    /// Registers a file that is read from the start. `path` should be the canonical path
+    /// linemux reports for each line.
+    pub fn add_source(&mut self, path: PathBuf) {
+        let initial_size = path.metadata().map(|m| m.len()).unwrap_or_default();
+        self.sources.push(Source {
+            path,
+            initial_size,
+            offset: AtomicU64::new(0),
+            lines: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+        });
+    }
+
+    fn source(&self, path: &Path) -> Option<&Source> {
+        self.sources.iter().find(|source| source.path == path)
+    }
+
+    /// Records a line read from `path`, `length` bytes long with its line break, and
+    /// whether it failed to parse.
+    pub fn line_read(&self, path: &Path, length: u64, parse_error: bool) {
+        if parse_error {
+            self.parse_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(source) = self.source(path) {
+            source.offset.fetch_add(length, Ordering::Relaxed);
+            source.lines.fetch_add(1, Ordering::Relaxed);
+            if parse_error {
+                source.parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // the last line may not be a record, so nothing may be applied after it
+        self.wake_if_replayed();
+    }
+
+    pub fn set_tailing(&self, tailing: bool) {
+        self.tailing.store(tailing, Ordering::Relaxed);
+    }
+
+    pub fn set_watchdog_fired(&self, fired: bool) {
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.store(fired, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a parsed record about to be queued for the database.
+    pub fn queued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that reading waited this long for room in the queue.
+    pub fn held_back(&self, waited: std::time::Duration) {
+        self.held_back.fetch_add(1, Ordering::Relaxed);
+        self.held_back_micros.fetch_add(
+            u64::try_from(waited.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Records a batch of queued records applied to the database.
+    pub fn applied(&self, records: usize) {
+        let records = records as u64;
+        self.queue_depth.fetch_sub(records, Ordering::Relaxed);
+        self.records_applied.fetch_add(records, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.wake_if_replayed();
+    }
+
+    pub fn record_pruned(&self, pruned: Pruned) {
+        self.expired
+            .fetch_add(pruned.expired as u64, Ordering::Relaxed);
+        self.evicted
+            .fetch_add(pruned.evicted as u64, Ordering::Relaxed);
+    }
+
+    /// Whether every source has been read up to where it ended at startup, and everything
+    /// read has been applied to the database.
+    pub fn replayed(&self) -> bool {
+        self.sources
+            .iter()
+            .all(|source| source.offset.load(Ordering::Relaxed) >= source.initial_size)
+            && self.queue_depth.load(Ordering::Relaxed) == 0
+    }
+
+    /// Waits until the logs have been replayed, as soon as they are, even if nothing is
+    /// read after them.
+    pub async fn until_replayed(&self) {
+        while !self.replayed() {
+            self.replay_done.notified().await;
+        }
+    }
+
+    fn wake_if_replayed(&self) {
+        if self.replayed() {
+            self.replay_done.notify_one();
+        }
+    }
+
+    fn fresh(&self, last_event: Option<DateTime<Utc>>) -> bool {
+        last_event.is_some_and(|t| Utc::now() - t <= self.max_event_age)
+    }
+
+    pub fn readiness(&self, last_event: Option<DateTime<Utc>>) -> Readiness {
+        let replayed = self.replayed();
+        let tailing = self.tailing.load(Ordering::Relaxed);
+        let fresh = self.fresh(last_event);
+        Readiness {
+            ready: replayed && tailing && (fresh || !self.require_fresh),
+            replayed,
+            tailing,
+            fresh,
+        }
+    }
+
+    pub fn report(&self, last_event: Option<DateTime<Utc>>) -> StatusReport {
+        let now = Utc::now();
+        StatusReport {
+            version: env!("CARGO_PKG_VERSION"),
+            started: self.started,
+            uptime_seconds: (now - self.started).num_seconds(),
+            sources: self
+                .sources
+                .iter()
+                .map(|source| SourceReport {
+                    path: source.path.display().to_string(),
+                    offset: source.offset.load(Ordering::Relaxed),
+                    lines: source.lines.load(Ordering::Relaxed),
+                    parse_errors: source.parse_errors.load(Ordering::Relaxed),
+                })
+                .collect(),
+            readiness: self.readiness(last_event),
+            last_event,
+            watchdog: match self.watchdog {
+                Some(ref fired) if fired.load(Ordering::Relaxed) => WatchdogState::Fired,
+                Some(_) => WatchdogState::Armed,
+                None => WatchdogState::Disabled,
+            },
+            ingest: IngestReport {
+                queue_depth: self.queue_depth.load(Ordering::Relaxed),
+                queue_capacity: QUEUE_CAPACITY,
+                batches: self.batches.load(Ordering::Relaxed),
+                records_applied: self.records_applied.load(Ordering::Relaxed),
+                parse_errors: self.parse_errors.load(Ordering::Relaxed),
+                held_back: self.held_back.load(Ordering::Relaxed),
+                held_back_seconds: std::time::Duration::from_micros(
+                    self.held_back_micros.load(Ordering::Relaxed),
+                )
+                .as_secs_f64(),
+            },
+            pruned: PrunedReport {
+                expired: self.expired.load(Ordering::Relaxed),
+                evicted: self.evicted.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+// a response body, where a flag per check is clearer than an enum
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Readiness {
+    /// Whether the service is ready: the logs have been replayed and are being followed,
+    /// and with `--require-fresh`, the events are fresh.
+    pub ready: bool,
+    /// The log files have been read up to where they ended at startup.
+    pub replayed: bool,
+    /// The log files are still being followed.
+    pub tailing: bool,
+    /// The last event is at most the maximum event age old. This is only required to be
+    /// ready with `--require-fresh`, since a quiet network can go hours without an event.
+    pub fresh: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogState {
+    /// No watchdog URL is configured.
+    Disabled,
+    /// Events are arriving and no alert has been sent.
+    Armed,
+    /// An alert has been sent and events haven't resumed since.
+    Fired,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct SourceReport {
+    path: String,
+    /// Bytes read since startup.
+    offset: u64,
+    /// Lines read since startup.
+    lines: u64,
+    /// Lines that couldn't be parsed.
+    parse_errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct StatusReport {
+    version: &'static str,
+    started: DateTime<Utc>,
+    uptime_seconds: i64,
+    sources: Vec<SourceReport>,
+    readiness: Readiness,
+    last_event: Option<DateTime<Utc>>,
+    watchdog: WatchdogState,
+    ingest: IngestReport,
+    pruned: PrunedReport,
+}
+
+/// The queue between reading the log and applying it to the database.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct IngestReport {
+    /// Records read but not yet applied.
+    queue_depth: u64,
+    queue_capacity: usize,
+    /// How many times the database was written to. Each write applies everything queued.
+    batches: u64,
+    records_applied: u64,
+    /// Lines that couldn't be parsed, from every file, which are skipped.
+    parse_errors: u64,
+    /// How many times reading had to wait because the queue was full.
+    held_back: u64,
+    /// The total time reading spent waiting for room in the queue.
+    held_back_seconds: f64,
+}
+
+/// How many devices the retention policy has forgotten since startup.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct PrunedReport {
+    /// Offline devices not seen within `--retention-days`.
+    expired: u64,
+    /// Offline devices forgotten to stay within `--max-devices`.
+    evicted: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_networks_stay_ready() {
+        let status = Status::new(Duration::minutes(30), false);
+        status.set_tailing(true);
+
+        let readiness = status.readiness(Some(Utc::now() - Duration::hours(6)));
+        assert!(readiness.ready && !readiness.fresh, "{readiness:?}");
+        let readiness = status.readiness(None);
+        assert!(readiness.ready && !readiness.fresh, "{readiness:?}");
+        let readiness = status.readiness(Some(Utc::now()));
+        assert!(readiness.ready && readiness.fresh, "{readiness:?}");
+
+        status.set_tailing(false);
+        assert!(!status.readiness(Some(Utc::now())).ready);
+    }
+
+    #[tokio::test]
+    async fn wakes_once_replayed_without_a_record() {
+        let path =
+            std::env::temp_dir().join(format!("hostapd-api-replayed-{}.log", std::process::id()));
+        std::fs::write(&path, "not json\n").unwrap();
+        let mut status = Status::new(Duration::minutes(30), false);
+        status.add_source(path.clone());
+        let _ = std::fs::remove_file(&path);
+        let status = Arc::new(status);
+
+        let waiting = tokio::spawn({
+            let status = status.clone();
+            async move { status.until_replayed().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        status.line_read(&path, 9, true);
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn stale_events_are_not_ready_when_required_fresh() {
+        let mut status = Status::new(Duration::minutes(30), false);
+        status.require_fresh = true;
+        status.set_tailing(true);
+
+        assert!(
+            !status
+                .readiness(Some(Utc::now() - Duration::hours(6)))
+                .ready
+        );
+        assert!(!status.readiness(None).ready);
+        assert!(status.readiness(Some(Utc::now())).ready);
+    }
+}
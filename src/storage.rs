@@ -0,0 +1,325 @@
+//! Where the state of the devices and the history of applied records are kept.
+//!
+//! [`DeviceStoreMut`] is what ingestion applies records to, [`DeviceStore`] is what the API
+//! queries, and [`Storage`] keeps the history that the state at an earlier moment is
+//! rebuilt from.
+//! [`Database`] and [`History`](crate::history::History) keep them in memory, and are the
+//! defaults. With the `sqlite` feature, [`sqlite::SqliteStorage`] keeps the history in an
+//! SQLite database instead, so that it outlives the logs it was read from, and
+//! [`sqlite::SqliteDeviceStore`] keeps the devices in the same database.
+//!
+//! Other implementations, such as test doubles, can be checked against the in-memory ones
+//! with the `conformance` suite, behind the `conformance` feature.
+
+use crate::{
+    db::{Database, DeviceListItem, DeviceQuery, Pruned, Retention, Station},
+    interface::{InterfaceConfig, InterfaceInfo},
+    mac::MacAddr,
+    parser::{AdminEvent, Record},
+    Error,
+};
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Answers questions about the current state of every device and station.
+///
+/// [`DB`](crate::db::DB) publishes one of these as a snapshot for readers after every
+/// write, and a database rebuilt from the history answers them the same way.
+pub trait DeviceStore: Send + Sync + Debug {
+    /// The timestamp of the newest station event applied.
+    fn last_event_timestamp(&self) -> Option<DateTime<Utc>>;
+
+    fn knows_ap(&self, ap: &str) -> bool;
+
+    fn knows_interface(&self, interface: &str) -> bool;
+
+    fn knows_station(&self, ap: &str, interface: &str) -> bool;
+
+    fn interface_info(&self, station: &Station) -> Option<InterfaceInfo>;
+
+    /// # Errors
+    ///
+    /// Returns an error if the device couldn't be read.
+    fn get(&self, mac: MacAddr) -> Result<Option<DeviceListItem>, Error>;
+
+    /// The devices matching `query`, along with the number of devices that matched it
+    /// before `limit` and `offset` were applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be read.
+    fn device_page(&self, query: &DeviceQuery) -> Result<(usize, Vec<DeviceListItem>), Error>;
+
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be read.
+    fn device_list(&self, query: &DeviceQuery) -> Result<Vec<DeviceListItem>, Error> {
+        self.device_page(query).map(|(_, devices)| devices)
+    }
+
+    /// The number of devices currently associated with every station that has been seen,
+    /// including those without any devices.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be counted.
+    fn station_clients(&self) -> Result<BTreeMap<Station, usize>, Error>;
+
+    /// The number of devices currently associated with every access point that has been
+    /// seen, including those without any devices. A device on several of an access point's
+    /// interfaces counts once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be counted.
+    fn access_point_clients(&self) -> Result<BTreeMap<String, usize>, Error>;
+}
+
+/// The [`DeviceStore`] that ingestion applies records to.
+///
+/// [`DB`](crate::db::DB) keeps one for writers, and publishes a
+/// [`DeviceStoreMut::snapshot`] of it for readers after every write.
+pub trait DeviceStoreMut: DeviceStore {
+    /// A copy to publish to readers, which later writes don't change.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the writes so far couldn't be made visible to readers.
+    fn snapshot(&mut self) -> Result<Arc<dyn DeviceStore>, Error>;
+
+    /// Applies a record from the log, or an admin change.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record couldn't be applied.
+    fn apply(&mut self, record: Record) -> Result<(), Error>;
+
+    /// Makes an admin change. Returns how many devices it changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the change couldn't be made.
+    fn change(&mut self, event: AdminEvent) -> Result<usize, Error>;
+
+    fn configure_interfaces(&mut self, config: InterfaceConfig);
+
+    fn configure_retention(&mut self, retention: Retention);
+
+    /// Drops offline devices according to the retention policy.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the devices couldn't be dropped.
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<Pruned, Error>;
+}
+
+impl DeviceStore for Database {
+    fn last_event_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.last_event_timestamp
+    }
+
+    fn knows_ap(&self, ap: &str) -> bool {
+        Database::knows_ap(self, ap)
+    }
+
+    fn knows_interface(&self, interface: &str) -> bool {
+        Database::knows_interface(self, interface)
+    }
+
+    fn knows_station(&self, ap: &str, interface: &str) -> bool {
+        Database::knows_station(self, ap, interface)
+    }
+
+    fn interface_info(&self, station: &Station) -> Option<InterfaceInfo> {
+        Database::interface_info(self, station)
+    }
+
+    fn get(&self, mac: MacAddr) -> Result<Option<DeviceListItem>, Error> {
+        Ok(Database::get(self, mac))
+    }
+
+    fn device_page(&self, query: &DeviceQuery) -> Result<(usize, Vec<DeviceListItem>), Error> {
+        Ok(Database::device_page(self, query))
+    }
+
+    fn station_clients(&self) -> Result<BTreeMap<Station, usize>, Error> {
+        Ok(Database::station_clients(self))
+    }
+
+    fn access_point_clients(&self) -> Result<BTreeMap<String, usize>, Error> {
+        Ok(Database::access_point_clients(self))
+    }
+}
+
+impl DeviceStoreMut for Database {
+    fn snapshot(&mut self) -> Result<Arc<dyn DeviceStore>, Error> {
+        Ok(Arc::new(self.clone()))
+    }
+
+    fn apply(&mut self, record: Record) -> Result<(), Error> {
+        Database::apply(self, record);
+        Ok(())
+    }
+
+    fn change(&mut self, event: AdminEvent) -> Result<usize, Error> {
+        Ok(Database::change(self, event))
+    }
+
+    fn configure_interfaces(&mut self, config: InterfaceConfig) {
+        Database::configure_interfaces(self, config);
+    }
+
+    fn configure_retention(&mut self, retention: Retention) {
+        Database::configure_retention(self, retention);
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<Pruned, Error> {
+        Ok(Database::prune(self, now))
+    }
+}
+
+/// Where in the log a record was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The path of the file.
+    pub file: Arc<str>,
+    /// Tells the file apart from the earlier files at the same path, e.g. before it was
+    /// rotated.
+    pub file_id: u64,
+    /// The offset of the start of the line in the file.
+    pub offset: u64,
+}
+
+/// A history of applied records, which the database can be rebuilt from by replaying them
+/// through [`Database::apply`], just as ingestion applied them.
+pub trait Storage: Send + Sync + Debug {
+    /// Keeps a batch of records, in the order they were applied, along with where they
+    /// were read from if they were read from the log. A storage that outlives a restart
+    /// skips a record read from a position it has kept one from already, since the log is
+    /// read again from the start.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be stored.
+    fn record(&mut self, records: &[(Record, Option<Position>)]) -> Result<(), Error>;
+
+    /// The earliest moment the history can be rebuilt at, or `None` if it goes back to
+    /// the start.
+    fn start(&self) -> Option<DateTime<Utc>>;
+
+    /// The database that the replay starts from, as of [`Storage::start`].
+    fn base(&self) -> &Database;
+
+    /// Takes what is needed to replay the records with a timestamp up to and including
+    /// `until` onto the base. The [`Replay`] doesn't borrow the storage, so it can run on a
+    /// blocking thread while records keep being added.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be read.
+    fn replay(&self, until: DateTime<Utc>) -> Result<Replay, Error>;
+
+    /// Drops the records older than the maximum age, folding them into the base. Returns
+    /// how many there were.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be dropped.
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<usize, Error>;
+
+    /// Hands out the admin changes kept from before a restart with a timestamp up to and
+    /// including `until`, or all of them, oldest first and each only once. The live
+    /// database is rebuilt from the log at startup, and these are applied to it as the log
+    /// catches up with them. Storage that doesn't outlive a restart has none.
+    fn restore(&mut self, until: Option<DateTime<Utc>>) -> Vec<Record> {
+        let _ = until;
+        Vec::new()
+    }
+
+    /// Takes what is needed to rebuild the database as it was at `at`. Returns `None` if
+    /// `at` is before [`Storage::start`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be read.
+    fn at(&self, at: DateTime<Utc>) -> Result<Option<Replay>, Error> {
+        if self.start().is_some_and(|start| at < start) {
+            return Ok(None);
+        }
+        self.replay(at).map(Some)
+    }
+}
+
+/// The records fed to a replay, in the order they were applied.
+type Records = Box<dyn FnOnce(&mut dyn FnMut(Record)) -> Result<(), Error> + Send>;
+
+/// A replay of the history taken by [`Storage::replay`]: the base database, and the records
+/// to apply to it.
+pub struct Replay {
+    base: Database,
+    records: Records,
+}
+
+impl Replay {
+    /// A replay from `base` of the records that `records` passes to its argument.
+    pub fn new(
+        base: Database,
+        records: impl FnOnce(&mut dyn FnMut(Record)) -> Result<(), Error> + Send + 'static,
+    ) -> Self {
+        Self {
+            base,
+            records: Box::new(records),
+        }
+    }
+
+    /// The database the replay starts from.
+    #[must_use]
+    pub fn base(&self) -> &Database {
+        &self.base
+    }
+
+    /// Applies the records to the base, calling `f` with each record and the database
+    /// after it was applied, and returns the database they leave.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be read.
+    pub fn run(self, f: &mut dyn FnMut(&Record, &Database)) -> Result<Database, Error> {
+        let Self { mut base, records } = self;
+        quietly(|| {
+            records(&mut |record| {
+                base.apply(record.clone());
+                f(&record, &base);
+            })
+        })?;
+        Ok(base)
+    }
+
+    /// Applies the records to the base, and returns the database they leave.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records couldn't be read.
+    pub fn database(self) -> Result<Database, Error> {
+        self.run(&mut |_, _| {})
+    }
+}
+
+impl Debug for Replay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Replay")
+            .field("base", &self.base)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs `f` without logging, for replaying records that were logged when they were first
+/// applied.
+pub(crate) fn quietly<T>(f: impl FnOnce() -> T) -> T {
+    tracing::subscriber::with_default(tracing::subscriber::NoSubscriber::default(), f)
+}
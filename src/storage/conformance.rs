@@ -0,0 +1,428 @@
+//! Checks that an implementation of [`DeviceStore`] or [`Storage`] answers like the
+//! in-memory [`Database`] and [`History`] do, given the same records.
+//!
+//! Each check panics with the first difference it finds, so it can be run as a test. The
+//! suite is behind the `conformance` feature, for the tests of other implementations:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! hostapd-api = { version = "0.1", features = ["conformance"] }
+//! ```
+//!
+//! ```
+//! use hostapd_api::{db::Database, storage::conformance};
+//!
+//! conformance::device_store(&|| Box::new(Database::new()));
+//! ```
+
+use super::{DeviceStore, DeviceStoreMut, Position, Storage};
+use crate::{
+    db::{self, Database, DeviceQuery, Pruned, Retention, SortKey, SortOrder},
+    history::History,
+    mac::MacAddr,
+    parser::{self, AdminEvent, Change, Record},
+    Error,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The log the checks apply, as syslog-ng writes it: a device authenticating onto a VLAN
+/// and roaming between two access points, another staying on one, and a third leaving.
+const LOG: &[&str] = &[
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:00:00Z","message":"Using interface wlan0 with hwaddr 04:17:b6:00:00:01 and ssid \"home\""}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:00:00Z","message":"wlan0: STA 04:17:b6:37:96:dc IEEE 802.11: associated"}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:00:01Z","message":"wlan0: STA 04:17:b6:37:96:dc IEEE 802.1X: STA identity 'alice@example.com'"}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:00:02Z","message":"wlan0: STA 04:17:b6:37:96:dc RADIUS: VLAN ID 20"}"#,
+    r#"{"host":"bedroom-ap","program":"hostapd","timestamp":"2024-01-02T12:05:00Z","message":"wl1.1: STA 32:42:fd:88:86:0c IEEE 802.11: associated"}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:10:00Z","message":"wlan0: STA aa:bb:cc:dd:ee:ff IEEE 802.11: associated"}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:20:00Z","message":"wlan0: STA aa:bb:cc:dd:ee:ff IEEE 802.11: disassociated"}"#,
+    r#"{"host":"bedroom-ap","program":"hostapd","timestamp":"2024-01-02T12:30:00Z","message":"wl1.1: STA 04:17:b6:37:96:dc IEEE 802.11: associated"}"#,
+    r#"{"host":"garage-ap","program":"hostapd","timestamp":"2024-01-02T12:30:05Z","message":"wlan0: STA 04:17:b6:37:96:dc IEEE 802.11: disassociated"}"#,
+    r#"{"host":"bedroom-ap","program":"hostapd","timestamp":"2024-01-02T12:45:00Z","message":"wl1.1: STA 32:42:fd:88:86:0c WPA: pairwise key handshake completed (RSN)"}"#,
+];
+
+/// The devices in [`LOG`], and one that isn't.
+const MACS: &[&str] = &[
+    "04:17:b6:37:96:dc",
+    "32:42:fd:88:86:0c",
+    "aa:bb:cc:dd:ee:ff",
+    "02:00:00:00:00:01",
+];
+
+/// The records the checks apply, in order, one for every line of the log.
+///
+/// # Panics
+///
+/// Panics if a line of the log doesn't parse into a record.
+#[must_use]
+pub fn records() -> Vec<Record> {
+    LOG.iter()
+        .map(|line| match parser::parse(line) {
+            Ok(Some(record)) => record,
+            Ok(None) => panic!("{line} has no record"),
+            Err(e) => panic!("{line} doesn't parse: {e}"),
+        })
+        .collect()
+}
+
+/// The records along with where in a log they were read from: the lines of [`LOG`] in
+/// order, one to every hundred bytes.
+fn read(records: Vec<Record>) -> Vec<(Record, Option<Position>)> {
+    let file: std::sync::Arc<str> = "/var/log/messages".into();
+    (0..)
+        .zip(records)
+        .map(|(i, record)| {
+            let position = Position {
+                file: file.clone(),
+                file_id: 1,
+                offset: i * 100,
+            };
+            (record, Some(position))
+        })
+        .collect()
+}
+
+fn macs() -> impl Iterator<Item = MacAddr> {
+    MACS.iter().map(|mac| {
+        mac.parse()
+            .unwrap_or_else(|e| panic!("{mac} doesn't parse: {e}"))
+    })
+}
+
+fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 1, 2, hour, minute, second)
+        .single()
+        .unwrap_or_default()
+}
+
+fn json(value: impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or_else(|e| Value::String(e.to_string()))
+}
+
+fn same<T: Serialize>(what: &str, expected: T, actual: T) {
+    assert_eq!(json(expected), json(actual), "{what} differs");
+}
+
+fn ok<T>(what: &str, result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|e| panic!("{what} failed: {e}"))
+}
+
+/// Checks the stores that `make` returns against [`Database`]: what they answer about the
+/// records in [`records`], that their snapshots don't change with later writes, and what
+/// the admin changes and retention do to them.
+///
+/// # Panics
+///
+/// Panics if the stores answer differently, or one fails.
+pub fn device_store(make: &dyn Fn() -> Box<dyn DeviceStoreMut>) {
+    let records = records();
+    let (first, rest) = records.split_at(records.len() / 2);
+    let mut expected = Database::new();
+    let mut actual = make();
+    same("an empty store", query(&expected), query(&*actual));
+
+    for record in first {
+        expected.apply(record.clone());
+        ok("applying", actual.apply(record.clone()));
+    }
+    let snapshot = ok("taking a snapshot", actual.snapshot());
+    let expected_snapshot = expected.clone();
+    for record in rest {
+        expected.apply(record.clone());
+        ok("applying", actual.apply(record.clone()));
+    }
+    same(
+        "a snapshot after later writes",
+        query(&expected_snapshot),
+        query(&*snapshot),
+    );
+    same(
+        "the store after every record",
+        query(&expected),
+        query(&*actual),
+    );
+    assert_eq!(
+        ok(
+            "listing",
+            actual.device_list(&DeviceQuery::default().online(true))
+        )
+        .len(),
+        2,
+        "the number of devices online after every record"
+    );
+
+    for (what, change) in changes() {
+        let event = AdminEvent {
+            timestamp: at(13, 0, 0),
+            change,
+        };
+        same(
+            what,
+            expected.change(event.clone()),
+            ok(what, actual.change(event)),
+        );
+        same(what, query(&expected), query(&*actual));
+    }
+
+    let retention = Retention {
+        max_age: Some(Duration::minutes(30)),
+        ..Retention::default()
+    };
+    expected.configure_retention(retention.clone());
+    actual.configure_retention(retention);
+    let pruned = |pruned: Pruned| (pruned.expired, pruned.evicted);
+    same(
+        "pruning",
+        pruned(expected.prune(at(13, 45, 0))),
+        pruned(ok("pruning", actual.prune(at(13, 45, 0)))),
+    );
+    same("the store after pruning", query(&expected), query(&*actual));
+}
+
+/// The admin changes the checks make, in order, with what they do.
+fn changes() -> [(&'static str, Change); 7] {
+    let macs: Vec<MacAddr> = macs().collect();
+    let [alice, phone, gone, unknown] = macs[..] else {
+        panic!(
+            "expected {} MAC addresses, parsed {}",
+            MACS.len(),
+            macs.len()
+        );
+    };
+    [
+        ("forgetting a device", Change::Forget { mac: gone }),
+        (
+            "forgetting an unknown device",
+            Change::Forget { mac: unknown },
+        ),
+        (
+            "merging an unknown device",
+            Change::Merge {
+                from: unknown,
+                into: phone,
+            },
+        ),
+        (
+            "forcing a device offline",
+            Change::ForceOffline { mac: phone },
+        ),
+        (
+            "clearing an access point",
+            Change::ClearAp {
+                ap: "bedroom-ap".to_string(),
+            },
+        ),
+        (
+            "merging a device",
+            Change::Merge {
+                from: phone,
+                into: alice,
+            },
+        ),
+        (
+            "forcing a merged device offline",
+            Change::ForceOffline { mac: alice },
+        ),
+    ]
+}
+
+/// Everything the API can ask a store, as JSON.
+fn query(db: &(impl DeviceStore + ?Sized)) -> Value {
+    let queries = [
+        DeviceQuery::default(),
+        DeviceQuery::default().online(true),
+        DeviceQuery::default().online(false),
+        DeviceQuery::default().ap("bedroom-ap".to_string()),
+        DeviceQuery::default().ssid("home".to_string()),
+        DeviceQuery::default().identity("alice@example.com".to_string()),
+        DeviceQuery::default().vlan(20),
+        DeviceQuery {
+            sort: SortKey::LastSeen,
+            order: SortOrder::Desc,
+            limit: Some(2),
+            offset: Some(1),
+            ..DeviceQuery::default()
+        },
+    ];
+    let pages: Vec<Value> = queries
+        .iter()
+        .map(|q| json(ok("paging", db.device_page(q))))
+        .collect();
+    let stations = ok("counting the clients", db.station_clients());
+    let info: Vec<Value> = stations
+        .keys()
+        .map(|station| json(db.interface_info(station)))
+        .collect();
+    let clients: Vec<(String, usize)> = stations
+        .into_iter()
+        .map(|(station, clients)| (station.to_string(), clients))
+        .collect();
+    let known: Vec<bool> = ["garage-ap", "bedroom-ap", "nowhere"]
+        .into_iter()
+        .flat_map(|name| [db.knows_ap(name), db.knows_interface(name)])
+        .chain([
+            db.knows_station("garage-ap", "wlan0"),
+            db.knows_station("garage-ap", "wl1.1"),
+            db.knows_interface("wlan0"),
+        ])
+        .collect();
+    let online = ok(
+        "listing",
+        db.device_list(&DeviceQuery::default().online(true)),
+    );
+    serde_json::json!({
+        "last_event_timestamp": db.last_event_timestamp(),
+        "get": macs().map(|mac| json(ok("getting", db.get(mac)))).collect::<Vec<_>>(),
+        "known": known,
+        "station_clients": clients,
+        "access_point_clients": ok("counting the clients", db.access_point_clients()),
+        "interface_info": info,
+        "station_vlans": db::station_vlans(&online),
+        "device_list": ok("listing", db.device_list(&DeviceQuery::default())),
+        "device_pages": pages,
+    })
+}
+
+/// Checks the storages that `make` returns, given a base database and a maximum age,
+/// against [`History`]: the databases they rebuild at moments throughout the records in
+/// [`records`], the records they replay, and what pruning drops.
+///
+/// # Panics
+///
+/// Panics if the storages answer differently.
+pub fn storage(make: &dyn Fn(Database, Option<Duration>) -> Box<dyn Storage>) {
+    let records = records();
+    // an admin change in between, which the replays have to make too
+    let Some(phone) = macs().nth(1) else {
+        panic!("expected {} MAC addresses", MACS.len());
+    };
+    let changed = records
+        .iter()
+        .position(|record| record.timestamp() > at(12, 25, 0))
+        .unwrap_or(records.len());
+    let mut records = read(records);
+    records.insert(
+        changed,
+        (
+            Record::Admin(AdminEvent {
+                timestamp: at(12, 25, 0),
+                change: Change::ForceOffline { mac: phone },
+            }),
+            None,
+        ),
+    );
+    let moments = [
+        at(11, 0, 0),
+        at(12, 0, 0),
+        at(12, 0, 1),
+        at(12, 10, 0),
+        at(12, 25, 0),
+        at(12, 30, 0),
+        at(12, 30, 5),
+        at(13, 0, 0),
+    ];
+
+    // without a maximum age
+    let mut expected = History::new(Database::new(), None);
+    let mut actual = make(Database::new(), None);
+    same(
+        "an empty history",
+        rebuilt(&expected, &moments),
+        rebuilt(&*actual, &moments),
+    );
+    for batch in records.chunks(3) {
+        ok("recording", expected.record(batch));
+        ok("recording", actual.record(batch));
+    }
+    same("the start", expected.start(), actual.start());
+    same("the base", expected.base(), actual.base());
+    same(
+        "rebuilding",
+        rebuilt(&expected, &moments),
+        rebuilt(&*actual, &moments),
+    );
+    same(
+        "replaying",
+        replayed(&expected, &moments),
+        replayed(&*actual, &moments),
+    );
+    same(
+        "pruning without a maximum age",
+        ok("pruning", expected.prune(at(13, 0, 0))),
+        ok("pruning", actual.prune(at(13, 0, 0))),
+    );
+
+    // with a maximum age, starting from a base database that has a device already
+    let mut base = Database::new();
+    if let Some((record, _)) = records.get(1) {
+        base.apply(record.clone());
+    }
+    let max_age = Some(Duration::minutes(30));
+    let mut expected = History::new(base.clone(), max_age);
+    let mut actual = make(base, max_age);
+    ok("recording", expected.record(&records));
+    ok("recording", actual.record(&records));
+    same(
+        "rebuilding from a base",
+        rebuilt(&expected, &moments),
+        rebuilt(&*actual, &moments),
+    );
+    same(
+        "pruning",
+        ok("pruning", expected.prune(at(12, 55, 0))),
+        ok("pruning", actual.prune(at(12, 55, 0))),
+    );
+    assert_eq!(
+        actual.start(),
+        Some(at(12, 20, 0)),
+        "the start after pruning"
+    );
+    same("the base after pruning", expected.base(), actual.base());
+    same(
+        "rebuilding after pruning",
+        rebuilt(&expected, &moments),
+        rebuilt(&*actual, &moments),
+    );
+    same(
+        "replaying after pruning",
+        replayed(&expected, &moments),
+        replayed(&*actual, &moments),
+    );
+}
+
+/// The database rebuilt at each moment, or `null` before the start.
+fn rebuilt(storage: &dyn Storage, moments: &[DateTime<Utc>]) -> Value {
+    moments
+        .iter()
+        .map(|t| {
+            ok("rebuilding", storage.at(*t))
+                .map(|replay| query(&ok("rebuilding", replay.database())))
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// The records replayed until each moment, with the number of devices online after each.
+fn replayed(storage: &dyn Storage, moments: &[DateTime<Utc>]) -> Value {
+    moments
+        .iter()
+        .map(|t| {
+            let mut seen = Vec::new();
+            let db = ok(
+                "replaying",
+                storage.replay(*t).and_then(|replay| {
+                    replay.run(&mut |record, db| {
+                        let online = db
+                            .devices()
+                            .values()
+                            .filter(|device| !device.stations.is_empty())
+                            .count();
+                        seen.push((json(record), online));
+                    })
+                }),
+            );
+            json((seen, query(&db)))
+        })
+        .collect()
+}
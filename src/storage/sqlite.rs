@@ -0,0 +1,378 @@
+//! Keeps the history in an SQLite database, so that it outlives the logs it was read from,
+//! and the devices too, with [`SqliteDeviceStore`].
+//!
+//! The records are kept in `events`. Replaying the log at startup adds nothing new: events
+//! read from a position in the log that is already there, or that was pruned, are skipped.
+//! Admin changes are kept in `events` too, and are made again as the replay catches up with
+//! them.
+//!
+//! The schema is created and upgraded by the migrations in `MIGRATIONS`, and the
+//! database's `user_version` records how many of them were run.
+
+mod devices;
+
+pub use devices::SqliteDeviceStore;
+
+use super::{quietly, Position, Replay, Storage};
+use crate::{
+    db::Database,
+    mac::MacAddr,
+    parser::{Change, Record},
+    Error,
+};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{Arc, Mutex},
+    time,
+};
+
+/// The schema, one migration per release that changed it. Migrations are only ever
+/// appended, since a database keeps the number it has run.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        hostname TEXT,
+        interface TEXT,
+        mac TEXT,
+        action TEXT,
+        admin INTEGER NOT NULL DEFAULT 0,
+        file TEXT,
+        file_id INTEGER,
+        file_offset INTEGER,
+        record TEXT NOT NULL,
+        UNIQUE (file, file_id, file_offset)
+    );
+    CREATE INDEX events_mac ON events (mac, timestamp);
+    CREATE INDEX events_station ON events (hostname, interface, timestamp);
+    CREATE UNIQUE INDEX events_admin ON events (timestamp, record) WHERE admin;
+
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY,
+        mac TEXT NOT NULL,
+        identity TEXT,
+        access_point TEXT NOT NULL,
+        interface TEXT NOT NULL,
+        started TEXT NOT NULL,
+        ended TEXT NOT NULL,
+        duration_seconds INTEGER NOT NULL,
+        UNIQUE (mac, access_point, interface, started)
+    );
+    CREATE INDEX sessions_ended ON sessions (ended);
+    CREATE INDEX sessions_access_point ON sessions (access_point, started);
+
+    CREATE TABLE devices (
+        mac TEXT PRIMARY KEY,
+        online INTEGER NOT NULL,
+        stations TEXT NOT NULL,
+        identity TEXT,
+        accounting_session TEXT,
+        vlan INTEGER,
+        vlan_interface TEXT,
+        last_associated TEXT,
+        last_disassociated TEXT,
+        last_observed TEXT,
+        last_seen TEXT
+    );
+    CREATE INDEX devices_identity ON devices (identity);
+    CREATE INDEX devices_last_seen ON devices (last_seen);
+",
+    r#"
+    -- records are tagged with their type, first, as serde writes them
+    UPDATE events SET record = '{"type":"' || CASE
+            WHEN admin THEN 'admin'
+            WHEN mac IS NULL THEN 'interface'
+            ELSE 'station'
+        END || '",' || substr(record, 2)
+    WHERE json_extract(record, '$.type') IS NULL;
+"#,
+    "
+    -- the sessions were never read, and the devices are kept by SqliteDeviceStore instead
+    DROP TABLE sessions;
+    DROP TABLE devices;
+
+    CREATE TABLE devices (
+        mac TEXT PRIMARY KEY,
+        last_associated TEXT,
+        last_disassociated TEXT,
+        last_observed TEXT,
+        last_seen TEXT,
+        identity TEXT,
+        accounting_session TEXT,
+        vlan INTEGER,
+        vlan_interface TEXT
+    );
+    CREATE INDEX devices_identity ON devices (identity);
+    CREATE INDEX devices_last_seen ON devices (last_seen);
+
+    CREATE TABLE device_stations (
+        mac TEXT NOT NULL,
+        hostname TEXT NOT NULL,
+        interface TEXT NOT NULL,
+        PRIMARY KEY (mac, hostname, interface)
+    ) WITHOUT ROWID;
+    CREATE INDEX device_stations_station ON device_stations (hostname, interface);
+
+    -- the furthest position in each log file that events were pruned up to
+    CREATE TABLE pruned (
+        file TEXT NOT NULL,
+        file_id INTEGER NOT NULL,
+        file_offset INTEGER NOT NULL,
+        PRIMARY KEY (file, file_id)
+    );
+",
+];
+
+/// The history, kept in an SQLite database.
+///
+/// Like [`History`](crate::history::History), records older than the maximum age are
+/// folded into a base database in memory and dropped. The base isn't stored, so after a
+/// restart the replay starts from an empty database at the oldest stored record: a device
+/// that stayed online across the pruned records only shows up once it is seen again.
+///
+/// A replay shares the connection, and only holds it while it reads the records.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+    base: Database,
+    /// The newest record folded into `base`, or dropped before a restart.
+    start: Option<DateTime<Utc>>,
+    max_age: Option<Duration>,
+    /// The admin changes stored before a restart, oldest first, until they are restored.
+    restored: VecDeque<Record>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed and running the migrations it
+    /// hasn't run yet. The replay starts from `base`, usually an empty, configured
+    /// database.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be opened or migrated, or was created by a
+    /// newer version.
+    pub fn open(path: &Path, base: Database, max_age: Option<Duration>) -> Result<Self, Error> {
+        let connection = open_connection(path)?;
+        let start = connection
+            .query_row("SELECT value FROM meta WHERE key = 'start'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?
+            .map(|start| parse_timestamp(&start))
+            .transpose()?;
+        let restored = connection
+            .prepare("SELECT record FROM events WHERE admin ORDER BY timestamp, id")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|row| Ok(serde_json::from_str(&row?)?))
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            base,
+            start,
+            max_age,
+            restored,
+        })
+    }
+}
+
+/// Opens the database at `path` for writing, and migrates it. [`SqliteStorage`] and
+/// [`SqliteDeviceStore`] each write through a connection of their own, so they wait for
+/// each other's transactions.
+fn open_connection(path: &Path) -> Result<Connection, Error> {
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+    connection.busy_timeout(time::Duration::from_secs(5))?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+/// Runs the migrations the database hasn't run yet, each in a transaction of its own.
+fn migrate(connection: &mut Connection) -> Result<(), Error> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::Storage(format!(
+            "the database is at version {version}, but this version only knows up to {}",
+            MIGRATIONS.len()
+        )));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("migrated the SQLite database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+/// SQLite integers are signed, so the larger file IDs are stored as negative numbers. They
+/// are only compared for equality, so that's fine, and offsets never get that large.
+fn sql_integer(n: u64) -> i64 {
+    i64::from_ne_bytes(n.to_ne_bytes())
+}
+
+/// Timestamps are stored with a fixed number of digits, so they sort as text.
+fn timestamp(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| Error::Storage(format!("invalid timestamp {s}: {e}")))
+}
+
+/// The `hostname`, `interface`, `mac` and `action` columns of a record in `events`.
+fn columns(record: &Record) -> (Option<&str>, Option<&str>, Option<MacAddr>, Option<&str>) {
+    match record {
+        Record::Station(event) => (
+            Some(&event.hostname),
+            Some(&event.interface),
+            Some(event.mac),
+            Some(event.action.name()),
+        ),
+        Record::Interface(event) => (Some(&event.hostname), Some(&event.interface), None, None),
+        Record::Admin(event) => {
+            let (hostname, mac) = match event.change {
+                Change::Forget { mac } | Change::ForceOffline { mac } => (None, Some(mac)),
+                Change::Merge { into, .. } => (None, Some(into)),
+                Change::ClearAp { ref ap } => (Some(ap.as_str()), None),
+            };
+            (hostname, None, mac, Some(event.change.name()))
+        }
+    }
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::Storage("the SQLite connection was poisoned by a panic".to_string())
+}
+
+impl Storage for SqliteStorage {
+    fn record(&mut self, records: &[(Record, Option<Position>)]) -> Result<(), Error> {
+        let mut connection = self.connection.lock().map_err(poisoned)?;
+        let tx = connection.transaction()?;
+        {
+            let mut insert_event = tx.prepare_cached(
+                "INSERT OR IGNORE INTO events
+                 (timestamp, hostname, interface, mac, action, admin, file, file_id, file_offset,
+                     record)
+                 SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM pruned
+                     WHERE file = ?7 AND file_id = ?8 AND file_offset >= ?9
+                 )",
+            )?;
+            for (record, position) in records {
+                let (hostname, interface, mac, action) = columns(record);
+                insert_event.execute(params![
+                    timestamp(record.timestamp()),
+                    hostname,
+                    interface,
+                    mac.map(|mac| mac.to_string()),
+                    action,
+                    matches!(record, Record::Admin(_)),
+                    position.as_ref().map(|p| &*p.file),
+                    position.as_ref().map(|p| sql_integer(p.file_id)),
+                    position.as_ref().map(|p| sql_integer(p.offset)),
+                    serde_json::to_string(record)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn start(&self) -> Option<DateTime<Utc>> {
+        self.start
+    }
+
+    fn restore(&mut self, until: Option<DateTime<Utc>>) -> Vec<Record> {
+        let due = self
+            .restored
+            .iter()
+            .take_while(|record| until.map_or(true, |until| record.timestamp() <= until))
+            .count();
+        self.restored.drain(..due).collect()
+    }
+
+    fn base(&self) -> &Database {
+        &self.base
+    }
+
+    fn replay(&self, until: DateTime<Utc>) -> Result<Replay, Error> {
+        let connection = Arc::clone(&self.connection);
+        Ok(Replay::new(self.base.clone(), move |f| {
+            let rows = {
+                let connection = connection.lock().map_err(poisoned)?;
+                let mut statement = connection.prepare_cached(
+                    "SELECT record FROM events WHERE timestamp <= ?1 ORDER BY timestamp, id",
+                )?;
+                let rows = statement
+                    .query_map([timestamp(until)], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows
+            };
+            for row in rows {
+                f(serde_json::from_str(&row)?);
+            }
+            Ok(())
+        }))
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let Some(max_age) = self.max_age else {
+            return Ok(0);
+        };
+        let horizon = timestamp(now - max_age);
+        let mut connection = self.connection.lock().map_err(poisoned)?;
+        let tx = connection.transaction()?;
+        let records = tx
+            .prepare_cached(
+                "SELECT record FROM events WHERE timestamp < ?1 ORDER BY timestamp, id",
+            )?
+            .query_map([&horizon], |row| row.get::<_, String>(0))?
+            .map(|row| Ok(serde_json::from_str(&row?)?))
+            .collect::<Result<Vec<Record>, Error>>()?;
+        if records.is_empty() {
+            return Ok(0);
+        }
+        let start = records.iter().map(Record::timestamp).max().max(self.start);
+        // so that replaying the log after a restart doesn't add them back
+        tx.execute(
+            "INSERT INTO pruned (file, file_id, file_offset)
+             SELECT file, file_id, max(file_offset) FROM events
+             WHERE timestamp < ?1 AND file IS NOT NULL
+             GROUP BY file, file_id
+             ON CONFLICT (file, file_id) DO UPDATE
+             SET file_offset = max(file_offset, excluded.file_offset)",
+            [&horizon],
+        )?;
+        tx.execute("DELETE FROM events WHERE timestamp < ?1", [&horizon])?;
+        if let Some(start) = start {
+            tx.execute(
+                "INSERT INTO meta (key, value) VALUES ('start', ?1)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                [timestamp(start)],
+            )?;
+        }
+        tx.commit()?;
+        drop(connection);
+
+        // only once they are gone from the database, in case that failed
+        let pruned = records.len();
+        self.start = start;
+        for record in records {
+            quietly(|| self.base.apply(record));
+        }
+        Ok(pruned)
+    }
+}
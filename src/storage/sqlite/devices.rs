@@ -0,0 +1,620 @@
+//! Keeps the devices in the SQLite database the history is in, in `devices` and
+//! `device_stations`, so that the API's lookups and queries run as SQL.
+//!
+//! The devices are rebuilt from the log at every start, like the in-memory [`Database`]
+//! is, so the tables are emptied when the store is opened. Writes are made in a
+//! transaction that is committed when a snapshot is taken, and each snapshot reads the
+//! database as it was then, through a connection of its own.
+
+use super::{open_connection, parse_timestamp, poisoned};
+use crate::{
+    db::{
+        Database, Device, DeviceListItem, DeviceQuery, Pruned, Retention, SortKey, SortOrder,
+        Station, Stations, Vendor,
+    },
+    interface::{InterfaceConfig, InterfaceInfo},
+    mac::MacAddr,
+    parser::{AdminEvent, Change, Event, Record},
+    storage::{DeviceStore, DeviceStoreMut},
+    Error,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{named_params, params, Connection, OpenFlags, ToSql};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How many idle connections are kept for the next snapshots.
+const IDLE_READERS: usize = 4;
+
+/// A device, with the stations it is on as a JSON array of `[hostname, interface]` pairs.
+const SELECT: &str = "
+    SELECT d.mac, d.last_associated, d.last_disassociated, d.last_observed, d.identity,
+        d.accounting_session, d.vlan, d.vlan_interface,
+        (SELECT json_group_array(json_array(s.hostname, s.interface))
+         FROM device_stations s WHERE s.mac = d.mac)
+    FROM devices d";
+
+/// The filters of a [`DeviceQuery`], apart from the station metadata, which is in memory:
+/// the stations that match it are passed in `:stations` instead.
+const MATCHES: &str = "
+    (:online IS NULL
+        OR EXISTS (SELECT 1 FROM device_stations s WHERE s.mac = d.mac) = :online)
+    AND (:identity IS NULL OR d.identity = :identity)
+    AND (:vlan IS NULL OR d.vlan = :vlan)
+    AND (:seen_since IS NULL OR d.last_seen >= :seen_since)
+    AND (:oui IS NULL OR substr(d.mac, 1, 8) = :oui)
+    AND (NOT :random OR substr(d.mac, 2, 1) IN ('2', '3', '6', '7', 'a', 'b', 'e', 'f'))
+    AND (:stations IS NULL OR EXISTS (
+        SELECT 1 FROM device_stations s, json_each(:stations) j
+        WHERE s.mac = d.mac
+            AND s.hostname = json_extract(j.value, '$[0]')
+            AND s.interface = json_extract(j.value, '$[1]')))";
+
+/// Offline devices that aren't in the keep list, passed as a JSON array in `?1`.
+const PRUNABLE: &str = "
+    NOT EXISTS (SELECT 1 FROM device_stations s WHERE s.mac = d.mac)
+    AND d.mac NOT IN (SELECT value FROM json_each(?1))";
+
+/// The devices, kept in an SQLite database.
+///
+/// The database has to be a file, since every snapshot opens it again.
+#[derive(Debug)]
+pub struct SqliteDeviceStore {
+    connection: Mutex<Connection>,
+    path: PathBuf,
+    stations: Stations,
+    retention: Retention,
+    last_event_timestamp: Option<DateTime<Utc>>,
+    /// Connections the snapshots no longer need.
+    readers: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl SqliteDeviceStore {
+    /// Opens the database at `path`, creating it if needed and running the migrations it
+    /// hasn't run yet, and starts over from the devices, configuration and retention of
+    /// `base`, usually an empty, configured database.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be opened or migrated, or was created by a
+    /// newer version.
+    pub fn open(path: &Path, base: &Database) -> Result<Self, Error> {
+        let mut connection = open_connection(path)?;
+        let tx = connection.transaction()?;
+        tx.execute_batch("DELETE FROM device_stations; DELETE FROM devices;")?;
+        for (mac, device) in base.devices() {
+            store(&tx, *mac, None, device)?;
+        }
+        tx.commit()?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            path: path.to_owned(),
+            stations: base.stations().clone(),
+            retention: base.retention().clone(),
+            last_event_timestamp: base.last_event_timestamp,
+            readers: Arc::default(),
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T, Error>) -> Result<T, Error> {
+        let connection = self.connection.lock().map_err(poisoned)?;
+        f(&connection)
+    }
+
+    /// The connection to write through, in the transaction the next snapshot commits.
+    fn connection(&mut self) -> Result<&mut Connection, Error> {
+        let connection = self.connection.get_mut().map_err(poisoned)?;
+        if connection.is_autocommit() {
+            connection.execute_batch("BEGIN")?;
+        }
+        Ok(connection)
+    }
+
+    /// A connection to read a snapshot through, idle or new.
+    fn reader(&self) -> Result<Connection, Error> {
+        if let Some(connection) = self.readers.lock().map_err(poisoned)?.pop() {
+            return Ok(connection);
+        }
+        let connection = Connection::open_with_flags(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        Ok(connection)
+    }
+
+    fn witness(&mut self, event: Event) -> Result<(), Error> {
+        let station = Station {
+            hostname: event.hostname,
+            interface: event.interface,
+        };
+        self.last_event_timestamp.replace(event.timestamp);
+        self.stations.see(&station);
+        let connection = self.connection()?;
+        let before = load(connection, event.mac)?;
+        let mut device = before.clone().unwrap_or_default();
+        device.witness(event.timestamp, station, event.action);
+        store(connection, event.mac, before.as_ref(), &device)
+    }
+}
+
+/// A snapshot of a [`SqliteDeviceStore`], which reads the database as it was when it was
+/// taken, through a read transaction of its own.
+#[derive(Debug)]
+struct Snapshot {
+    /// Only taken back when the snapshot is dropped.
+    connection: Mutex<Option<Connection>>,
+    readers: Arc<Mutex<Vec<Connection>>>,
+    stations: Stations,
+    last_event_timestamp: Option<DateTime<Utc>>,
+}
+
+impl Snapshot {
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T, Error>) -> Result<T, Error> {
+        let connection = self.connection.lock().map_err(poisoned)?;
+        let connection = connection
+            .as_ref()
+            .ok_or_else(|| Error::Storage("the snapshot was dropped".to_string()))?;
+        f(connection)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let Ok(connection) = self.connection.get_mut() else {
+            return;
+        };
+        let Some(connection) = connection.take() else {
+            return;
+        };
+        if connection.execute_batch("ROLLBACK").is_err() {
+            return;
+        }
+        if let Ok(mut readers) = self.readers.lock() {
+            if readers.len() < IDLE_READERS {
+                readers.push(connection);
+            }
+        }
+    }
+}
+
+/// Timestamps are stored with every digit, so that they read back as they were, and with
+/// a fixed number of them, so that they sort as text.
+fn precise(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn parse_mac(mac: &str) -> Result<MacAddr, Error> {
+    mac.parse()
+        .map_err(|e| Error::Storage(format!("invalid MAC address {mac}: {e}")))
+}
+
+/// Reads a device from a row of [`SELECT`].
+fn read_device(row: &rusqlite::Row) -> Result<(MacAddr, Device), Error> {
+    let time = |i: usize| -> Result<Option<DateTime<Utc>>, Error> {
+        row.get::<_, Option<String>>(i)?
+            .map(|t| parse_timestamp(&t))
+            .transpose()
+    };
+    let stations: Vec<(String, String)> = serde_json::from_str(&row.get::<_, String>(8)?)?;
+    let device = Device {
+        stations: stations
+            .into_iter()
+            .map(|(hostname, interface)| Station {
+                hostname,
+                interface,
+            })
+            .collect(),
+        last_associated: time(1)?,
+        last_disassociated: time(2)?,
+        last_observed: time(3)?,
+        identity: row.get(4)?,
+        accounting_session: row.get(5)?,
+        vlan: row.get(6)?,
+        vlan_interface: row.get(7)?,
+    };
+    Ok((parse_mac(&row.get::<_, String>(0)?)?, device))
+}
+
+fn load(connection: &Connection, mac: MacAddr) -> Result<Option<Device>, Error> {
+    let mut statement = connection.prepare_cached(&format!("{SELECT} WHERE d.mac = ?1"))?;
+    let mut rows = statement.query([mac.to_string()])?;
+    rows.next()?
+        .map(read_device)
+        .transpose()
+        .map(|device| device.map(|(_, device)| device))
+}
+
+/// Writes a device, which was `before`, if it was known.
+fn store(
+    connection: &Connection,
+    mac: MacAddr,
+    before: Option<&Device>,
+    device: &Device,
+) -> Result<(), Error> {
+    let key = mac.to_string();
+    connection
+        .prepare_cached(
+            "INSERT INTO devices (mac, last_associated, last_disassociated, last_observed,
+                 last_seen, identity, accounting_session, vlan, vlan_interface)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (mac) DO UPDATE SET
+                 last_associated = excluded.last_associated,
+                 last_disassociated = excluded.last_disassociated,
+                 last_observed = excluded.last_observed,
+                 last_seen = excluded.last_seen,
+                 identity = excluded.identity,
+                 accounting_session = excluded.accounting_session,
+                 vlan = excluded.vlan,
+                 vlan_interface = excluded.vlan_interface",
+        )?
+        .execute(params![
+            key,
+            device.last_associated.map(precise),
+            device.last_disassociated.map(precise),
+            device.last_observed.map(precise),
+            device.last_seen().map(precise),
+            device.identity,
+            device.accounting_session,
+            device.vlan,
+            device.vlan_interface,
+        ])?;
+    // most events don't move the device
+    if before.is_some_and(|before| before.stations == device.stations) {
+        return Ok(());
+    }
+    connection
+        .prepare_cached("DELETE FROM device_stations WHERE mac = ?1")?
+        .execute([&key])?;
+    let mut insert = connection.prepare_cached(
+        "INSERT INTO device_stations (mac, hostname, interface) VALUES (?1, ?2, ?3)",
+    )?;
+    for station in &device.stations {
+        insert.execute(params![key, station.hostname, station.interface])?;
+    }
+    Ok(())
+}
+
+fn forget(connection: &Connection, mac: MacAddr) -> Result<usize, Error> {
+    connection.execute(
+        "DELETE FROM device_stations WHERE mac = ?1",
+        [mac.to_string()],
+    )?;
+    Ok(connection.execute("DELETE FROM devices WHERE mac = ?1", [mac.to_string()])?)
+}
+
+fn get(
+    connection: &Connection,
+    stations: &Stations,
+    mac: MacAddr,
+) -> Result<Option<DeviceListItem>, Error> {
+    Ok(load(connection, mac)?
+        .map(|device| DeviceListItem::new(mac, Arc::new(device), &stations.interfaces)))
+}
+
+fn device_page(
+    connection: &Connection,
+    stations: &Stations,
+    query: &DeviceQuery,
+) -> Result<(usize, Vec<DeviceListItem>), Error> {
+    let matching: Option<Vec<[&str; 2]>> = query.filters_stations().then(|| {
+        stations
+            .seen
+            .iter()
+            .filter(|station| query.matches_station(station, stations.info(station).as_ref()))
+            .map(|station| [station.hostname.as_str(), station.interface.as_str()])
+            .collect()
+    });
+    let matching = matching.map(|m| serde_json::to_string(&m)).transpose()?;
+    let oui = match query.vendor {
+        Some(Vendor::Oui(oui)) => Some(oui.to_string()),
+        _ => None,
+    };
+    let seen_since = query.seen_since.map(precise);
+    let filters = named_params! {
+        ":online": query.online,
+        ":identity": query.identity,
+        ":vlan": query.vlan,
+        ":seen_since": seen_since,
+        ":oui": oui,
+        ":random": query.vendor == Some(Vendor::Random),
+        ":stations": matching,
+    };
+
+    let total: i64 = connection
+        .prepare_cached(&format!("SELECT count(*) FROM devices d WHERE {MATCHES}"))?
+        .query_row(filters, |row| row.get(0))?;
+
+    let key = match query.sort {
+        SortKey::Mac => "d.mac",
+        SortKey::LastAssociated => "d.last_associated",
+        SortKey::LastDisassociated => "d.last_disassociated",
+        SortKey::LastObserved => "d.last_observed",
+        SortKey::LastSeen => "d.last_seen",
+    };
+    let order = match query.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    let limit = query
+        .limit
+        .map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+    let offset = i64::try_from(query.offset.unwrap_or(0)).unwrap_or(i64::MAX);
+    let mut page: Vec<(&str, &dyn ToSql)> = filters.to_vec();
+    page.extend([(":limit", &limit as &dyn ToSql), (":offset", &offset)]);
+    let mut statement = connection.prepare_cached(&format!(
+        "{SELECT} WHERE {MATCHES}
+         ORDER BY {key} {order}, d.mac {order}
+         LIMIT :limit OFFSET :offset"
+    ))?;
+    let mut rows = statement.query(page.as_slice())?;
+    let mut devices = Vec::new();
+    while let Some(row) = rows.next()? {
+        let (mac, device) = read_device(row)?;
+        devices.push(DeviceListItem::new(
+            mac,
+            Arc::new(device),
+            &stations.interfaces,
+        ));
+    }
+    Ok((usize::try_from(total).unwrap_or_default(), devices))
+}
+
+fn station_clients(
+    connection: &Connection,
+    stations: &Stations,
+) -> Result<BTreeMap<Station, usize>, Error> {
+    let mut clients: BTreeMap<Station, usize> =
+        stations.seen.iter().map(|s| (s.clone(), 0)).collect();
+    let mut statement = connection.prepare_cached(
+        "SELECT hostname, interface, count(*) FROM device_stations GROUP BY hostname, interface",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            Station {
+                hostname: row.get(0)?,
+                interface: row.get(1)?,
+            },
+            row.get::<_, usize>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (station, count) = row?;
+        clients.insert(station, count);
+    }
+    Ok(clients)
+}
+
+fn access_point_clients(
+    connection: &Connection,
+    stations: &Stations,
+) -> Result<BTreeMap<String, usize>, Error> {
+    let mut clients: BTreeMap<String, usize> = stations
+        .seen
+        .iter()
+        .map(|s| (s.hostname.clone(), 0))
+        .collect();
+    let mut statement = connection.prepare_cached(
+        "SELECT hostname, count(DISTINCT mac) FROM device_stations GROUP BY hostname",
+    )?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, usize>(1)?)))?;
+    for row in rows {
+        let (ap, count) = row?;
+        clients.insert(ap, count);
+    }
+    Ok(clients)
+}
+
+impl DeviceStore for SqliteDeviceStore {
+    fn last_event_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.last_event_timestamp
+    }
+
+    fn knows_ap(&self, ap: &str) -> bool {
+        self.stations.knows_ap(ap)
+    }
+
+    fn knows_interface(&self, interface: &str) -> bool {
+        self.stations.knows_interface(interface)
+    }
+
+    fn knows_station(&self, ap: &str, interface: &str) -> bool {
+        self.stations.knows_station(ap, interface)
+    }
+
+    fn interface_info(&self, station: &Station) -> Option<InterfaceInfo> {
+        self.stations.info(station)
+    }
+
+    fn get(&self, mac: MacAddr) -> Result<Option<DeviceListItem>, Error> {
+        self.read(|connection| get(connection, &self.stations, mac))
+    }
+
+    fn device_page(&self, query: &DeviceQuery) -> Result<(usize, Vec<DeviceListItem>), Error> {
+        self.read(|connection| device_page(connection, &self.stations, query))
+    }
+
+    fn station_clients(&self) -> Result<BTreeMap<Station, usize>, Error> {
+        self.read(|connection| station_clients(connection, &self.stations))
+    }
+
+    fn access_point_clients(&self) -> Result<BTreeMap<String, usize>, Error> {
+        self.read(|connection| access_point_clients(connection, &self.stations))
+    }
+}
+
+impl DeviceStoreMut for SqliteDeviceStore {
+    fn snapshot(&mut self) -> Result<Arc<dyn DeviceStore>, Error> {
+        let connection = self.connection.get_mut().map_err(poisoned)?;
+        if !connection.is_autocommit() {
+            connection.execute_batch("COMMIT")?;
+        }
+        let reader = self.reader()?;
+        // the snapshot starts with the first read
+        reader.execute_batch("BEGIN")?;
+        reader.query_row("SELECT count(*) FROM devices", [], |_| Ok(()))?;
+        Ok(Arc::new(Snapshot {
+            connection: Mutex::new(Some(reader)),
+            readers: Arc::clone(&self.readers),
+            stations: self.stations.clone(),
+            last_event_timestamp: self.last_event_timestamp,
+        }))
+    }
+
+    fn apply(&mut self, record: Record) -> Result<(), Error> {
+        match record {
+            Record::Station(event) => self.witness(event),
+            Record::Interface(event) => {
+                self.stations.learn(event);
+                Ok(())
+            }
+            Record::Admin(event) => self.change(event).map(|_| ()),
+        }
+    }
+
+    fn change(&mut self, AdminEvent { timestamp, change }: AdminEvent) -> Result<usize, Error> {
+        let connection = self.connection()?;
+        match change {
+            Change::Forget { mac } => {
+                tracing::info!("forget {mac}");
+                forget(connection, mac)
+            }
+            Change::Merge { from, into } => {
+                let Some(device) = load(connection, from)? else {
+                    return Ok(0);
+                };
+                tracing::info!("merge {from} into {into}");
+                forget(connection, from)?;
+                let before = load(connection, into)?;
+                let mut merged = before.clone().unwrap_or_default();
+                merged.merge(device);
+                store(connection, into, before.as_ref(), &merged)?;
+                Ok(1)
+            }
+            Change::ForceOffline { mac } => {
+                let Some(before) = load(connection, mac)? else {
+                    return Ok(0);
+                };
+                let mut device = before.clone();
+                device.disconnect(timestamp, None);
+                store(connection, mac, Some(&before), &device)?;
+                Ok(1)
+            }
+            Change::ClearAp { ap } => {
+                let macs = connection
+                    .prepare_cached("SELECT DISTINCT mac FROM device_stations WHERE hostname = ?1")?
+                    .query_map([&ap], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut cleared = 0;
+                for mac in macs {
+                    let mac = parse_mac(&mac)?;
+                    let Some(before) = load(connection, mac)? else {
+                        continue;
+                    };
+                    let mut device = before.clone();
+                    if device.disconnect(timestamp, Some(&ap)) {
+                        cleared += 1;
+                    }
+                    store(connection, mac, Some(&before), &device)?;
+                }
+                Ok(cleared)
+            }
+        }
+    }
+
+    fn configure_interfaces(&mut self, config: InterfaceConfig) {
+        self.stations.interfaces.configure(config);
+    }
+
+    fn configure_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) -> Result<Pruned, Error> {
+        let Retention {
+            max_age,
+            max_devices,
+            ref keep,
+        } = self.retention;
+        let keep: Vec<String> = keep.iter().map(ToString::to_string).collect();
+        let keep = serde_json::to_string(&keep)?;
+        let connection = self.connection()?;
+        let mut pruned = Pruned::default();
+
+        if let Some(horizon) = max_age.and_then(|max_age| now.checked_sub_signed(max_age)) {
+            pruned.expired = connection.execute(
+                &format!(
+                    "DELETE FROM devices AS d WHERE {PRUNABLE}
+                     AND (d.last_seen IS NULL OR d.last_seen < ?2)"
+                ),
+                params![keep, precise(horizon)],
+            )?;
+        }
+
+        if let Some(max_devices) = max_devices {
+            let devices: usize =
+                connection.query_row("SELECT count(*) FROM devices", [], |row| row.get(0))?;
+            if let Some(excess) = devices.checked_sub(max_devices).filter(|&n| n > 0) {
+                pruned.evicted = connection.execute(
+                    &format!(
+                        "DELETE FROM devices WHERE mac IN (
+                             SELECT d.mac FROM devices d WHERE {PRUNABLE}
+                             ORDER BY d.last_seen, d.mac LIMIT ?2)"
+                    ),
+                    params![keep, excess],
+                )?;
+            }
+        }
+
+        if pruned.expired > 0 || pruned.evicted > 0 {
+            tracing::info!(
+                "pruned {} expired and {} evicted devices",
+                pruned.expired,
+                pruned.evicted
+            );
+        }
+        Ok(pruned)
+    }
+}
+
+impl DeviceStore for Snapshot {
+    fn last_event_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.last_event_timestamp
+    }
+
+    fn knows_ap(&self, ap: &str) -> bool {
+        self.stations.knows_ap(ap)
+    }
+
+    fn knows_interface(&self, interface: &str) -> bool {
+        self.stations.knows_interface(interface)
+    }
+
+    fn knows_station(&self, ap: &str, interface: &str) -> bool {
+        self.stations.knows_station(ap, interface)
+    }
+
+    fn interface_info(&self, station: &Station) -> Option<InterfaceInfo> {
+        self.stations.info(station)
+    }
+
+    fn get(&self, mac: MacAddr) -> Result<Option<DeviceListItem>, Error> {
+        self.read(|connection| get(connection, &self.stations, mac))
+    }
+
+    fn device_page(&self, query: &DeviceQuery) -> Result<(usize, Vec<DeviceListItem>), Error> {
+        self.read(|connection| device_page(connection, &self.stations, query))
+    }
+
+    fn station_clients(&self) -> Result<BTreeMap<Station, usize>, Error> {
+        self.read(|connection| station_clients(connection, &self.stations))
+    }
+
+    fn access_point_clients(&self) -> Result<BTreeMap<String, usize>, Error> {
+        self.read(|connection| access_point_clients(connection, &self.stations))
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{db::DB, status::SharedStatus};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// Sends an alert to `watchdog_url` when no events have been seen for
+/// [`Status::max_event_age`](crate::status::Status::max_event_age), and again each time
+/// events stop after resuming. The URL is presumably a Pushcut URL, accepting a POST request
+/// with a JSON body containing a `text` field.
+pub async fn watchdog_loop(
+    watchdog_url: &str,
+    db: DB,
+    status: SharedStatus,
+    shutdown: CancellationToken,
+) {
+    let client = reqwest::Client::new();
+    let mut watchdog = interval(std::time::Duration::from_secs(60));
+    let mut watchdog_fired = false;
+    let watchdog_started = Utc::now();
+    let watchdog_period = status.max_event_age;
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => {
+                break;
+            }
+            _ = watchdog.tick() => {
+                let now = Utc::now();
+                let last_event = db.read().last_event_timestamp();
+                match last_event {
+                    Some(t) if now - t > watchdog_period && !watchdog_fired => {
+                        tracing::warn!("watchdog: no events in {} minutes, sending notification", watchdog_period.num_minutes());
+                        watchdog_alert(&client, watchdog_url, now - t).await;
+                        watchdog_fired = true;
+                    }
+                    None if now - watchdog_started > watchdog_period && !watchdog_fired => {
+                        tracing::warn!("watchdog: never seen any events in {} minutes, sending notification", watchdog_period.num_minutes());
+                        watchdog_alert(&client, watchdog_url, now - watchdog_started).await;
+                        watchdog_fired = true;
+                    }
+                    // events resumed, so alert again the next time they stop
+                    Some(t) if now - t <= watchdog_period => {
+                        watchdog_fired = false;
+                    }
+                    _ => { }
+                }
+                status.set_watchdog_fired(watchdog_fired);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WatchdogBody {
+    text: String,
+}
+
+async fn watchdog_alert(client: &reqwest::Client, url: &str, period: Duration) {
+    let resp = client
+        .post(url)
+        .json(&WatchdogBody {
+            text: format!("No hostapd events in {} minutes", period.num_minutes()),
+        })
+        .send()
+        .await;
+
+    if let Err(e) = resp {
+        tracing::error!("error sending watchdog alert: {}", e);
+    }
+}
@@ -0,0 +1,242 @@
+//! Runs the storage conformance suite against every implementation in the crate. It needs
+//! the `conformance` feature, which the crate's dev-dependency on itself turns on.
+#![cfg(feature = "conformance")]
+
+use hostapd_api::{db::Database, history::History, storage::conformance};
+
+#[test]
+fn database_conforms() {
+    conformance::device_store(&|| Box::new(Database::new()));
+}
+
+#[test]
+fn history_conforms() {
+    conformance::storage(&|base, max_age| Box::new(History::new(base, max_age)));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_conforms() {
+    use hostapd_api::storage::sqlite::SqliteStorage;
+    use std::path::Path;
+
+    conformance::storage(&|base, max_age| {
+        Box::new(SqliteStorage::open(Path::new(":memory:"), base, max_age).unwrap())
+    });
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_device_store_conforms() {
+    use hostapd_api::storage::sqlite::SqliteDeviceStore;
+
+    let dir = std::env::temp_dir().join(format!("hostapd-api-devices-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("devices.db");
+    conformance::device_store(&|| {
+        Box::new(SqliteDeviceStore::open(&path, &Database::new()).unwrap())
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The records in [`conformance::records`], as read from consecutive lines of a log.
+#[cfg(feature = "sqlite")]
+fn read() -> Vec<(
+    hostapd_api::parser::Record,
+    Option<hostapd_api::storage::Position>,
+)> {
+    use hostapd_api::storage::Position;
+
+    let file: std::sync::Arc<str> = "/var/log/messages".into();
+    (0..)
+        .zip(conformance::records())
+        .map(|(i, record)| {
+            let position = Position {
+                file: file.clone(),
+                file_id: 7,
+                offset: i * 200,
+            };
+            (record, Some(position))
+        })
+        .collect()
+}
+
+#[cfg(feature = "sqlite")]
+fn query<T: rusqlite::types::FromSql>(path: &std::path::Path, sql: &str) -> Vec<T> {
+    let connection = rusqlite::Connection::open(path).unwrap();
+    let mut statement = connection.prepare(sql).unwrap();
+    let rows = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    rows
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_skips_only_records_read_from_the_same_position() {
+    use hostapd_api::storage::{sqlite::SqliteStorage, Storage};
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("hostapd-api-positions-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.db");
+    let mut log = read();
+    // the same event logged twice in the same second, on another line
+    let mut again = log[1].clone();
+    again.1.as_mut().unwrap().offset = 10_000;
+    log.push(again);
+
+    let mut storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    storage.record(&log).unwrap();
+    drop(storage);
+    // read again from the start after a restart
+    let mut storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    storage.record(&log).unwrap();
+    drop(storage);
+
+    let events: Vec<i64> = query(&path, "SELECT count(*) FROM events");
+    assert_eq!(events, [i64::try_from(log.len()).unwrap()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_skips_records_pruned_before_a_restart() {
+    use chrono::{Duration, TimeZone, Utc};
+    use hostapd_api::storage::{sqlite::SqliteStorage, Storage};
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("hostapd-api-pruned-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.db");
+    let log = read();
+    let max_age = Some(Duration::minutes(30));
+    // half an hour after 12:20, between the events
+    let now = Utc.with_ymd_and_hms(2024, 1, 2, 12, 50, 0).unwrap();
+
+    let mut storage = SqliteStorage::open(&path, Database::new(), max_age).unwrap();
+    storage.record(&log).unwrap();
+    let pruned = storage.prune(now).unwrap();
+    assert!(pruned > 0 && pruned < log.len(), "pruned {pruned}");
+    let start = storage.start();
+    drop(storage);
+    // read again from the start after a restart
+    let mut storage = SqliteStorage::open(&path, Database::new(), max_age).unwrap();
+    storage.record(&log).unwrap();
+    assert_eq!(storage.start(), start);
+    drop(storage);
+
+    let events: Vec<i64> = query(&path, "SELECT count(*) FROM events");
+    assert_eq!(events, [i64::try_from(log.len() - pruned).unwrap()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_restores_admin_changes_after_a_restart() {
+    use chrono::{TimeZone, Utc};
+    use hostapd_api::{
+        parser::{AdminEvent, Change, Record},
+        storage::{sqlite::SqliteStorage, Storage},
+    };
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("hostapd-api-restore-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.db");
+    let gone = "aa:bb:cc:dd:ee:ff".parse().unwrap();
+    let forgotten = |storage: &SqliteStorage| {
+        let replay = storage.replay(Utc::now()).unwrap();
+        !replay.database().unwrap().devices().contains_key(&gone)
+    };
+    let forget = Record::Admin(AdminEvent {
+        timestamp: Utc.with_ymd_and_hms(2024, 1, 2, 12, 25, 0).unwrap(),
+        change: Change::Forget { mac: gone },
+    });
+    // the log, with the change made where it caught up with it
+    let mut log = read();
+    let caught_up = log
+        .iter()
+        .position(|(record, _)| record.timestamp() > forget.timestamp())
+        .unwrap();
+
+    let mut storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    assert!(
+        storage.restore(None).is_empty(),
+        "nothing to restore at first"
+    );
+    log.insert(caught_up, (forget.clone(), None));
+    storage.record(&log).unwrap();
+    assert!(forgotten(&storage));
+    drop(storage);
+    log.remove(caught_up);
+
+    let mut storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    let mut replayed = Vec::new();
+    for (record, position) in log {
+        let restored = storage.restore(Some(record.timestamp()));
+        replayed.extend(restored.into_iter().map(|record| (record, None)));
+        replayed.push((record, position));
+    }
+    assert!(
+        storage.restore(None).is_empty(),
+        "a change is restored once"
+    );
+    assert_eq!(
+        serde_json::to_value(&replayed[caught_up].0).unwrap(),
+        serde_json::to_value(&forget).unwrap(),
+        "the change is restored once the log catches up with it"
+    );
+    storage.record(&replayed).unwrap();
+    assert!(
+        forgotten(&storage),
+        "the history keeps the change after a restart"
+    );
+    let events: Vec<i64> = query(&path, "SELECT count(*) FROM events");
+    assert_eq!(
+        events,
+        [i64::try_from(replayed.len()).unwrap()],
+        "nothing is stored twice"
+    );
+    drop(storage);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_tags_records_stored_untagged() {
+    use hostapd_api::storage::{sqlite::SqliteStorage, Storage};
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("hostapd-api-untagged-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.db");
+    let records = "SELECT record FROM events ORDER BY id";
+
+    let mut storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    storage.record(&read()).unwrap();
+    drop(storage);
+    let tagged: Vec<String> = query(&path, records);
+    // as an earlier version stored them, with the tables it had
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch(
+            "UPDATE events SET record = json_remove(record, '$.type');
+             DROP TABLE device_stations;
+             DROP TABLE pruned;
+             CREATE TABLE sessions (id INTEGER PRIMARY KEY);
+             PRAGMA user_version = 1;",
+        )
+        .unwrap();
+    assert_ne!(query::<String>(&path, records), tagged);
+
+    let storage = SqliteStorage::open(&path, Database::new(), None).unwrap();
+    assert_eq!(query::<String>(&path, records), tagged);
+    let replay = storage.replay(chrono::Utc::now()).unwrap();
+    let db = replay.database().unwrap();
+    assert!(!db.devices().is_empty());
+    drop(storage);
+    std::fs::remove_dir_all(&dir).unwrap();
+}